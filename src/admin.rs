@@ -0,0 +1,370 @@
+use std::fs::{self, File};
+use std::io::Read;
+use std::path::Path;
+
+use chrono::{DateTime, UTC};
+use postgres::Connection;
+use uuid::Uuid;
+
+use db;
+use email;
+use export;
+use ingest::{self, Note};
+use mail::{self, Message};
+use session;
+use subject::EmptySubject;
+use zone;
+
+
+const USAGE: &'static str = "usage: write-only-space <command> [args...]
+
+commands:
+  authors                      list every author
+  show <email>                 show an author's key and topics
+  merge <from-email> <into>    move everything by one author to another
+  delete <email>               put an author and all of their notes in the trash
+  restore <email>              take an author back out of the trash
+  rename-topic <key> <name>    rename a topic
+  empty-subject <email> <rule> where subject-less notes go: body, untitled or dated
+  timezone <email> <zone>      show an author's dates in an IANA timezone
+  resend-welcome <email>       send an author's welcome email again
+  import <mbox-or-maildir>     add notes from old emails, keeping their dates
+  encrypt-notes [batch-size]   encrypt notes stored before there was a master key
+  rotate-master-key            re-wrap data keys from the previous master key to the new one
+  stats                        print some numbers";
+
+
+/// Run an admin command, returning the process exit code.
+pub fn run(conn: &Connection, args: &[String]) -> i32 {
+    let result = match (args.get(0).map(|a| &a[..]), args.len()) {
+        (Some("authors"), 1) => authors(conn),
+        (Some("show"), 2) => show(conn, &args[1]),
+        (Some("merge"), 3) => merge(conn, &args[1], &args[2]),
+        (Some("delete"), 2) => delete(conn, &args[1]),
+        (Some("restore"), 2) => restore(conn, &args[1]),
+        (Some("rename-topic"), n) if n > 2 => rename_topic(conn, &args[1], &args[2..].join(" ")),
+        (Some("empty-subject"), 3) => empty_subject(conn, &args[1], &args[2]),
+        (Some("timezone"), 3) => timezone(conn, &args[1], &args[2]),
+        (Some("resend-welcome"), 2) => resend_welcome(conn, &args[1]),
+        (Some("import"), 2) => import(conn, &args[1]),
+        (Some("encrypt-notes"), 1) => encrypt_notes(conn, "500"),
+        (Some("encrypt-notes"), 2) => encrypt_notes(conn, &args[1]),
+        (Some("rotate-master-key"), 1) => rotate_master_key(conn),
+        (Some("stats"), 1) => stats(conn),
+        _ => Err(USAGE.to_string()),
+    };
+    match result {
+        Ok(()) => 0,
+        Err(message) => {
+            println!("{}", message);
+            1
+        },
+    }
+}
+
+
+fn authors(conn: &Connection) -> Result<(), String> {
+    let authors = try!(db::authors(conn).map_err(|e| e.to_string()));
+    for author in authors {
+        println!("{}  {}  joined {}", author.key, author.email, author.joined.format("%Y-%m-%d"));
+    }
+    Ok(())
+}
+
+fn show(conn: &Connection, email: &str) -> Result<(), String> {
+    let key = match try!(db::author_key(conn, email).map_err(|e| e.to_string())) {
+        Some(key) => key,
+        None => return Err(format!("no author {}", email)),
+    };
+    let topics = try!(db::author_topics(conn, email, true).map_err(|e| e.to_string()));
+    println!("{}", email);
+    println!("  key: {}", key);
+    println!("  topics:");
+    for topic in topics {
+        println!("    {}  {}  (latest {})", topic.key, topic.topic, topic.latest.format("%Y-%m-%d"));
+    }
+    Ok(())
+}
+
+fn merge(conn: &Connection, from: &str, into: &str) -> Result<(), String> {
+    try!(distinct_authors(from, into));
+    for email in &[from, into] {
+        if try!(db::author_key(conn, email).map_err(|e| e.to_string())).is_none() {
+            return Err(format!("no author {}", email));
+        }
+    }
+    try!(db::merge_authors(conn, from, into).map_err(|e| e.to_string()));
+    println!("merged {} into {}", from, into);
+    Ok(())
+}
+
+/// Merging an author into themselves would move their notes nowhere and then delete them.
+fn distinct_authors(from: &str, into: &str) -> Result<(), String> {
+    if from.trim().to_lowercase() == into.trim().to_lowercase() {
+        Err(format!("can't merge {} into themselves", from))
+    } else {
+        Ok(())
+    }
+}
+
+fn delete(conn: &Connection, email: &str) -> Result<(), String> {
+    if try!(db::delete_author(conn, email).map_err(|e| e.to_string())) {
+        println!("put {} and all of their notes in the trash; they'll be gone for good in {} days",
+            email, ::SETTINGS.trash_days);
+        Ok(())
+    } else {
+        Err(format!("no author {} (or they're already in the trash)", email))
+    }
+}
+
+fn restore(conn: &Connection, email: &str) -> Result<(), String> {
+    if try!(db::restore_author(conn, email).map_err(|e| e.to_string())) {
+        println!("restored {}", email);
+        Ok(())
+    } else {
+        Err(format!("{} isn't in the trash", email))
+    }
+}
+
+fn rename_topic(conn: &Connection, key: &str, name: &str) -> Result<(), String> {
+    let key = try!(Uuid::parse_str(key).map_err(|_| format!("not a topic key: {}", key)));
+    if try!(db::topic_name_taken(conn, &key, name).map_err(|e| e.to_string())) {
+        return Err(format!("the author already has a topic called {}", name));
+    }
+    if try!(db::rename_topic(conn, &key, name).map_err(|e| e.to_string())) {
+        println!("renamed {} to {}", key, name);
+        Ok(())
+    } else {
+        Err(format!("no topic {}", key))
+    }
+}
+
+fn empty_subject(conn: &Connection, email: &str, rule: &str) -> Result<(), String> {
+    let rule: EmptySubject = try!(rule.parse());
+    if try!(db::set_empty_subject_rule(conn, email, rule).map_err(|e| e.to_string())) {
+        println!("notes from {} without a subject now go by {}", email, rule.as_str());
+        Ok(())
+    } else {
+        Err(format!("no author {}", email))
+    }
+}
+
+fn timezone(conn: &Connection, email: &str, name: &str) -> Result<(), String> {
+    try!(zone::parse(name));
+    if try!(db::set_timezone(conn, email, name).map_err(|e| e.to_string())) {
+        println!("{}'s dates are now shown in {}", email, name);
+        Ok(())
+    } else {
+        Err(format!("no author {}", email))
+    }
+}
+
+fn resend_welcome(conn: &Connection, email: &str) -> Result<(), String> {
+    let user_key = match try!(db::author_key(conn, email).map_err(|e| e.to_string())) {
+        Some(key) => key,
+        None => return Err(format!("no author {}", email)),
+    };
+    let (topic, topic_key) = match try!(db::first_topic(conn, email).map_err(|e| e.to_string())) {
+        Some(first) => first,
+        None => return Err(format!("{} has no topics", email)),
+    };
+    // a fresh link for anyone who hasn't confirmed their address yet
+    let token = session::new_token();
+    let confirm = if try!(db::set_confirm_token(conn, email, &session::hash_token(&token)).map_err(|e| e.to_string())) {
+        Some(&token[..])
+    } else {
+        None
+    };
+    email::welcome(&::SETTINGS, email, &topic, &topic_key, &user_key, confirm, None);
+    Ok(())
+}
+
+fn import(conn: &Connection, path: &str) -> Result<(), String> {
+    let messages = try!(read_messages(Path::new(path)).map_err(|e| format!("couldn't read {}: {}", path, e)));
+    let (mut imported, mut duplicates, mut skipped) = (0, 0, 0);
+    for raw in messages {
+        let prepared = try!(prepare_import(&raw, &|sender, message_id|
+            db::has_message_id(conn, sender, message_id).map_err(|e| e.to_string())));
+        match prepared {
+            Import::Note(note, timestamp) => {
+                try!(ingest::ingest(conn, &note, timestamp.as_ref(), ingest::Newcomer::Quiet).map_err(|e| e.to_string()));
+                imported += 1;
+            },
+            Import::Duplicate => duplicates += 1,
+            Import::NoSender => skipped += 1,
+        }
+    }
+    println!("imported {} notes", imported);
+    if duplicates > 0 {
+        println!("skipped {} messages that were imported already", duplicates);
+    }
+    if skipped > 0 {
+        println!("skipped {} messages without a sender", skipped);
+    }
+    Ok(())
+}
+
+/// What to do with one message being imported.
+enum Import {
+    Note(Note, Option<DateTime<UTC>>),
+    /// its author already has a note with its `Message-Id`, so it's been imported before
+    Duplicate,
+    NoSender,
+}
+
+/// Turn a raw message into a note to import, unless `already_imported(sender, message_id)`.
+///
+/// Ingesting a note keeps its `Message-Id`, so importing the same mailbox twice only adds
+/// each note once. Messages without one are always imported.
+fn prepare_import(raw: &[u8], already_imported: &Fn(&str, &str) -> Result<bool, String>) -> Result<Import, String> {
+    let message = Message::parse(raw);
+    let sender = match message.sender() {
+        Some(sender) => sender,
+        None => return Ok(Import::NoSender),
+    };
+    if let Some(message_id) = mail::header(&message.headers, "Message-Id") {
+        if try!(already_imported(&sender, message_id)) {
+            return Ok(Import::Duplicate);
+        }
+    }
+    let timestamp = mail::date(&message.headers).map(|d| d.with_timezone(&UTC));
+    let html = message.html();
+    let mut text = message.text();
+    if text.is_empty() {
+        text = export::to_markdown(&html);
+    }
+    let note = Note {
+        sender: sender,
+        subject: message.subject(),
+        html: html,
+        text: text,
+        headers: message.headers.clone(),
+    };
+    Ok(Import::Note(note, timestamp))
+}
+
+/// Raw messages from a Maildir (its `cur` and `new` folders) or an mbox file.
+fn read_messages(path: &Path) -> ::std::io::Result<Vec<Vec<u8>>> {
+    if !path.is_dir() {
+        let mut raw = vec![];
+        try!(try!(File::open(path)).read_to_end(&mut raw));
+        return Ok(mail::split_mbox(&raw));
+    }
+    let mut files = vec![];
+    for folder in &["cur", "new"] {
+        let folder = path.join(folder);
+        if !folder.is_dir() {
+            continue;
+        }
+        for entry in try!(fs::read_dir(folder)) {
+            files.push(try!(entry).path());
+        }
+    }
+    files.sort();  // maildir names start with the delivery time
+    let mut messages = vec![];
+    for file in files {
+        let mut raw = vec![];
+        try!(try!(File::open(file)).read_to_end(&mut raw));
+        messages.push(raw);
+    }
+    Ok(messages)
+}
+
+fn encrypt_notes(conn: &Connection, batch: &str) -> Result<(), String> {
+    let batch: i64 = match batch.parse() {
+        Ok(n) if n > 0 => n,
+        _ => return Err(format!("batch size must be a positive number, not {}", batch)),
+    };
+    if ::SETTINGS.encryption.is_none() {
+        return Err("set encryption.master_key (or $MASTER_KEY) first".to_string());
+    }
+    let mut total = 0;
+    loop {
+        let encrypted = try!(db::encrypt_stored_notes(conn, batch).map_err(|e| e.to_string()));
+        if encrypted == 0 {
+            break;
+        }
+        total += encrypted;
+        println!("encrypted {} notes and earlier versions so far", total);
+    }
+    println!("every note is encrypted at rest");
+    Ok(())
+}
+
+fn rotate_master_key(conn: &Connection) -> Result<(), String> {
+    match ::SETTINGS.encryption {
+        Some(ref masters) if masters.previous.is_some() => {},
+        _ => return Err("set encryption.previous_master_key to the old key and encryption.master_key to the new one first".to_string()),
+    }
+    let rewrapped = try!(db::rewrap_data_keys(conn).map_err(|e| e.to_string()));
+    println!("re-wrapped {} data keys; encryption.previous_master_key can go now", rewrapped);
+    Ok(())
+}
+
+fn stats(conn: &Connection) -> Result<(), String> {
+    let stats = try!(db::stats(conn).map_err(|e| e.to_string()));
+    println!("authors: {}", stats.authors);
+    println!("topics: {}", stats.topics);
+    println!("posts: {}", stats.posts);
+    println!("posts this week: {}", stats.posts_this_week);
+    Ok(())
+}
+
+
+#[test]
+fn test_distinct_authors() {
+    assert!(distinct_authors("old@example.com", "new@example.com").is_ok());
+    assert!(distinct_authors("me@example.com", "me@example.com").is_err());
+    assert!(distinct_authors("Me@Example.com", "me@example.com ").is_err());
+}
+
+#[test]
+fn test_import_twice() {
+    use std::cell::RefCell;
+    use db::{Post, Topic};
+
+    let mbox = b"From someone Tue Oct 18 21:30:12 2016\n\
+                 From: Me <me@example.com>\n\
+                 Subject: one\n\
+                 Message-Id: <1@example.com>\n\
+                 \n\
+                 first\n\
+                 From someone Tue Oct 18 21:31:12 2016\n\
+                 From: Me <me@example.com>\n\
+                 Subject: two\n\
+                 \n\
+                 no message id\n\
+                 From someone Tue Oct 18 21:32:12 2016\n\
+                 From: Someone Else <else@example.com>\n\
+                 Subject: three\n\
+                 Message-Id: <1@example.com>\n\
+                 \n\
+                 same id, different author\n";
+    // stands in for the posts table: each note's author and id, and the Message-Id it came with
+    let stored: RefCell<Vec<(String, Uuid, Option<String>)>> = RefCell::new(vec![]);
+    let import = |mbox: &[u8]| mail::split_mbox(mbox).iter().map(|raw| {
+        let seen = |sender: &str, id: &str| Ok(stored.borrow().iter().any(|&(ref author, post, ref message_id)|
+            author == sender && (message_id.as_ref().map_or(false, |m| m == id) || export::exported_post_id(id) == Some(post))));
+        match prepare_import(raw, &seen).unwrap() {
+            Import::Note(note, _) => {
+                let id = mail::header(&note.headers, "Message-Id").map(|id| id.to_string());
+                let post = Uuid::parse_str(&format!("7c1b0e0c-3c55-4a4b-9a3f-{:012}", stored.borrow().len())).unwrap();
+                stored.borrow_mut().push((note.sender.clone(), post, id));
+                "note"
+            },
+            Import::Duplicate => "duplicate",
+            Import::NoSender => "no sender",
+        }
+    }).collect::<Vec<_>>();
+    assert_eq!(import(mbox), vec!["note", "note", "note"]);
+    assert_eq!(import(mbox), vec!["duplicate", "note", "duplicate"]);
+
+    // an export of those notes, imported back in, is all duplicates
+    let mine = stored.borrow().iter()
+        .filter(|&&(ref author, _, _)| author == "me@example.com")
+        .map(|&(_, id, _)| Post { id: id, body: "<p>hi</p>".to_string(), timestamp: UTC::now(), edited: None })
+        .collect::<Vec<_>>();
+    let topic = Topic { key: Uuid::parse_str("0f9d2b4f-d1a2-4a4b-9a3f-7c1b0e0c3c55").unwrap(), topic: "one".to_string(), latest: UTC::now() };
+    let exported = export::mbox("me@example.com", "note@write-only.space", vec![(topic, mine)]);
+    assert_eq!(import(exported.as_bytes()), vec!["duplicate"; 3]);
+}
@@ -0,0 +1,140 @@
+//! Just enough of the zip format to hand someone a folder of text files.
+//!
+//! Entries are stored uncompressed, and every byte of the output comes from the entries
+//! themselves (no "now" timestamps), so the same notes always produce the same archive.
+
+use chrono::{DateTime, Datelike, Timelike, UTC};
+
+
+struct Entry {
+    name: String,
+    crc: u32,
+    size: u32,
+    time: u16,
+    date: u16,
+    offset: u32,
+}
+
+
+pub struct Zip {
+    out: Vec<u8>,
+    entries: Vec<Entry>,
+}
+
+impl Zip {
+    pub fn new() -> Zip {
+        Zip { out: vec![], entries: vec![] }
+    }
+
+    pub fn add(&mut self, name: &str, modified: &DateTime<UTC>, data: &[u8]) {
+        let (time, date) = dos_datetime(modified);
+        let entry = Entry {
+            name: name.to_string(),
+            crc: crc32(data),
+            size: data.len() as u32,
+            time: time,
+            date: date,
+            offset: self.out.len() as u32,
+        };
+        u32le(&mut self.out, 0x04034b50);  // local file header
+        u16le(&mut self.out, 10);          // version needed: plain stored files
+        u16le(&mut self.out, 0x0800);      // flags: utf-8 names
+        u16le(&mut self.out, 0);           // stored, no compression
+        u16le(&mut self.out, entry.time);
+        u16le(&mut self.out, entry.date);
+        u32le(&mut self.out, entry.crc);
+        u32le(&mut self.out, entry.size);  // compressed size
+        u32le(&mut self.out, entry.size);
+        u16le(&mut self.out, entry.name.len() as u16);
+        u16le(&mut self.out, 0);           // extra field length
+        self.out.extend_from_slice(entry.name.as_bytes());
+        self.out.extend_from_slice(data);
+        self.entries.push(entry);
+    }
+
+    pub fn finish(mut self) -> Vec<u8> {
+        let directory_start = self.out.len() as u32;
+        for entry in &self.entries {
+            u32le(&mut self.out, 0x02014b50);  // central directory header
+            u16le(&mut self.out, 20);          // made by
+            u16le(&mut self.out, 10);          // version needed
+            u16le(&mut self.out, 0x0800);
+            u16le(&mut self.out, 0);
+            u16le(&mut self.out, entry.time);
+            u16le(&mut self.out, entry.date);
+            u32le(&mut self.out, entry.crc);
+            u32le(&mut self.out, entry.size);
+            u32le(&mut self.out, entry.size);
+            u16le(&mut self.out, entry.name.len() as u16);
+            u16le(&mut self.out, 0);           // extra field length
+            u16le(&mut self.out, 0);           // comment length
+            u16le(&mut self.out, 0);           // disk number
+            u16le(&mut self.out, 0);           // internal attributes
+            u32le(&mut self.out, 0);           // external attributes
+            u32le(&mut self.out, entry.offset);
+            self.out.extend_from_slice(entry.name.as_bytes());
+        }
+        let directory_size = self.out.len() as u32 - directory_start;
+        u32le(&mut self.out, 0x06054b50);  // end of central directory
+        u16le(&mut self.out, 0);
+        u16le(&mut self.out, 0);
+        u16le(&mut self.out, self.entries.len() as u16);
+        u16le(&mut self.out, self.entries.len() as u16);
+        u32le(&mut self.out, directory_size);
+        u32le(&mut self.out, directory_start);
+        u16le(&mut self.out, 0);           // comment length
+        self.out
+    }
+}
+
+
+fn u16le(out: &mut Vec<u8>, n: u16) {
+    out.push(n as u8);
+    out.push((n >> 8) as u8);
+}
+
+fn u32le(out: &mut Vec<u8>, n: u32) {
+    u16le(out, n as u16);
+    u16le(out, (n >> 16) as u16);
+}
+
+/// MS-DOS `(time, date)`, which can't go earlier than 1980.
+fn dos_datetime(t: &DateTime<UTC>) -> (u16, u16) {
+    if t.year() < 1980 {
+        return (0, (1 << 5) | 1);
+    }
+    let time = (t.hour() << 11) | (t.minute() << 5) | (t.second() / 2);
+    let date = (((t.year() - 1980) as u32) << 9) | (t.month() << 5) | t.day();
+    (time as u16, date as u16)
+}
+
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 == 1 { (crc >> 1) ^ 0xedb88320 } else { crc >> 1 };
+        }
+    }
+    !crc
+}
+
+
+#[test]
+fn test_zip() {
+    use chrono::TimeZone;
+    assert_eq!(crc32(b"123456789"), 0xcbf43926);
+
+    let when = UTC.ymd(2016, 10, 18).and_hms(21, 30, 12);
+    assert_eq!(dos_datetime(&when), ((21 << 11) | (30 << 5) | 6, (36 << 9) | (10 << 5) | 18));
+
+    let build = || {
+        let mut zip = Zip::new();
+        zip.add("a/note.md", &when, b"hello");
+        zip.finish()
+    };
+    let bytes = build();
+    assert_eq!(bytes, build());
+    assert_eq!(&bytes[..4], &[0x50, 0x4b, 0x03, 0x04]);
+    assert_eq!(bytes.len(), (30 + 9 + 5) + (46 + 9) + 22);
+}
@@ -0,0 +1,204 @@
+//! Encrypting note bodies at rest, so a copy of the database isn't a copy of everyone's notes.
+//!
+//! Each author gets random data keys, stored wrapped (AES-256-GCM) by the master key from the
+//! settings, and their notes and earlier versions of notes are encrypted with AES-256-GCM
+//! under one of those. A stored body names the data key that opens it, so a new master key
+//! only means re-wrapping the data keys, not re-encrypting every note; and once an author is
+//! gone for good, so are their keys.
+//!
+//! Search works from keyed hashes: an encrypted note is indexed by an HMAC of each of its
+//! (stemmed) words under a key derived from its data key, and a search looks for the HMACs of
+//! its words under each of the author's keys. The index shows which of an author's notes share
+//! words, and how many different words each has, but not what they are.
+//!
+//! This only covers bodies. Topic names, addresses and dates stay as they were.
+
+use crypto::aead::{AeadDecryptor, AeadEncryptor};
+use crypto::aes::KeySize;
+use crypto::aes_gcm::AesGcm;
+use crypto::hmac::Hmac;
+use crypto::mac::Mac;
+use crypto::sha2::Sha256;
+use rand::{OsRng, Rng};
+use rustc_serialize::base64::{FromBase64, ToBase64, STANDARD};
+use rustc_serialize::hex::ToHex;
+use uuid::Uuid;
+
+
+/// Marks a stored body as encrypted at rest, and which scheme did it.
+pub const PREFIX: &'static str = "enc1:";
+
+/// How long master and data keys are.
+pub const KEY_BYTES: usize = 32;
+const NONCE_BYTES: usize = 12;
+const TAG_BYTES: usize = 16;
+
+/// Associated data for wrapped keys, so a wrapped key can't pass for a note or vice versa.
+const WRAP_AAD: &'static [u8] = b"write-only data key";
+
+/// What a data key's search key is derived with, so the two are never the same key.
+const SEARCH_INFO: &'static [u8] = b"write-only search index";
+
+/// How much of each word's HMAC is kept: plenty to keep different words apart.
+const BLIND_BYTES: usize = 16;
+
+
+pub fn new_data_key() -> Vec<u8> {
+    let mut key = vec![0u8; KEY_BYTES];
+    OsRng::new()
+        .expect("the OS can provide randomness")
+        .fill_bytes(&mut key);
+    key
+}
+
+/// A data key, encrypted with a master key for storing.
+pub fn wrap(master: &[u8], key: &[u8]) -> Vec<u8> {
+    seal(master, WRAP_AAD, key)
+}
+
+/// A stored data key, if `master` is the one that wrapped it.
+pub fn unwrap(master: &[u8], wrapped: &[u8]) -> Option<Vec<u8>> {
+    open(master, WRAP_AAD, wrapped)
+}
+
+/// Which data key a stored body was encrypted with, if it was.
+pub fn key_id(stored: &str) -> Option<Uuid> {
+    if !stored.starts_with(PREFIX) {
+        return None;
+    }
+    stored[PREFIX.len()..].split(':').next().and_then(|id| Uuid::parse_str(id).ok())
+}
+
+/// A body as it's stored: encrypted with the data key `key`, whose id is `key_id`.
+pub fn encrypt(key_id: &Uuid, key: &[u8], body: &str) -> String {
+    let id = key_id.to_string();
+    format!("{}{}:{}", PREFIX, id, seal(key, id.as_bytes(), body.as_bytes()).to_base64(STANDARD))
+}
+
+/// A stored body as it was written, if `key` is the data key it names.
+pub fn decrypt(key: &[u8], stored: &str) -> Option<String> {
+    if !stored.starts_with(PREFIX) {
+        return None;
+    }
+    let rest = &stored[PREFIX.len()..];
+    let colon = match rest.find(':') {
+        Some(i) => i,
+        None => return None,
+    };
+    let (id, sealed) = (&rest[..colon], &rest[colon + 1..]);
+    sealed.from_base64().ok()
+        .and_then(|sealed| open(key, id.as_bytes(), &sealed))
+        .and_then(|body| String::from_utf8(body).ok())
+}
+
+
+/// The words in a search vector or query, as Postgres writes them (`'cat':2 'run':1`, or
+/// `'cat' & 'run'`), each replaced by a keyed hash that only matches the same word under the
+/// same data key. The hashes are plain lexemes themselves, ready to go back in either.
+pub fn blind_words(key: &[u8], words: &str) -> Vec<String> {
+    let search_key = hmac(key, SEARCH_INFO);
+    lexemes(words)
+        .iter()
+        .map(|word| format!("h{}", hmac(&search_key, word.as_bytes())[..BLIND_BYTES].to_hex()))
+        .collect()
+}
+
+/// The quoted lexemes in Postgres' text form of a tsvector or tsquery.
+fn lexemes(words: &str) -> Vec<String> {
+    let mut found = vec![];
+    let mut chars = words.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c != '\'' {
+            continue;
+        }
+        let mut lexeme = String::new();
+        while let Some(c) = chars.next() {
+            match c {
+                // quotes inside are doubled
+                '\'' => if chars.peek() == Some(&'\'') {
+                    chars.next();
+                    lexeme.push('\'');
+                } else {
+                    break;
+                },
+                '\\' => if let Some(escaped) = chars.next() {
+                    lexeme.push(escaped);
+                },
+                _ => lexeme.push(c),
+            }
+        }
+        found.push(lexeme);
+    }
+    found
+}
+
+fn hmac(key: &[u8], message: &[u8]) -> Vec<u8> {
+    let mut mac = Hmac::new(Sha256::new(), key);
+    mac.input(message);
+    mac.result().code().to_vec()
+}
+
+
+/// AES-256-GCM with a random nonce: nonce ‖ ciphertext ‖ tag.
+fn seal(key: &[u8], aad: &[u8], plaintext: &[u8]) -> Vec<u8> {
+    let mut nonce = [0u8; NONCE_BYTES];
+    OsRng::new()
+        .expect("the OS can provide randomness")
+        .fill_bytes(&mut nonce);
+    let mut sealed = vec![0u8; plaintext.len()];
+    let mut tag = [0u8; TAG_BYTES];
+    AesGcm::new(KeySize::KeySize256, key, &nonce, aad)
+        .encrypt(plaintext, &mut sealed, &mut tag);
+
+    let mut out = Vec::with_capacity(NONCE_BYTES + sealed.len() + TAG_BYTES);
+    out.extend_from_slice(&nonce);
+    out.extend_from_slice(&sealed);
+    out.extend_from_slice(&tag);
+    out
+}
+
+fn open(key: &[u8], aad: &[u8], sealed: &[u8]) -> Option<Vec<u8>> {
+    if key.len() != KEY_BYTES || sealed.len() < NONCE_BYTES + TAG_BYTES {
+        return None;
+    }
+    let (nonce, rest) = sealed.split_at(NONCE_BYTES);
+    let (ciphertext, tag) = rest.split_at(rest.len() - TAG_BYTES);
+    let mut plaintext = vec![0u8; ciphertext.len()];
+    if AesGcm::new(KeySize::KeySize256, key, nonce, aad).decrypt(ciphertext, &mut plaintext, tag) {
+        Some(plaintext)
+    } else {
+        None
+    }
+}
+
+
+#[test]
+fn test_at_rest() {
+    let master = [1u8; KEY_BYTES];
+    let key = new_data_key();
+    let wrapped = wrap(&master, &key);
+    assert_eq!(unwrap(&master, &wrapped), Some(key.clone()));
+    assert_eq!(unwrap(&[2u8; KEY_BYTES], &wrapped), None);
+
+    let id = Uuid::parse_str("7c1b0e0c-3c55-4a4b-9a3f-0f9d2b4fd1a2").unwrap();
+    let stored = encrypt(&id, &key, "<p>dear diary</p>");
+    assert_eq!(key_id(&stored), Some(id));
+    assert!(!stored.contains("diary"));
+    assert_eq!(decrypt(&key, &stored), Some("<p>dear diary</p>".to_string()));
+    assert_eq!(decrypt(&new_data_key(), &stored), None);
+
+    // the key id is authenticated, so a body can't be pointed at another key
+    let moved = stored.replace("7c1b0e0c", "00000000");
+    assert_eq!(decrypt(&key, &moved), None);
+
+    assert_eq!(key_id("<p>from before</p>"), None);
+    assert_eq!(decrypt(&key, "<p>from before</p>"), None);
+
+    // the same word blinds the same way under one key, whether it's in a note or a search
+    let indexed = blind_words(&key, "'cat':2 'dog''s':4,7 'run':1");
+    assert_eq!(indexed.len(), 3);
+    assert!(indexed.iter().all(|word| !word.contains("cat") && !word.contains("run")));
+    assert_eq!(blind_words(&key, "'run' & 'cat'"), vec![indexed[2].clone(), indexed[0].clone()]);
+    assert!(blind_words(&new_data_key(), "'cat'") != vec![indexed[0].clone()]);
+    assert_eq!(lexemes("'dog''s':4,7 'back\\\\slash'"), vec!["dog's".to_string(), "back\\slash".to_string()]);
+}
@@ -0,0 +1,187 @@
+//! Settings changed by email: a subject starting with `!` and a command's name is a command,
+//! not a note.
+
+use chrono::Duration;
+use postgres::Connection;
+use postgres::error::Error;
+
+use db::{self, Visibility};
+use e2e;
+use ingest::Note;
+use mail;
+use sanitize;
+use subject;
+use zone;
+
+
+pub const HELP: &'static str = "Commands go in the subject line: \"!timezone America/Toronto\" shows your dates in that timezone, \"!fade 30d Some topic\" makes a topic's notes fade away after 30 days (\"!fade never Some topic\" keeps them), \"!fade-warnings on\" emails you the day before notes fade, \"!private Some topic\" hides a topic from everyone but you (\"!unlisted Some topic\" lets anyone with its link read it again), and replying to a note with \"!edit\" as the subject replaces it with your reply.";
+
+const NO_AUTHOR: &'static str = "Send a note first, then your settings will have somewhere to go.";
+
+
+pub enum Command {
+    Timezone(String),
+    /// a topic, and how long its notes last (`None` for forever)
+    Fade(String, Option<Duration>),
+    FadeWarnings(bool),
+    /// a topic, and who can read it
+    Visibility(String, Visibility),
+    /// replace the note being replied to with this one
+    Edit,
+}
+
+
+/// The command in a (normalized) subject line, or `None` if it's just a note (including ones
+/// like "!important" that only start with a `!`).
+pub fn parse(subject: &str) -> Option<Result<Command, String>> {
+    if !subject.starts_with('!') {
+        return None;
+    }
+    let mut words = subject[1..].splitn(2, ' ');
+    let name = words.next().unwrap_or("").to_lowercase();
+    let arg = words.next().unwrap_or("").trim();
+    Some(match &name[..] {
+        "timezone" | "tz" => zone::parse(arg).map(|_| Command::Timezone(arg.to_string())),
+        "fade" => parse_fade(arg),
+        "fade-warnings" => match &arg.to_lowercase()[..] {
+            "on" | "yes" => Ok(Command::FadeWarnings(true)),
+            "off" | "no" => Ok(Command::FadeWarnings(false)),
+            _ => Err("Say \"!fade-warnings on\" or \"!fade-warnings off\".".to_string()),
+        },
+        "edit" => Ok(Command::Edit),
+        "private" | "unlisted" => {
+            let topic = subject::normalize(arg);
+            if topic.is_empty() {
+                Err(format!("Say which topic, like \"!{} Some topic\".", name))
+            } else {
+                name.parse().map(|visibility| Command::Visibility(topic, visibility))
+            }
+        },
+        "help" => Err(HELP.to_string()),
+        _ => return None,
+    })
+}
+
+/// `never <topic>`, `30d <topic>`, or `30 days <topic>`.
+fn parse_fade(arg: &str) -> Result<Command, String> {
+    let words: Vec<&str> = arg.split(' ').collect();
+    let (lifetime, topic) = if words[0].to_lowercase() == "never" {
+        (None, words[1..].join(" "))
+    } else if let Some(lifetime) = subject::parse_lifetime(words[0]) {
+        (Some(lifetime), words[1..].join(" "))
+    } else if let Some(lifetime) = words.get(1).and_then(|unit| subject::parse_lifetime(&format!("{} {}", words[0], unit))) {
+        (Some(lifetime), words[2..].join(" "))
+    } else {
+        return Err("Say how long notes should last, like \"!fade 30d Some topic\" (or h for hours, w for weeks).".to_string());
+    };
+    let topic = subject::normalize(&topic);
+    if topic.is_empty() {
+        return Err("Say which topic should fade, like \"!fade 30d Some topic\".".to_string());
+    }
+    Ok(Command::Fade(topic, lifetime))
+}
+
+
+/// Carry out the command in an author's email, returning what to tell them.
+pub fn run(conn: &Connection, note: &Note, command: Command) -> Result<String, Error> {
+    let author = &note.sender[..];
+    Ok(match command {
+        Command::Timezone(name) =>
+            if try!(db::set_timezone(conn, author, &name)) {
+                format!("Your dates will now be shown in {}.", name)
+            } else {
+                NO_AUTHOR.to_string()
+            },
+        Command::Fade(topic, lifetime) =>
+            if !try!(db::set_topic_fade(conn, author, &topic, lifetime.map(|l| l.num_seconds()))) {
+                format!("You don't have a topic called {}.", topic)
+            } else if let Some(lifetime) = lifetime {
+                format!("Notes in {} will now fade away {} after they're posted, including the ones already there.",
+                    topic, describe(&lifetime))
+            } else {
+                format!("Notes in {} will stay put.", topic)
+            },
+        Command::FadeWarnings(on) =>
+            if !try!(db::set_fade_warnings(conn, author, on)) {
+                NO_AUTHOR.to_string()
+            } else if on {
+                "You'll get an email the day before any of your notes fade.".to_string()
+            } else {
+                "Your notes will fade quietly.".to_string()
+            },
+        Command::Visibility(topic, visibility) =>
+            if !try!(db::set_topic_visibility(conn, author, &topic, visibility)) {
+                format!("You don't have a topic called {}.", topic)
+            } else if visibility == Visibility::Private {
+                format!("{} is now private: only you can read it, after signing in.", topic)
+            } else {
+                format!("{} is unlisted again: anyone with its link can read it.", topic)
+            },
+        Command::Edit => try!(edit(conn, note)),
+    })
+}
+
+fn edit(conn: &Connection, note: &Note) -> Result<String, Error> {
+    let replying_to = match mail::header(&note.headers, "In-Reply-To") {
+        Some(id) => id,
+        None => return Ok("To edit a note, reply to the email you sent it in, with \"!edit\" as the subject.".to_string()),
+    };
+    let post = match try!(db::post_by_message_id(conn, &note.sender, replying_to.trim())) {
+        Some(post) => post,
+        None => return Ok("Couldn't find the note you replied to (or it's in the trash). You can also edit notes on the web after signing in.".to_string()),
+    };
+    let mut body = sanitize::clean(&note.html);
+    if body.trim().is_empty() {
+        return Ok("Your reply was empty, so the note was left alone.".to_string());
+    }
+    if let Some(public_key) = try!(db::post_public_key(conn, &post)) {
+        body = e2e::seal(&public_key, &body);
+    }
+    try!(db::edit_post(conn, &note.sender, &post, &body));
+    Ok("Your note was updated. Its earlier version is in its history.".to_string())
+}
+
+fn describe(lifetime: &Duration) -> String {
+    let hours = lifetime.num_hours();
+    match (hours % (24 * 7), hours % 24) {
+        (0, _) => format!("{} week(s)", hours / (24 * 7)),
+        (_, 0) => format!("{} day(s)", hours / 24),
+        _ => format!("{} hour(s)", hours),
+    }
+}
+
+
+#[test]
+fn test_parse() {
+    assert!(parse("just a note").is_none());
+    match parse("!timezone America/Toronto") {
+        Some(Ok(Command::Timezone(ref name))) => assert_eq!(name, "America/Toronto"),
+        _ => panic!("expected a timezone command"),
+    }
+    assert!(parse("!timezone Mars/Olympus_Mons").unwrap().is_err());
+    match parse("!fade 30 days Some  topic") {
+        Some(Ok(Command::Fade(ref topic, Some(lifetime)))) => {
+            assert_eq!(topic, "Some topic");
+            assert_eq!(lifetime, Duration::days(30));
+        },
+        _ => panic!("expected a fade command"),
+    }
+    match parse("!fade never Some topic") {
+        Some(Ok(Command::Fade(ref topic, None))) => assert_eq!(topic, "Some topic"),
+        _ => panic!("expected a fade command"),
+    }
+    assert!(parse("!fade 30d").unwrap().is_err());
+    assert!(parse("!fade 99999999999999w Some topic").unwrap().is_err());
+    match parse("!private  Dream  journal") {
+        Some(Ok(Command::Visibility(ref topic, Visibility::Private))) => assert_eq!(topic, "Dream journal"),
+        _ => panic!("expected a visibility command"),
+    }
+    assert!(parse("!unlisted").unwrap().is_err());
+    match parse("!edit") {
+        Some(Ok(Command::Edit)) => {},
+        _ => panic!("expected an edit command"),
+    }
+    assert!(parse("!help").unwrap().is_err());
+    assert!(parse("!important").is_none());
+    assert!(parse("!!! big news").is_none());
+}
@@ -1,4 +1,1902 @@
+use std::collections::HashMap;
+use std::io;
+use std::str::FromStr;
+
+use chrono::{DateTime, UTC};
+use chrono_tz::Tz;
+use postgres::Connection;
+use postgres::error::Error;
+use postgres::rows::Row;
 use r2d2;
-use r2d2_postgres::{PostgresConnectionManager};
+use r2d2_postgres::{SslMode, PostgresConnectionManager};
+use uuid::Uuid;
+
+use at_rest;
+use export;
+use sender;
+use session;
+use subject::EmptySubject;
+use zone;
 
+pub type PostgresPool = r2d2::Pool<PostgresConnectionManager>;
 pub type PostgresConnection = r2d2::PooledConnection<PostgresConnectionManager>;
+
+
+pub fn get_pool(uri: &str, size: u32) -> Result<PostgresPool, String> {
+    let config = r2d2::Config::builder()
+        .pool_size(size)
+        .build();
+    let manager = try!(PostgresConnectionManager::new(uri, SslMode::None)
+        .map_err(|err| err.to_string()));
+    r2d2::Pool::new(config, manager)
+        .map_err(|err| err.to_string())
+}
+
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct Topic {
+    pub key: Uuid,
+    pub topic: String,
+    pub latest: DateTime<UTC>,
+}
+
+impl Topic {
+    fn from_row(row: Row) -> Topic {
+        Topic {
+            key: row.get("key"),
+            topic: row.get("topic"),
+            latest: DateTime::from_utc(row.get("latest"), UTC),
+        }
+    }
+}
+
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct Post {
+    pub id: Uuid,
+    pub body: String,
+    pub timestamp: DateTime<UTC>,
+    /// when it was last changed, if ever
+    pub edited: Option<DateTime<UTC>>,
+}
+
+impl Post {
+    fn from_row(row: &Row, keys: &mut Keyring) -> Result<Post, Error> {
+        Ok(Post {
+            id: row.get("id"),
+            body: try!(keys.decrypt(row.get("body"))),
+            timestamp: DateTime::from_utc(row.get("timestamp"), UTC),
+            edited: row.get::<_, Option<_>>("edited_at").map(|t| DateTime::from_utc(t, UTC)),
+        })
+    }
+}
+
+
+/// The data keys that note bodies are encrypted at rest with, unwrapped as they're needed and
+/// kept for as long as the keyring is.
+struct Keyring<'a> {
+    conn: &'a Connection,
+    keys: HashMap<Uuid, Vec<u8>>,
+    /// which key each author's new notes get
+    current: HashMap<String, Uuid>,
+}
+
+impl<'a> Keyring<'a> {
+    fn new(conn: &'a Connection) -> Keyring<'a> {
+        Keyring { conn: conn, keys: HashMap::new(), current: HashMap::new() }
+    }
+
+    /// A stored body as it was written.
+    fn decrypt(&mut self, stored: String) -> Result<String, Error> {
+        let id = match at_rest::key_id(&stored) {
+            Some(id) => id,
+            None => return Ok(stored),  // from before encryption at rest, or without it
+        };
+        let body = {
+            let key = try!(self.key(&id));
+            at_rest::decrypt(key, &stored)
+        };
+        body.ok_or_else(|| unreadable(format!("a note didn't decrypt with its data key {}", id)))
+    }
+
+    /// A body as it should be stored for `author`: encrypted with their current data key, if
+    /// there's a master key to make one with.
+    fn encrypt(&mut self, author: &str, body: &str) -> Result<String, Error> {
+        let masters = match ::SETTINGS.encryption {
+            Some(ref masters) => masters,
+            None => return Ok(body.to_string()),
+        };
+        let cached = self.current.get(author).cloned();
+        let id = match cached {
+            Some(id) => id,
+            None => {
+                let latest = try!(self.conn.query("
+                    SELECT id FROM data_key
+                    WHERE author = $1
+                    ORDER BY created DESC
+                    LIMIT 1", &[&author]))
+                    .into_iter()
+                    .map(|row| row.get("id"))
+                    .next();
+                let id = match latest {
+                    Some(id) => id,
+                    None => try!(self.new_key(&masters.current, Some(author))),
+                };
+                self.current.insert(author.to_string(), id);
+                id
+            },
+        };
+        let key = try!(self.key(&id));
+        Ok(at_rest::encrypt(&id, key, body))
+    }
+
+    /// Bodies encrypted with a new data key that belongs to no author, for notes from someone
+    /// who might not be one, along with the key's id so the key can go when the notes do.
+    /// Without a master key, they're returned as they are.
+    fn encrypt_loose(&mut self, bodies: &[&str]) -> Result<(Option<Uuid>, Vec<String>), Error> {
+        let masters = match ::SETTINGS.encryption {
+            Some(ref masters) => masters,
+            None => return Ok((None, bodies.iter().map(|body| body.to_string()).collect())),
+        };
+        let id = try!(self.new_key(&masters.current, None));
+        let key = try!(self.key(&id));
+        Ok((Some(id), bodies.iter().map(|body| at_rest::encrypt(&id, key, body)).collect()))
+    }
+
+    /// The search vector for a body as it's stored, as text (for `$n::text::tsvector`): its
+    /// words, or if it's encrypted, keyed hashes of them.
+    fn search_vector(&mut self, stored: &str, body: &str) -> Result<String, Error> {
+        let words: String = try!(self.conn.query("
+            SELECT to_tsvector('english', post_text($1))::text as words", &[&body]))
+            .into_iter()
+            .map(|row| row.get("words"))
+            .next()
+            .unwrap();  // SELECT without FROM is always one row
+        let id = match at_rest::key_id(stored) {
+            Some(id) => id,
+            None => return Ok(words),
+        };
+        let key = try!(self.key(&id));
+        Ok(at_rest::blind_words(key, &words).join(" "))
+    }
+
+    /// A search for an author's encrypted notes, as text (for `$n::text::tsquery`): the
+    /// query's words hashed under each of their data keys. `None` if there's nothing to find.
+    fn search_query(&mut self, author: &str, query: &str) -> Result<Option<String>, Error> {
+        let words: String = try!(self.conn.query("
+            SELECT plainto_tsquery('english', $1)::text as words", &[&query]))
+            .into_iter()
+            .map(|row| row.get("words"))
+            .next()
+            .unwrap();  // SELECT without FROM is always one row
+        let ids: Vec<Uuid> = try!(self.conn.query("SELECT id FROM data_key WHERE author = $1", &[&author]))
+            .into_iter()
+            .map(|row| row.get("id"))
+            .collect();
+        let mut alternatives = vec![];
+        for id in ids {
+            let blinded = at_rest::blind_words(try!(self.key(&id)), &words);
+            if !blinded.is_empty() {
+                alternatives.push(format!("({})", blinded.join(" & ")));
+            }
+        }
+        Ok(if alternatives.is_empty() { None } else { Some(alternatives.join(" | ")) })
+    }
+
+    fn new_key(&mut self, master: &[u8], author: Option<&str>) -> Result<Uuid, Error> {
+        let key = at_rest::new_data_key();
+        let id: Uuid = try!(self.conn.query("
+            INSERT INTO data_key (author, wrapped)
+            VALUES ($1, $2)
+            RETURNING id",
+            &[&author, &at_rest::wrap(master, &key)]))
+            .into_iter()
+            .map(|row| row.get("id"))
+            .next()
+            .unwrap();  // INSERT ... RETURNING always gives back the row
+        self.keys.insert(id, key);
+        Ok(id)
+    }
+
+    fn key(&mut self, id: &Uuid) -> Result<&Vec<u8>, Error> {
+        if !self.keys.contains_key(id) {
+            let wrapped: Vec<u8> = match try!(self.conn.query("SELECT wrapped FROM data_key WHERE id = $1", &[id]))
+                .into_iter()
+                .map(|row| row.get("wrapped"))
+                .next() {
+                Some(wrapped) => wrapped,
+                None => return Err(unreadable(format!("data key {} is missing", id))),
+            };
+            let key = match ::SETTINGS.encryption {
+                Some(ref masters) => at_rest::unwrap(&masters.current, &wrapped)
+                    .or_else(|| masters.previous.as_ref().and_then(|previous| at_rest::unwrap(previous, &wrapped))),
+                None => None,
+            };
+            match key {
+                Some(key) => { self.keys.insert(*id, key); },
+                None => return Err(unreadable(format!("no master key unwraps data key {}", id))),
+            }
+        }
+        Ok(&self.keys[id])
+    }
+}
+
+/// Stored data that can't be read back, as the kind of error everything here returns.
+fn unreadable(why: String) -> Error {
+    Error::Conversion(Box::new(io::Error::new(io::ErrorKind::InvalidData, why)))
+}
+
+
+/// An earlier version of an edited post.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Revision {
+    pub body: String,
+    /// when the next version took its place
+    pub replaced: DateTime<UTC>,
+}
+
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct SearchHit {
+    pub post: Uuid,
+    pub timestamp: DateTime<UTC>,
+    /// plain text with matches wrapped in `<mark>`
+    pub snippet: String,
+}
+
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct Author {
+    pub email: String,
+    pub key: Uuid,
+    pub joined: DateTime<UTC>,
+}
+
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct Session {
+    pub id: Uuid,
+    pub author: String,
+    pub created: DateTime<UTC>,
+    pub last_seen: DateTime<UTC>,
+    pub user_agent: Option<String>,
+}
+
+impl Session {
+    fn from_row(row: Row) -> Session {
+        Session {
+            id: row.get("id"),
+            author: row.get("author"),
+            created: DateTime::from_utc(row.get("created"), UTC),
+            last_seen: DateTime::from_utc(row.get("last_seen"), UTC),
+            user_agent: row.get("user_agent"),
+        }
+    }
+}
+
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct ShareLink {
+    pub key: Uuid,
+    /// who it was given to
+    pub label: String,
+    pub created: DateTime<UTC>,
+    pub expires: Option<DateTime<UTC>>,
+    pub revoked: Option<DateTime<UTC>>,
+    pub last_used: Option<DateTime<UTC>>,
+}
+
+
+/// An end-to-end encrypted topic's keys, as the author's browser made them.
+#[derive(Debug, PartialEq, Eq)]
+pub struct E2eKey {
+    pub public_key: Vec<u8>,
+    /// the private key, encrypted with a key from the author's passphrase
+    pub wrapped_private_key: Vec<u8>,
+    pub salt: Vec<u8>,
+    pub iv: Vec<u8>,
+}
+
+
+/// Who can read a topic.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Visibility {
+    /// anyone with its link
+    Unlisted,
+    /// anyone with its link and its passphrase
+    Protected,
+    /// only its signed-in author
+    Private,
+}
+
+impl Visibility {
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            Visibility::Unlisted => "unlisted",
+            Visibility::Protected => "protected",
+            Visibility::Private => "private",
+        }
+    }
+}
+
+impl FromStr for Visibility {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Visibility, String> {
+        match s {
+            "unlisted" => Ok(Visibility::Unlisted),
+            "protected" => Ok(Visibility::Protected),
+            "private" => Ok(Visibility::Private),
+            other => Err(format!("unknown visibility `{}` (try unlisted, protected or private)", other)),
+        }
+    }
+}
+
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Removal {
+    Deleted,
+    /// its notes reached the end of their lifetime
+    Faded,
+}
+
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct Stats {
+    pub authors: i64,
+    pub topics: i64,
+    pub posts: i64,
+    pub posts_this_week: i64,
+}
+
+
+pub fn recent_post_times(conn: &Connection) -> Result<Vec<DateTime<UTC>>, Error> {
+    Ok(try!(conn
+        .query("
+            SELECT
+                author,
+                max(post.timestamp) as latest
+            FROM post, topic, author
+            WHERE post.topic = topic.id
+              AND topic.author = author.email
+              AND (post.reveal_at IS NULL OR post.reveal_at <= now())
+              AND post.deleted_at IS NULL
+              AND topic.deleted_at IS NULL
+              AND author.deleted_at IS NULL
+              AND author.confirmed_at IS NOT NULL
+              AND topic.visibility = 'unlisted'
+            GROUP BY topic.author
+            ORDER BY latest DESC", &[]))
+        .into_iter()
+        .map(|row| DateTime::from_utc(row.get("latest"), UTC))
+        .collect())
+}
+
+pub fn authors(conn: &Connection) -> Result<Vec<Author>, Error> {
+    Ok(try!(conn
+        .query("
+            SELECT email, key, timestamp
+            FROM author
+            ORDER BY timestamp", &[]))
+        .into_iter()
+        .map(|row| Author {
+            email: row.get("email"),
+            key: row.get("key"),
+            joined: DateTime::from_utc(row.get("timestamp"), UTC),
+        })
+        .collect())
+}
+
+pub fn author_by_key(conn: &Connection, key: &Uuid) -> Result<Option<String>, Error> {
+    Ok(try!(conn
+        .query("
+            SELECT email
+            FROM author
+            WHERE key = $1
+              AND deleted_at IS NULL
+              AND confirmed_at IS NOT NULL
+        ", &[key]))
+        .into_iter()
+        .map(|row| row.get("email"))
+        .next())
+}
+
+/// An author's key, unless they're in the trash.
+pub fn author_key(conn: &Connection, email: &str) -> Result<Option<Uuid>, Error> {
+    Ok(try!(conn
+        .query("SELECT key FROM author WHERE email = $1 AND deleted_at IS NULL", &[&email]))
+        .into_iter()
+        .map(|row| row.get("key"))
+        .next())
+}
+
+/// An author's topics, latest first, leaving out private ones unless `private` is set.
+/// Protected topics are listed, since reading them still takes the passphrase.
+pub fn author_topics(conn: &Connection, author: &str, private: bool) -> Result<Vec<Topic>, Error> {
+    Ok(try!(conn
+        .query("
+            SELECT
+                topic.topic as topic,
+                topic.key as key,
+                max(post.timestamp) as latest
+            FROM post, topic, author
+            WHERE post.topic = topic.id
+              AND topic.author = author.email
+              AND author.email = $1
+              AND (post.reveal_at IS NULL OR post.reveal_at <= now())
+              AND post.deleted_at IS NULL
+              AND topic.deleted_at IS NULL
+              AND (topic.visibility != 'private' OR $2)
+            GROUP BY post.topic, topic.topic, topic.key
+            ORDER BY latest DESC
+        ", &[&author, &private]))
+        .into_iter()
+        .map(Topic::from_row)
+        .collect())
+}
+
+/// The author, topic and visibility for a topic key, whether or not the topic has any posts
+/// yet.
+pub fn topic_by_key(conn: &Connection, key: &Uuid) -> Result<Option<(String, Topic, Visibility)>, Error> {
+    Ok(try!(conn
+        .query("
+            SELECT
+                topic.author as author,
+                topic.visibility as visibility,
+                topic.topic as topic,
+                topic.key as key,
+                coalesce(max(post.timestamp), topic.timestamp) as latest
+            FROM topic
+            JOIN author ON author.email = topic.author
+            LEFT JOIN post ON post.topic = topic.id
+              AND (post.reveal_at IS NULL OR post.reveal_at <= now())
+              AND post.deleted_at IS NULL
+            WHERE topic.key = $1
+              AND topic.deleted_at IS NULL
+              AND author.deleted_at IS NULL
+              AND author.confirmed_at IS NOT NULL
+            GROUP BY topic.id
+        ", &[key]))
+        .into_iter()
+        .map(|row| {
+            // an unknown visibility errs on the side of hiding the topic
+            let visibility = row.get::<_, String>("visibility").parse().unwrap_or(Visibility::Private);
+            (row.get("author"), Topic::from_row(row), visibility)
+        })
+        .next())
+}
+
+pub fn topic_posts(conn: &Connection, key: &Uuid) -> Result<Vec<Post>, Error> {
+    let mut keys = Keyring::new(conn);
+    try!(conn
+        .query("
+            SELECT
+                post.id,
+                body,
+                post.timestamp,
+                post.edited_at
+            FROM post, topic
+            WHERE post.topic = topic.id
+              AND topic.key = $1
+              AND (post.reveal_at IS NULL OR post.reveal_at <= now())
+              AND post.deleted_at IS NULL
+              AND topic.deleted_at IS NULL
+            ORDER BY post.timestamp DESC
+        ", &[key]))
+        .iter()
+        .map(|row| Post::from_row(&row, &mut keys))
+        .collect()
+}
+
+/// Every post by an author, grouped by topic, leaving out private (and protected) topics
+/// unless `private` is set.
+pub fn author_posts(conn: &Connection, author: &str, private: bool) -> Result<Vec<(Topic, Vec<Post>)>, Error> {
+    let mut topics: Vec<(Topic, Vec<Post>)> = vec![];
+    let mut keys = Keyring::new(conn);
+    for row in try!(conn
+        .query("
+            SELECT
+                topic.topic as topic,
+                topic.key as key,
+                max(post.timestamp) OVER (PARTITION BY topic.id) as latest,
+                post.id as id,
+                post.body as body,
+                post.timestamp as timestamp,
+                post.edited_at as edited_at
+            FROM post, topic
+            WHERE post.topic = topic.id
+              AND topic.author = $1
+              AND (post.reveal_at IS NULL OR post.reveal_at <= now())
+              AND post.deleted_at IS NULL
+              AND topic.deleted_at IS NULL
+              AND (topic.visibility = 'unlisted' OR $2)
+            ORDER BY topic.id, post.timestamp
+        ", &[&author, &private])).iter() {
+        let post = try!(Post::from_row(&row, &mut keys));
+        let key: Uuid = row.get("key");
+        match topics.last_mut() {
+            Some(&mut (ref topic, ref mut posts)) if topic.key == key => {
+                posts.push(post);
+                continue;
+            },
+            _ => {},
+        }
+        topics.push((Topic::from_row(row), vec![post]));
+    }
+    Ok(topics)
+}
+
+/// Search one author's notes, grouped by topic, best matches first. Private (and protected)
+/// topics are only searched if `private` is set.
+pub fn search(conn: &Connection, author: &str, query: &str, private: bool) -> Result<Vec<(Topic, Vec<SearchHit>)>, Error> {
+    let mut results: Vec<(Topic, Vec<SearchHit>)> = vec![];
+    let mut keys = Keyring::new(conn);
+    let blinded = try!(keys.search_query(author, query));
+    for row in try!(conn
+        .query("
+            SELECT
+                topic.topic as topic,
+                topic.key as key,
+                post.id as post,
+                post.timestamp as latest,
+                post.body as body,
+                ts_headline('english', post_text(post.body), query, $4) as snippet
+            FROM post, topic, plainto_tsquery('english', $2) query
+            WHERE post.topic = topic.id
+              AND topic.author = $1
+              AND (post.reveal_at IS NULL OR post.reveal_at <= now())
+              AND post.deleted_at IS NULL
+              AND topic.deleted_at IS NULL
+              AND (topic.visibility = 'unlisted' OR $3)
+              AND (post.search @@ query OR post.search @@ $5::text::tsquery)
+            ORDER BY ts_rank(post.search,
+                CASE WHEN post.body LIKE 'enc1:%' THEN $5::text::tsquery ELSE query END) DESC
+            LIMIT 200
+        ", &[&author, &query, &private, &HEADLINE, &blinded])).iter() {
+        let body: String = row.get("body");
+        let snippet = if at_rest::key_id(&body).is_some() {
+            // the database only has the ciphertext, so it needs the note back to quote it
+            try!(headline(conn, &try!(keys.decrypt(body)), query))
+        } else {
+            row.get("snippet")
+        };
+        let hit = SearchHit {
+            post: row.get("post"),
+            timestamp: DateTime::from_utc(row.get("latest"), UTC),
+            snippet: snippet,
+        };
+        let key: Uuid = row.get("key");
+        match results.iter().position(|&(ref topic, _)| topic.key == key) {
+            Some(i) => results[i].1.push(hit),
+            None => results.push((Topic::from_row(row), vec![hit])),
+        }
+    }
+    Ok(results)
+}
+
+const HEADLINE: &'static str = "StartSel=<mark>, StopSel=</mark>, MaxFragments=2";
+
+/// Where a note matches a search, as plain text with the matches marked.
+fn headline(conn: &Connection, body: &str, query: &str) -> Result<String, Error> {
+    Ok(try!(conn
+        .query("
+            SELECT ts_headline('english', post_text($1), plainto_tsquery('english', $2), $3) as snippet
+        ", &[&body, &query, &HEADLINE]))
+        .into_iter()
+        .map(|row| row.get("snippet"))
+        .next()
+        .unwrap())  // SELECT without FROM is always one row
+}
+
+
+/// Where a topic that was merged into another one went, by its old key.
+pub fn moved_topic(conn: &Connection, key: &Uuid) -> Result<Option<Uuid>, Error> {
+    Ok(try!(conn.query("SELECT moved_to FROM moved_topic WHERE key = $1", &[key]))
+        .into_iter()
+        .map(|row| row.get("moved_to"))
+        .next())
+}
+
+
+/// Whether an author or topic key used to exist (or is in the trash), and how its content
+/// went away.
+pub fn was_removed(conn: &Connection, key: &Uuid) -> Result<Option<Removal>, Error> {
+    Ok(try!(conn.query("
+        SELECT faded FROM removed WHERE key = $1
+        UNION ALL
+        SELECT false FROM author WHERE key = $1 AND deleted_at IS NOT NULL
+        UNION ALL
+        SELECT false FROM topic WHERE key = $1 AND deleted_at IS NOT NULL", &[key]))
+        .into_iter()
+        .map(|row| if row.get("faded") { Removal::Faded } else { Removal::Deleted })
+        .next())
+}
+
+
+/// Create the author if they don't exist yet. Returns true for new authors.
+///
+/// Unless they're `confirmed` already, nothing of theirs is shown until they confirm their
+/// address with `confirm_author_token` (or by signing in).
+pub fn add_author(conn: &Connection, email: &str, confirmed: bool) -> Result<bool, Error> {
+    let added = try!(conn.execute("
+        INSERT INTO author (email, confirmed_at)
+            SELECT $1, CASE WHEN $2 THEN now() END
+        WHERE NOT EXISTS (
+            SELECT email
+            FROM author
+            WHERE email = $1)",
+        &[&email, &confirmed]));
+    Ok(added == 1)
+}
+
+/// Give an unconfirmed author a new confirmation token. Returns false if they're confirmed
+/// already (or don't exist).
+pub fn set_confirm_token(conn: &Connection, email: &str, token_hash: &str) -> Result<bool, Error> {
+    Ok(try!(conn.execute("
+        UPDATE author
+            SET confirm_token_hash = $2
+        WHERE email = $1
+          AND confirmed_at IS NULL",
+        &[&email, &token_hash])) == 1)
+}
+
+/// Confirm a new author's address with the token from their welcome email, returning who they
+/// are if it was still good.
+pub fn confirm_author_token(conn: &Connection, token_hash: &str) -> Result<Option<String>, Error> {
+    Ok(try!(conn
+        .query("
+            UPDATE author
+                SET confirmed_at = now(), confirm_token_hash = NULL
+            WHERE confirm_token_hash = $1
+              AND confirmed_at IS NULL
+              AND timestamp > now() - $2::integer * interval '1 day'
+            RETURNING email",
+            &[&token_hash, &(session::CONFIRM_DAYS as i32)]))
+        .into_iter()
+        .map(|row| row.get("email"))
+        .next())
+}
+
+/// Count an author's address as confirmed, since they just used it to sign in.
+pub fn confirm_author(conn: &Connection, email: &str) -> Result<(), Error> {
+    try!(conn.execute("
+        UPDATE author
+            SET confirmed_at = now(), confirm_token_hash = NULL
+        WHERE email = $1
+          AND confirmed_at IS NULL",
+        &[&email]));
+    Ok(())
+}
+
+/// Forget authors who never confirmed their address, and their notes, returning how many.
+pub fn purge_unconfirmed_authors(conn: &Connection) -> Result<u64, Error> {
+    conn.execute("
+        DELETE FROM author
+        WHERE confirmed_at IS NULL
+          AND timestamp < now() - $1::integer * interval '1 day'",
+        &[&(session::CONFIRM_DAYS as i32)])
+}
+
+/// Find or create an author's topic, returning its `(id, key)`.
+///
+/// A topic in the trash comes back out, but the notes that were trashed with it stay there.
+pub fn add_topic(conn: &Connection, author: &str, topic: &str) -> Result<(Uuid, Uuid), Error> {
+    try!(conn.execute("
+        INSERT INTO topic (topic, author)
+            SELECT $1, $2
+        WHERE NOT EXISTS (
+            SELECT topic.topic as topic
+            FROM topic
+            WHERE topic.author = $2
+              AND topic.topic = $1)",
+        &[&topic, &author]));
+    try!(conn.execute("
+        UPDATE topic
+            SET deleted_at = NULL
+        WHERE topic.author = $2
+          AND topic.topic = $1
+          AND deleted_at IS NOT NULL",
+        &[&topic, &author]));
+
+    Ok(try!(conn
+        .query("
+            SELECT id, key
+            FROM topic
+            WHERE topic.topic = $1
+              AND topic.author = $2",
+            &[&topic, &author]))
+        .into_iter()
+        .map(|row| (row.get("id"), row.get("key")))
+        .next()
+        .unwrap())  // guarded by the previous query (what's a race?..)
+}
+
+/// Add a post, dated by when it was `authored` if we know, or else by when it arrived (now).
+///
+/// The body is encrypted at rest if there's a master key, and then its search index only has
+/// keyed hashes of its words.
+pub fn add_post(conn: &Connection, topic_id: &Uuid, body: &str, authored: Option<&DateTime<UTC>>) -> Result<Uuid, Error> {
+    let authored = authored.map(|t| t.naive_utc());
+    let author: String = try!(conn.query("SELECT author::text FROM topic WHERE id = $1", &[topic_id]))
+        .into_iter()
+        .map(|row| row.get("author"))
+        .next()
+        .unwrap();  // posts are only added to topics that were just found or made
+    let mut keys = Keyring::new(conn);
+    let stored = try!(keys.encrypt(&author, body));
+    let search = try!(keys.search_vector(&stored, body));
+    Ok(try!(conn.query("
+        INSERT INTO post (topic, body, search, timestamp)
+        VALUES ($1, $2, $3::text::tsvector, coalesce($4::timestamp, now()))
+        RETURNING id",
+        &[topic_id, &stored, &search, &authored]))
+        .into_iter()
+        .map(|row| row.get("id"))
+        .next()
+        .unwrap())  // INSERT ... RETURNING always gives back the row
+}
+
+/// Remember the `Message-Id` of the email a post came from, so replies can refer to it.
+pub fn set_message_id(conn: &Connection, post_id: &Uuid, message_id: &str) -> Result<(), Error> {
+    try!(conn.execute("UPDATE post SET message_id = $2 WHERE id = $1", &[post_id, &message_id]));
+    Ok(())
+}
+
+/// Whether the author already has a post (even one in the trash) from the email with this
+/// `Message-Id`, or exported with it (see `post_by_message_id`).
+pub fn has_message_id(conn: &Connection, author: &str, message_id: &str) -> Result<bool, Error> {
+    let exported = export::exported_post_id(message_id);
+    Ok(try!(conn.query("
+        SELECT 1
+        FROM post
+        JOIN topic ON topic.id = post.topic
+        WHERE topic.author = $1
+          AND (post.message_id = $2 OR post.id = $3)", &[&author, &message_id, &exported]))
+        .len() > 0)
+}
+
+/// One of an author's posts, with its topic key, if it's theirs.
+pub fn author_post(conn: &Connection, author: &str, post_id: &Uuid) -> Result<Option<(Uuid, Post)>, Error> {
+    let mut keys = Keyring::new(conn);
+    match try!(conn
+        .query("
+            SELECT
+                topic.key as key,
+                post.id as id,
+                post.body as body,
+                post.timestamp as timestamp,
+                post.edited_at as edited_at
+            FROM post, topic
+            WHERE post.topic = topic.id
+              AND topic.author = $1
+              AND post.id = $2
+              AND post.deleted_at IS NULL
+              AND topic.deleted_at IS NULL
+        ", &[&author, post_id]))
+        .iter()
+        .map(|row| Post::from_row(&row, &mut keys).map(|post| (row.get::<_, Uuid>("key"), post)))
+        .next() {
+        Some(found) => Ok(Some(try!(found))),
+        None => Ok(None),
+    }
+}
+
+/// The author's post that an email with this `Message-Id` became, unless it's in the trash.
+///
+/// Exported mboxes (and so anything imported from one) use `<post-id@domain>` message ids,
+/// so those find their post too.
+pub fn post_by_message_id(conn: &Connection, author: &str, message_id: &str) -> Result<Option<Uuid>, Error> {
+    let exported = export::exported_post_id(message_id);
+    Ok(try!(conn
+        .query("
+            SELECT post.id
+            FROM post, topic
+            WHERE post.topic = topic.id
+              AND topic.author = $1
+              AND (post.message_id = $2 OR post.id = $3)
+              AND post.deleted_at IS NULL
+              AND topic.deleted_at IS NULL
+            LIMIT 1
+        ", &[&author, &message_id, &exported]))
+        .into_iter()
+        .map(|row| row.get("id"))
+        .next())
+}
+
+/// Replace a post's body, keeping the old one as a revision. Returns false if the post isn't
+/// the author's, or is in the trash.
+pub fn edit_post(conn: &Connection, author: &str, post_id: &Uuid, body: &str) -> Result<bool, Error> {
+    let trans = try!(conn.transaction());
+    let saved = try!(trans.execute("
+        INSERT INTO post_revision (post, body)
+            SELECT post.id, post.body
+            FROM post, topic
+            WHERE post.topic = topic.id
+              AND topic.author = $1
+              AND post.id = $2
+              AND post.deleted_at IS NULL
+              AND topic.deleted_at IS NULL",
+        &[&author, post_id]));
+    if saved == 0 {
+        return Ok(false);
+    }
+    let mut keys = Keyring::new(conn);
+    let stored = try!(keys.encrypt(author, body));
+    let search = try!(keys.search_vector(&stored, body));
+    try!(trans.execute("
+        UPDATE post
+            SET body = $2, search = $3::text::tsvector, edited_at = now()
+        WHERE id = $1",
+        &[post_id, &stored, &search]));
+    try!(trans.commit());
+    Ok(true)
+}
+
+/// The earlier versions of a post, newest first.
+pub fn post_revisions(conn: &Connection, post_id: &Uuid) -> Result<Vec<Revision>, Error> {
+    let mut keys = Keyring::new(conn);
+    try!(conn
+        .query("
+            SELECT body, replaced
+            FROM post_revision
+            WHERE post = $1
+            ORDER BY replaced DESC
+        ", &[post_id]))
+        .into_iter()
+        .map(|row| Ok(Revision {
+            body: try!(keys.decrypt(row.get("body"))),
+            replaced: DateTime::from_utc(row.get("replaced"), UTC),
+        }))
+        .collect()
+}
+
+/// Something of an author's in the trash: a whole topic, or one post from a topic that isn't.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Trashed {
+    pub topic: Topic,
+    pub post: Option<Post>,
+    pub deleted: DateTime<UTC>,
+}
+
+/// Put one of an author's posts in the trash. Returns false if it's not theirs.
+pub fn trash_post(conn: &Connection, author: &str, post_id: &Uuid) -> Result<bool, Error> {
+    Ok(try!(conn.execute("
+        UPDATE post
+            SET deleted_at = now()
+        FROM topic
+        WHERE post.topic = topic.id
+          AND topic.author = $1
+          AND post.id = $2
+          AND post.deleted_at IS NULL",
+        &[&author, post_id])) == 1)
+}
+
+/// Put one of an author's topics in the trash, with its posts. Returns false if it's not
+/// theirs.
+pub fn trash_topic(conn: &Connection, author: &str, key: &Uuid) -> Result<bool, Error> {
+    let trans = try!(conn.transaction());
+    // now() is the same all through a transaction, which ties the posts to the topic
+    try!(trans.execute("
+        UPDATE post
+            SET deleted_at = now()
+        FROM topic
+        WHERE post.topic = topic.id
+          AND topic.author = $1
+          AND topic.key = $2
+          AND post.deleted_at IS NULL",
+        &[&author, key]));
+    let trashed = try!(trans.execute("
+        UPDATE topic
+            SET deleted_at = now()
+        WHERE author = $1
+          AND key = $2
+          AND deleted_at IS NULL",
+        &[&author, key])) == 1;
+    try!(trans.commit());
+    Ok(trashed)
+}
+
+/// Take a post back out of the trash. Returns false if it's not the author's, or not trashed.
+pub fn restore_post(conn: &Connection, author: &str, post_id: &Uuid) -> Result<bool, Error> {
+    Ok(try!(conn.execute("
+        UPDATE post
+            SET deleted_at = NULL
+        FROM topic
+        WHERE post.topic = topic.id
+          AND topic.author = $1
+          AND post.id = $2
+          AND post.deleted_at IS NOT NULL
+          AND topic.deleted_at IS NULL",
+        &[&author, post_id])) == 1)
+}
+
+/// Take a topic back out of the trash, along with the posts that went in with it.
+pub fn restore_topic(conn: &Connection, author: &str, key: &Uuid) -> Result<bool, Error> {
+    let trans = try!(conn.transaction());
+    try!(trans.execute("
+        UPDATE post
+            SET deleted_at = NULL
+        FROM topic
+        WHERE post.topic = topic.id
+          AND topic.author = $1
+          AND topic.key = $2
+          AND post.deleted_at = topic.deleted_at",
+        &[&author, key]));
+    let restored = try!(trans.execute("
+        UPDATE topic
+            SET deleted_at = NULL
+        WHERE author = $1
+          AND key = $2
+          AND deleted_at IS NOT NULL",
+        &[&author, key])) == 1;
+    try!(trans.commit());
+    Ok(restored)
+}
+
+/// What's in an author's trash, most recently deleted first.
+pub fn trash(conn: &Connection, author: &str) -> Result<Vec<Trashed>, Error> {
+    let mut trashed: Vec<Trashed> = try!(conn
+        .query("
+            SELECT
+                topic.topic as topic,
+                topic.key as key,
+                coalesce(max(post.timestamp), topic.timestamp) as latest,
+                topic.deleted_at as deleted_at
+            FROM topic
+            LEFT JOIN post ON post.topic = topic.id
+            WHERE topic.author = $1
+              AND topic.deleted_at IS NOT NULL
+            GROUP BY topic.id
+        ", &[&author]))
+        .iter()
+        .map(|row| Trashed {
+            deleted: DateTime::from_utc(row.get("deleted_at"), UTC),
+            topic: Topic::from_row(row),
+            post: None,
+        })
+        .collect();
+    let mut keys = Keyring::new(conn);
+    for row in try!(conn
+        .query("
+            SELECT
+                topic.topic as topic,
+                topic.key as key,
+                post.timestamp as latest,
+                post.id as id,
+                post.body as body,
+                post.timestamp as timestamp,
+                post.edited_at as edited_at,
+                post.deleted_at as deleted_at
+            FROM post, topic
+            WHERE post.topic = topic.id
+              AND topic.author = $1
+              AND post.deleted_at IS NOT NULL
+              AND topic.deleted_at IS NULL
+        ", &[&author])).iter() {
+        trashed.push(Trashed {
+            deleted: DateTime::from_utc(row.get("deleted_at"), UTC),
+            post: Some(try!(Post::from_row(&row, &mut keys))),
+            topic: Topic::from_row(row),
+        });
+    }
+    trashed.sort_by(|a, b| b.deleted.cmp(&a.deleted));
+    Ok(trashed)
+}
+
+/// Permanently delete whatever has been in the trash for more than `days`, returning how many
+/// authors, topics and posts went.
+pub fn purge_trash(conn: &Connection, days: u32) -> Result<(u64, u64, u64), Error> {
+    let trans = try!(conn.transaction());
+    let days = days as i32;
+    let authors = try!(trans.execute("
+        DELETE FROM author
+        WHERE deleted_at < now() - $1::integer * interval '1 day'",
+        &[&days]));
+    let topics = try!(trans.execute("
+        DELETE FROM topic
+        WHERE deleted_at < now() - $1::integer * interval '1 day'",
+        &[&days]));
+    let posts = try!(trans.execute("
+        DELETE FROM post
+        WHERE deleted_at < now() - $1::integer * interval '1 day'",
+        &[&days]));
+    try!(trans.commit());
+    Ok((authors, topics, posts))
+}
+
+/// Make a share link for one of an author's topics, optionally expiring after some `days`.
+/// Returns the link's key, or `None` if the topic isn't theirs.
+pub fn add_share_link(conn: &Connection, author: &str, topic_key: &Uuid, label: &str, days: Option<i32>) -> Result<Option<Uuid>, Error> {
+    Ok(try!(conn
+        .query("
+            INSERT INTO share_link (topic, label, expires)
+                SELECT id, $3, now() + $4::integer * interval '1 day'
+                FROM topic
+                WHERE author = $1
+                  AND key = $2
+                  AND deleted_at IS NULL
+            RETURNING key",
+            &[&author, topic_key, &label, &days]))
+        .into_iter()
+        .map(|row| row.get("key"))
+        .next())
+}
+
+/// Every share link a topic has had, newest first.
+pub fn share_links(conn: &Connection, topic_key: &Uuid) -> Result<Vec<ShareLink>, Error> {
+    let optional = |t: Option<_>| t.map(|t| DateTime::from_utc(t, UTC));
+    Ok(try!(conn
+        .query("
+            SELECT share_link.*
+            FROM share_link, topic
+            WHERE share_link.topic = topic.id
+              AND topic.key = $1
+            ORDER BY share_link.created DESC
+        ", &[topic_key]))
+        .into_iter()
+        .map(|row| ShareLink {
+            key: row.get("key"),
+            label: row.get("label"),
+            created: DateTime::from_utc(row.get("created"), UTC),
+            expires: optional(row.get("expires")),
+            revoked: optional(row.get("revoked")),
+            last_used: optional(row.get("last_used")),
+        })
+        .collect())
+}
+
+/// Returns false if it isn't a live link to one of the author's topics.
+pub fn revoke_share_link(conn: &Connection, author: &str, key: &Uuid) -> Result<bool, Error> {
+    Ok(try!(conn.execute("
+        UPDATE share_link
+            SET revoked = now()
+        FROM topic
+        WHERE share_link.topic = topic.id
+          AND topic.author = $1
+          AND share_link.key = $2
+          AND share_link.revoked IS NULL",
+        &[&author, key])) == 1)
+}
+
+/// The topic key behind a working share link, noting that it was used.
+pub fn use_share_link(conn: &Connection, key: &Uuid) -> Result<Option<Uuid>, Error> {
+    Ok(try!(conn
+        .query("
+            UPDATE share_link
+                SET last_used = now()
+            FROM topic
+            WHERE share_link.topic = topic.id
+              AND share_link.key = $1
+              AND share_link.revoked IS NULL
+              AND (share_link.expires IS NULL OR share_link.expires > now())
+            RETURNING topic.key as topic_key",
+            &[key]))
+        .into_iter()
+        .map(|row| row.get("topic_key"))
+        .next())
+}
+
+/// The keys for an end-to-end encrypted topic, by its key.
+pub fn e2e_key(conn: &Connection, topic_key: &Uuid) -> Result<Option<E2eKey>, Error> {
+    Ok(try!(conn
+        .query("
+            SELECT topic_e2e_key.*
+            FROM topic_e2e_key, topic
+            WHERE topic_e2e_key.topic = topic.id
+              AND topic.key = $1
+        ", &[topic_key]))
+        .into_iter()
+        .map(|row| E2eKey {
+            public_key: row.get("public_key"),
+            wrapped_private_key: row.get("wrapped_private_key"),
+            salt: row.get("salt"),
+            iv: row.get("iv"),
+        })
+        .next())
+}
+
+/// The public key that notes for a topic (by id) are sealed to, if it's encrypted.
+pub fn topic_public_key(conn: &Connection, topic_id: &Uuid) -> Result<Option<Vec<u8>>, Error> {
+    Ok(try!(conn
+        .query("SELECT public_key FROM topic_e2e_key WHERE topic = $1", &[topic_id]))
+        .into_iter()
+        .map(|row| row.get("public_key"))
+        .next())
+}
+
+/// The public key that a post's topic seals notes to, if it's encrypted.
+pub fn post_public_key(conn: &Connection, post_id: &Uuid) -> Result<Option<Vec<u8>>, Error> {
+    Ok(try!(conn
+        .query("
+            SELECT topic_e2e_key.public_key
+            FROM topic_e2e_key, post
+            WHERE topic_e2e_key.topic = post.topic
+              AND post.id = $1
+        ", &[post_id]))
+        .into_iter()
+        .map(|row| row.get("public_key"))
+        .next())
+}
+
+/// Turn on end-to-end encryption for one of an author's topics, sealing the notes already
+/// there with `seal` and forgetting their plaintext revisions. Returns false if the topic
+/// isn't theirs, or is already encrypted.
+pub fn encrypt_topic(conn: &Connection, author: &str, topic_key: &Uuid, key: &E2eKey, seal: &Fn(&str) -> String) -> Result<bool, Error> {
+    let trans = try!(conn.transaction());
+    let added = try!(trans.execute("
+        INSERT INTO topic_e2e_key (topic, public_key, wrapped_private_key, salt, iv)
+            SELECT id, $3, $4, $5, $6
+            FROM topic
+            WHERE author = $1
+              AND key = $2
+        ON CONFLICT DO NOTHING",
+        &[&author, topic_key, &key.public_key, &key.wrapped_private_key, &key.salt, &key.iv]));
+    if added == 0 {
+        return Ok(false);
+    }
+    let mut keys = Keyring::new(conn);
+    for row in try!(trans.query("
+        SELECT post.id, post.body
+        FROM post, topic
+        WHERE post.topic = topic.id
+          AND topic.key = $1", &[topic_key])).iter() {
+        let id: Uuid = row.get("id");
+        let sealed = seal(&try!(keys.decrypt(row.get("body"))));
+        let stored = try!(keys.encrypt(author, &sealed));
+        try!(trans.execute("
+            UPDATE post
+                SET body = $2, search = to_tsvector('english', post_text($3))
+            WHERE id = $1",
+            &[&id, &stored, &sealed]));
+        try!(trans.execute("DELETE FROM post_revision WHERE post = $1", &[&id]));
+    }
+    try!(trans.commit());
+    Ok(true)
+}
+
+/// Keep a post hidden until `reveal_at`, and maybe mail it back to its author then.
+pub fn seal_post(conn: &Connection, post_id: &Uuid, reveal_at: &DateTime<UTC>, send_letter: bool) -> Result<(), Error> {
+    try!(conn.execute("
+        UPDATE post
+            SET reveal_at = $2, send_letter = $3
+        WHERE id = $1",
+        &[post_id, &reveal_at.naive_utc(), &send_letter]));
+    Ok(())
+}
+
+/// When a topic's sealed posts will open, soonest first.
+pub fn sealed_posts(conn: &Connection, key: &Uuid) -> Result<Vec<DateTime<UTC>>, Error> {
+    Ok(try!(conn
+        .query("
+            SELECT post.reveal_at
+            FROM post, topic
+            WHERE post.topic = topic.id
+              AND topic.key = $1
+              AND post.reveal_at > now()
+              AND post.deleted_at IS NULL
+              AND topic.deleted_at IS NULL
+            ORDER BY post.reveal_at
+        ", &[key]))
+        .into_iter()
+        .map(|row| DateTime::from_utc(row.get("reveal_at"), UTC))
+        .collect())
+}
+
+
+/// Delete a post at `fades_at`.
+pub fn fade_post(conn: &Connection, post_id: &Uuid, fades_at: &DateTime<UTC>) -> Result<(), Error> {
+    try!(conn.execute("UPDATE post SET fades_at = $2 WHERE id = $1",
+        &[post_id, &fades_at.naive_utc()]));
+    Ok(())
+}
+
+/// Give every post in one of an author's topics a lifetime, or `None` to keep them. Returns
+/// false if the author has no such topic.
+pub fn set_topic_fade(conn: &Connection, author: &str, topic: &str, seconds: Option<i64>) -> Result<bool, Error> {
+    Ok(try!(conn.execute("
+        UPDATE topic
+            SET fade_after_seconds = $3
+        WHERE author = $1
+          AND topic = $2",
+        &[&author, &topic, &seconds])) == 1)
+}
+
+/// Make one of an author's topics unlisted or private, dropping any passphrase. Returns false
+/// if the author has no such topic.
+pub fn set_topic_visibility(conn: &Connection, author: &str, topic: &str, visibility: Visibility) -> Result<bool, Error> {
+    Ok(try!(conn.execute("
+        UPDATE topic
+            SET visibility = $3, passphrase_hash = NULL
+        WHERE author = $1
+          AND topic = $2",
+        &[&author, &topic, &visibility.as_str()])) == 1)
+}
+
+/// Protect one of an author's topics with a passphrase (by its hash), or make it unlisted
+/// again with `None`. Returns false if the topic isn't theirs.
+pub fn set_passphrase(conn: &Connection, author: &str, key: &Uuid, passphrase_hash: Option<&str>) -> Result<bool, Error> {
+    let visibility = if passphrase_hash.is_some() { Visibility::Protected } else { Visibility::Unlisted };
+    Ok(try!(conn.execute("
+        UPDATE topic
+            SET visibility = $3, passphrase_hash = $4
+        WHERE author = $1
+          AND key = $2",
+        &[&author, key, &visibility.as_str(), &passphrase_hash])) == 1)
+}
+
+/// The hash of a protected topic's passphrase.
+pub fn passphrase_hash(conn: &Connection, key: &Uuid) -> Result<Option<String>, Error> {
+    Ok(try!(conn
+        .query("SELECT passphrase_hash FROM topic WHERE key = $1", &[key]))
+        .into_iter()
+        .filter_map(|row| row.get("passphrase_hash"))
+        .next())
+}
+
+/// How many wrong passphrases a topic has seen lately.
+pub fn recent_unlock_failures(conn: &Connection, key: &Uuid, client: &str) -> Result<i64, Error> {
+    Ok(try!(conn
+        .query("
+            SELECT count(*) as failures
+            FROM unlock_failure, topic
+            WHERE unlock_failure.topic = topic.id
+              AND topic.key = $1
+              AND unlock_failure.client = $3
+              AND unlock_failure.created > now() - $2::integer * interval '1 minute'",
+            &[key, &(session::UNLOCK_FAILURE_MINUTES as i32), &client]))
+        .into_iter()
+        .map(|row| row.get("failures"))
+        .next()
+        .unwrap_or(0))
+}
+
+pub fn add_unlock_failure(conn: &Connection, key: &Uuid, client: &str) -> Result<(), Error> {
+    try!(conn.execute("
+        INSERT INTO unlock_failure (topic, client)
+            SELECT id, $2 FROM topic WHERE key = $1",
+        &[key, &client]));
+    Ok(())
+}
+
+/// Returns false if there was no such author.
+pub fn set_fade_warnings(conn: &Connection, author: &str, on: bool) -> Result<bool, Error> {
+    Ok(try!(conn.execute("UPDATE author SET fade_warnings = $2 WHERE email = $1",
+        &[&author, &on])) == 1)
+}
+
+
+pub struct Fading {
+    pub author: String,
+    pub topic: String,
+    pub topic_key: Uuid,
+    pub post: Uuid,
+    pub fades_at: DateTime<UTC>,
+}
+
+/// Posts that will fade within a day, whose authors want a warning and haven't had one yet.
+///
+/// Warnings go by email, so only to authors who've shown the address is theirs: otherwise
+/// anyone could sign a stranger up for them.
+pub fn fading_soon(conn: &Connection) -> Result<Vec<Fading>, Error> {
+    Ok(try!(conn
+        .query("
+            SELECT * FROM (
+                SELECT
+                    topic.author as author,
+                    topic.topic as topic,
+                    topic.key as key,
+                    post.id as post,
+                    fades_at(post.fades_at, coalesce(post.reveal_at, post.arrived), topic.fade_after_seconds) as fades_at
+                FROM post, topic, author
+                WHERE post.topic = topic.id
+                  AND topic.author = author.email
+                  AND author.fade_warnings
+                  AND author.confirmed_at IS NOT NULL
+                  AND NOT post.fade_warned
+                  AND post.deleted_at IS NULL
+                  AND topic.deleted_at IS NULL
+                  AND author.deleted_at IS NULL
+            ) fading
+            WHERE fades_at <= now() + interval '1 day'
+            ORDER BY author, fades_at
+        ", &[]))
+        .into_iter()
+        .map(|row| Fading {
+            author: row.get("author"),
+            topic: row.get("topic"),
+            topic_key: row.get("key"),
+            post: row.get("post"),
+            fades_at: DateTime::from_utc(row.get("fades_at"), UTC),
+        })
+        .collect())
+}
+
+pub fn fade_warned(conn: &Connection, post_id: &Uuid) -> Result<(), Error> {
+    try!(conn.execute("UPDATE post SET fade_warned = true WHERE id = $1", &[post_id]));
+    Ok(())
+}
+
+/// Delete every post past its lifetime, along with topics left empty by it. Returns how many
+/// posts faded.
+pub fn sweep_faded(conn: &Connection) -> Result<usize, Error> {
+    let trans = try!(conn.transaction());
+    let mut topics: Vec<Uuid> = vec![];
+    let mut faded = 0;
+    for row in try!(trans.query("
+        DELETE FROM post
+        USING topic
+        WHERE post.topic = topic.id
+          AND fades_at(post.fades_at, coalesce(post.reveal_at, post.arrived), topic.fade_after_seconds) <= now()
+        RETURNING post.topic", &[])).iter() {
+        let topic: Uuid = row.get("topic");
+        if !topics.contains(&topic) {
+            topics.push(topic);
+        }
+        faded += 1;
+    }
+    for topic in topics {
+        // its link should say it faded, rather than that it was deleted
+        try!(trans.execute("
+            WITH emptied AS (
+                DELETE FROM topic
+                WHERE id = $1
+                  AND NOT EXISTS (SELECT 1 FROM post WHERE post.topic = $1)
+                RETURNING key
+            )
+            INSERT INTO removed (key, faded)
+                SELECT key, true FROM emptied
+            ON CONFLICT (key) DO UPDATE SET faded = true",
+            &[&topic]));
+    }
+    try!(trans.commit());
+    Ok(faded)
+}
+
+
+pub struct Letter {
+    pub author: String,
+    pub topic: String,
+    pub topic_key: Uuid,
+    pub post: Post,
+}
+
+/// Sealed posts that have opened and are waiting to be mailed back to their authors. Like
+/// fade warnings, they only go to confirmed addresses.
+pub fn due_letters(conn: &Connection) -> Result<Vec<Letter>, Error> {
+    let mut keys = Keyring::new(conn);
+    try!(conn
+        .query("
+            SELECT
+                topic.author as author,
+                topic.topic as topic,
+                topic.key as key,
+                post.id as id,
+                post.body as body,
+                post.timestamp as timestamp,
+                post.edited_at as edited_at
+            FROM post, topic, author
+            WHERE post.topic = topic.id
+              AND topic.author = author.email
+              AND author.confirmed_at IS NOT NULL
+              AND post.send_letter
+              AND post.letter_sent_at IS NULL
+              AND post.reveal_at <= now()
+              AND post.deleted_at IS NULL
+              AND topic.deleted_at IS NULL
+              AND author.deleted_at IS NULL
+            ORDER BY post.reveal_at
+        ", &[]))
+        .iter()
+        .map(|row| Ok(Letter {
+            author: row.get("author"),
+            topic: row.get("topic"),
+            topic_key: row.get("key"),
+            post: try!(Post::from_row(&row, &mut keys)),
+        }))
+        .collect()
+}
+
+pub fn letter_sent(conn: &Connection, post_id: &Uuid) -> Result<(), Error> {
+    try!(conn.execute("UPDATE post SET letter_sent_at = now() WHERE id = $1", &[post_id]));
+    Ok(())
+}
+
+
+pub fn empty_subject_rule(conn: &Connection, author: &str) -> Result<EmptySubject, Error> {
+    Ok(try!(conn
+        .query("SELECT empty_subject FROM author WHERE email = $1", &[&author]))
+        .into_iter()
+        .map(|row| row.get::<_, String>("empty_subject"))
+        .next()
+        .and_then(|rule| rule.parse().ok())
+        .unwrap_or(EmptySubject::FromBody))
+}
+
+/// Returns false if there was no such author.
+pub fn set_empty_subject_rule(conn: &Connection, author: &str, rule: EmptySubject) -> Result<bool, Error> {
+    Ok(try!(conn.execute("
+        UPDATE author
+            SET empty_subject = $2
+        WHERE email = $1",
+        &[&author, &rule.as_str()])) == 1)
+}
+
+
+/// Where an author's dates are shown, UTC by default.
+pub fn author_timezone(conn: &Connection, author: &str) -> Result<Tz, Error> {
+    Ok(try!(conn
+        .query("SELECT timezone FROM author WHERE email = $1", &[&author]))
+        .into_iter()
+        .map(|row| row.get::<_, String>("timezone"))
+        .next()
+        .and_then(|name| zone::parse(&name).ok())
+        .unwrap_or(Tz::UTC))
+}
+
+/// Returns false if there was no such author. The timezone name should already be checked.
+pub fn set_timezone(conn: &Connection, author: &str, timezone: &str) -> Result<bool, Error> {
+    Ok(try!(conn.execute("
+        UPDATE author
+            SET timezone = $2
+        WHERE email = $1",
+        &[&author, &timezone])) == 1)
+}
+
+
+/// The topic an author started with, as `(topic, key)`.
+pub fn first_topic(conn: &Connection, author: &str) -> Result<Option<(String, Uuid)>, Error> {
+    Ok(try!(conn
+        .query("
+            SELECT topic, key
+            FROM topic
+            WHERE author = $1
+              AND deleted_at IS NULL
+            ORDER BY timestamp
+            LIMIT 1", &[&author]))
+        .into_iter()
+        .map(|row| (row.get("topic"), row.get("key")))
+        .next())
+}
+
+/// Encrypt up to `batch` note bodies, and as many quarantined notes and earlier versions of
+/// notes, that were stored before encryption at rest was turned on, and re-index as many
+/// encrypted notes that are missing from search. Returns how many were, which is none once
+/// they're all done (or if there's no master key).
+pub fn encrypt_stored_notes(conn: &Connection, batch: i64) -> Result<u64, Error> {
+    if ::SETTINGS.encryption.is_none() {
+        return Ok(0);
+    }
+    let trans = try!(conn.transaction());
+    let mut keys = Keyring::new(conn);
+    let mut encrypted = 0;
+    for row in try!(trans.query("
+        SELECT post.id, post.body, topic.author::text as author
+        FROM post, topic
+        WHERE post.topic = topic.id
+          AND post.body NOT LIKE 'enc1:%'
+        LIMIT $1
+        FOR UPDATE OF post SKIP LOCKED", &[&batch])).iter() {
+        let id: Uuid = row.get("id");
+        let author: String = row.get("author");
+        let body: String = row.get("body");
+        let stored = try!(keys.encrypt(&author, &body));
+        let search = try!(keys.search_vector(&stored, &body));
+        try!(trans.execute("UPDATE post SET body = $2, search = $3::text::tsvector WHERE id = $1",
+            &[&id, &stored, &search]));
+        encrypted += 1;
+    }
+    // encrypted notes whose index still had their words, until it was cleared
+    for row in try!(trans.query("
+        SELECT id, body
+        FROM post
+        WHERE body LIKE 'enc1:%'
+          AND search IS NULL
+        LIMIT $1
+        FOR UPDATE SKIP LOCKED", &[&batch])).iter() {
+        let id: Uuid = row.get("id");
+        let stored: String = row.get("body");
+        let body = try!(keys.decrypt(stored.clone()));
+        let search = try!(keys.search_vector(&stored, &body));
+        try!(trans.execute("UPDATE post SET search = $2::text::tsvector WHERE id = $1", &[&id, &search]));
+        encrypted += 1;
+    }
+    for row in try!(trans.query("
+        SELECT id, html, text
+        FROM pending_note
+        WHERE data_key IS NULL
+        LIMIT $1
+        FOR UPDATE SKIP LOCKED", &[&batch])).iter() {
+        let id: Uuid = row.get("id");
+        let (html, text): (String, String) = (row.get("html"), row.get("text"));
+        let (data_key, stored) = try!(keys.encrypt_loose(&[&html[..], &text[..]]));
+        try!(trans.execute("UPDATE pending_note SET data_key = $2, html = $3, text = $4 WHERE id = $1",
+            &[&id, &data_key, &stored[0], &stored[1]]));
+        encrypted += 1;
+    }
+    for row in try!(trans.query("
+        SELECT post_revision.id, post_revision.body, topic.author::text as author
+        FROM post_revision, post, topic
+        WHERE post_revision.post = post.id
+          AND post.topic = topic.id
+          AND post_revision.body NOT LIKE 'enc1:%'
+        LIMIT $1
+        FOR UPDATE OF post_revision SKIP LOCKED", &[&batch])).iter() {
+        let id: Uuid = row.get("id");
+        let author: String = row.get("author");
+        let stored = try!(keys.encrypt(&author, &row.get::<_, String>("body")));
+        try!(trans.execute("UPDATE post_revision SET body = $2 WHERE id = $1", &[&id, &stored]));
+        encrypted += 1;
+    }
+    try!(trans.commit());
+    Ok(encrypted)
+}
+
+/// Re-wrap every data key that isn't wrapped by the current master key yet, returning how
+/// many were. If the previous master key doesn't open one either, nothing changes.
+pub fn rewrap_data_keys(conn: &Connection) -> Result<u64, Error> {
+    let masters = match ::SETTINGS.encryption {
+        Some(ref masters) => masters,
+        None => return Ok(0),
+    };
+    let trans = try!(conn.transaction());
+    let mut rewrapped = 0;
+    for row in try!(trans.query("SELECT id, wrapped FROM data_key FOR UPDATE", &[])).iter() {
+        let id: Uuid = row.get("id");
+        let wrapped: Vec<u8> = row.get("wrapped");
+        if at_rest::unwrap(&masters.current, &wrapped).is_some() {
+            continue;
+        }
+        let key = match masters.previous.as_ref().and_then(|previous| at_rest::unwrap(previous, &wrapped)) {
+            Some(key) => key,
+            None => return Err(unreadable(format!("no master key unwraps data key {}", id))),
+        };
+        try!(trans.execute("UPDATE data_key SET wrapped = $2 WHERE id = $1",
+            &[&id, &at_rest::wrap(&masters.current, &key)]));
+        rewrapped += 1;
+    }
+    try!(trans.commit());
+    Ok(rewrapped)
+}
+
+/// Move all of one author's topics and posts over to another author, then remove the first.
+///
+/// Topics with the same name are combined into the surviving author's topic, and links to
+/// the combined ones go there (see `moved_topic`).
+pub fn merge_authors(conn: &Connection, from: &str, into: &str) -> Result<(), Error> {
+    let trans = try!(conn.transaction());
+    try!(trans.execute("
+        INSERT INTO moved_topic (key, moved_to)
+            SELECT from_topic.key, into_topic.key
+            FROM topic from_topic, topic into_topic
+            WHERE from_topic.author = $1
+              AND into_topic.author = $2
+              AND into_topic.topic = from_topic.topic",
+        &[&from, &into]));
+    // anything that had already moved to those topics moves on with them
+    try!(trans.execute("
+        UPDATE moved_topic
+            SET moved_to = into_topic.key
+        FROM topic from_topic, topic into_topic
+        WHERE moved_topic.moved_to = from_topic.key
+          AND from_topic.author = $1
+          AND into_topic.author = $2
+          AND into_topic.topic = from_topic.topic",
+        &[&from, &into]));
+    try!(trans.execute("
+        UPDATE post
+            SET topic = into_topic.id
+        FROM topic from_topic, topic into_topic
+        WHERE post.topic = from_topic.id
+          AND from_topic.author = $1
+          AND into_topic.author = $2
+          AND into_topic.topic = from_topic.topic",
+        &[&from, &into]));
+    try!(trans.execute("
+        DELETE FROM topic
+        WHERE author = $1
+          AND topic IN (
+            SELECT topic
+            FROM topic
+            WHERE author = $2)",
+        &[&from, &into]));
+    // deleting them recorded their keys as removed, but they live on
+    try!(trans.execute("DELETE FROM removed USING moved_topic WHERE removed.key = moved_topic.key", &[]));
+    try!(trans.execute("
+        UPDATE topic
+            SET author = $2
+        WHERE author = $1",
+        &[&from, &into]));
+    // their notes still need their data keys
+    try!(trans.execute("UPDATE data_key SET author = $2 WHERE author = $1", &[&from, &into]));
+    try!(trans.execute("DELETE FROM author WHERE email = $1", &[&from]));
+    trans.commit()
+}
+
+/// Put an author, and so all of their topics and posts, in the trash. Returns false if there
+/// was no such author (or they're already there).
+pub fn delete_author(conn: &Connection, email: &str) -> Result<bool, Error> {
+    Ok(try!(conn.execute("
+        UPDATE author
+            SET deleted_at = now()
+        WHERE email = $1
+          AND deleted_at IS NULL",
+        &[&email])) == 1)
+}
+
+/// Take an author back out of the trash. Returns false if they weren't in it.
+pub fn restore_author(conn: &Connection, email: &str) -> Result<bool, Error> {
+    Ok(try!(conn.execute("
+        UPDATE author
+            SET deleted_at = NULL
+        WHERE email = $1
+          AND deleted_at IS NOT NULL",
+        &[&email])) == 1)
+}
+
+/// Returns false if there was no such topic.
+pub fn rename_topic(conn: &Connection, key: &Uuid, name: &str) -> Result<bool, Error> {
+    Ok(try!(conn.execute("
+        UPDATE topic
+            SET topic = $2
+        WHERE key = $1",
+        &[key, &name])) == 1)
+}
+
+/// Whether the author of a topic already has a different topic going by `name`.
+pub fn topic_name_taken(conn: &Connection, key: &Uuid, name: &str) -> Result<bool, Error> {
+    Ok(try!(conn.query("
+        SELECT other.id
+        FROM topic, topic other
+        WHERE topic.key = $1
+          AND other.author = topic.author
+          AND other.topic = $2
+          AND other.id != topic.id",
+        &[key, &name])).len() > 0)
+}
+
+pub fn stats(conn: &Connection) -> Result<Stats, Error> {
+    Ok(try!(conn
+        .query("
+            SELECT
+                (SELECT count(*) FROM author WHERE deleted_at IS NULL) as authors,
+                (SELECT count(*) FROM topic WHERE deleted_at IS NULL) as topics,
+                (SELECT count(*) FROM post WHERE deleted_at IS NULL) as posts,
+                (SELECT count(*) FROM post
+                    WHERE arrived > now() - interval '7 days'
+                      AND deleted_at IS NULL) as posts_this_week", &[]))
+        .into_iter()
+        .map(|row| Stats {
+            authors: row.get("authors"),
+            topics: row.get("topics"),
+            posts: row.get("posts"),
+            posts_this_week: row.get("posts_this_week"),
+        })
+        .next()
+        .unwrap())  // aggregates always return a row
+}
+
+
+pub fn add_sign_in_token(conn: &Connection, author: &str, token_hash: &str) -> Result<(), Error> {
+    try!(conn.execute("
+        INSERT INTO sign_in_token (token_hash, author, expires)
+        VALUES ($1, $2, now() + $3::integer * interval '1 minute')",
+        &[&token_hash, &author, &(session::LINK_MINUTES as i32)]));
+    Ok(())
+}
+
+/// How many sign-in links an author has asked for lately.
+pub fn recent_sign_in_tokens(conn: &Connection, author: &str) -> Result<i64, Error> {
+    Ok(try!(conn.query("
+        SELECT count(*) as count
+        FROM sign_in_token
+        WHERE author = $1
+          AND created > now() - $2::integer * interval '1 minute'",
+        &[&author, &(session::LINK_MINUTES as i32)]))
+        .into_iter()
+        .map(|row| row.get("count"))
+        .next()
+        .unwrap_or(0))
+}
+
+/// Use up a sign-in token, returning who it was for if it was still good.
+pub fn use_sign_in_token(conn: &Connection, token_hash: &str) -> Result<Option<String>, Error> {
+    Ok(try!(conn
+        .query("
+            UPDATE sign_in_token
+                SET used = now()
+            WHERE token_hash = $1
+              AND used IS NULL
+              AND expires > now()
+            RETURNING author",
+            &[&token_hash]))
+        .into_iter()
+        .map(|row| row.get("author"))
+        .next())
+}
+
+/// A note held back because its sender couldn't be authenticated, until the sender's address
+/// confirms it.
+#[derive(Debug, PartialEq, Eq)]
+pub struct PendingNote {
+    pub sender: String,
+    pub subject: String,
+    pub html: String,
+    pub text: String,
+    /// the email's headers, as json
+    pub headers: String,
+    pub authored: Option<DateTime<UTC>>,
+    pub received: DateTime<UTC>,
+}
+
+impl PendingNote {
+    fn from_row(row: &Row, keys: &mut Keyring) -> Result<PendingNote, Error> {
+        Ok(PendingNote {
+            sender: row.get("sender"),
+            subject: row.get("subject"),
+            html: try!(keys.decrypt(row.get("html"))),
+            text: try!(keys.decrypt(row.get("text"))),
+            headers: row.get("headers"),
+            authored: row.get::<_, Option<_>>("authored").map(|t| DateTime::from_utc(t, UTC)),
+            received: DateTime::from_utc(row.get("received"), UTC),
+        })
+    }
+}
+
+/// Hold a note until someone follows the confirmation link with this token. Its body is
+/// encrypted at rest with a data key of its own, since the sender might not be an author.
+pub fn add_pending_note(conn: &Connection, token_hash: &str, sender: &str, subject: &str, html: &str, text: &str, headers: &str, authored: Option<&DateTime<UTC>>, verdict: sender::Verdict) -> Result<(), Error> {
+    let authored = authored.map(|t| t.naive_utc());
+    let (data_key, stored) = try!(Keyring::new(conn).encrypt_loose(&[html, text]));
+    try!(conn.execute("
+        INSERT INTO pending_note (token_hash, sender, subject, html, text, headers, authored, verdict, data_key)
+        VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9)",
+        &[&token_hash, &sender, &subject, &stored[0], &stored[1], &headers, &authored, &verdict.as_str(), &data_key]));
+    Ok(())
+}
+
+/// How many notes from an address are waiting to be confirmed.
+pub fn pending_notes_from(conn: &Connection, sender: &str) -> Result<i64, Error> {
+    Ok(try!(conn.query("
+        SELECT count(*) as count
+        FROM pending_note
+        WHERE sender = $1
+          AND received > now() - $2::integer * interval '1 day'",
+        &[&sender, &(sender::PENDING_DAYS as i32)]))
+        .into_iter()
+        .map(|row| row.get("count"))
+        .next()
+        .unwrap_or(0))
+}
+
+/// The pending note a confirmation link is for, if it hasn't expired.
+pub fn pending_note(conn: &Connection, token_hash: &str) -> Result<Option<PendingNote>, Error> {
+    let mut keys = Keyring::new(conn);
+    match try!(conn
+        .query("
+            SELECT *
+            FROM pending_note
+            WHERE token_hash = $1
+              AND received > now() - $2::integer * interval '1 day'",
+            &[&token_hash, &(sender::PENDING_DAYS as i32)]))
+        .iter()
+        .map(|row| PendingNote::from_row(&row, &mut keys))
+        .next() {
+        Some(found) => Ok(Some(try!(found))),
+        None => Ok(None),
+    }
+}
+
+/// Stop holding a pending note, returning it if the link was still good. Confirming and
+/// discarding both take it, so a link only works once.
+pub fn take_pending_note(conn: &Connection, token_hash: &str) -> Result<Option<PendingNote>, Error> {
+    let trans = try!(conn.transaction());
+    let mut keys = Keyring::new(conn);
+    let taken = match try!(trans
+        .query("
+            DELETE FROM pending_note
+            WHERE token_hash = $1
+              AND received > now() - $2::integer * interval '1 day'
+            RETURNING *",
+            &[&token_hash, &(sender::PENDING_DAYS as i32)]))
+        .iter()
+        .map(|row| PendingNote::from_row(&row, &mut keys).map(|note| (row.get::<_, Option<Uuid>>("data_key"), note)))
+        .next() {
+        Some(found) => Some(try!(found)),
+        None => None,
+    };
+    // its key was only ever for this note
+    if let Some((Some(ref data_key), _)) = taken {
+        try!(trans.execute("DELETE FROM data_key WHERE id = $1", &[data_key]));
+    }
+    try!(trans.commit());
+    Ok(taken.map(|(_, note)| note))
+}
+
+/// Forget pending notes that were never confirmed, and their keys, returning how many.
+pub fn clean_pending_notes(conn: &Connection) -> Result<u64, Error> {
+    Ok(try!(conn.query("
+        WITH gone AS (
+            DELETE FROM pending_note
+            WHERE received < now() - $1::integer * interval '1 day'
+            RETURNING data_key
+        ), keys AS (
+            DELETE FROM data_key
+            WHERE id IN (SELECT data_key FROM gone)
+        )
+        SELECT count(*) as count FROM gone",
+        &[&(sender::PENDING_DAYS as i32)]))
+        .into_iter()
+        .map(|row| row.get::<_, i64>("count") as u64)
+        .next()
+        .unwrap_or(0))
+}
+
+pub fn add_session(conn: &Connection, author: &str, user_agent: Option<&str>) -> Result<Uuid, Error> {
+    Ok(try!(conn
+        .query("
+            INSERT INTO session (author, user_agent)
+            VALUES ($1, $2)
+            RETURNING id",
+            &[&author, &user_agent]))
+        .into_iter()
+        .map(|row| row.get("id"))
+        .next()
+        .unwrap())  // INSERT ... RETURNING always gives back the row
+}
+
+/// A session that's still good, marking it as just used.
+pub fn live_session(conn: &Connection, id: &Uuid) -> Result<Option<Session>, Error> {
+    Ok(try!(conn
+        .query("
+            UPDATE session
+                SET last_seen = now()
+            FROM author
+            WHERE session.id = $1
+              AND session.author = author.email
+              AND author.deleted_at IS NULL
+              AND revoked IS NULL
+              AND last_seen > now() - $2::integer * interval '1 day'
+            RETURNING session.id, session.author, session.created, session.last_seen, session.user_agent",
+            &[id, &(session::SESSION_DAYS as i32)]))
+        .into_iter()
+        .map(Session::from_row)
+        .next())
+}
+
+/// An author's live sessions, most recently used first.
+pub fn author_sessions(conn: &Connection, author: &str) -> Result<Vec<Session>, Error> {
+    Ok(try!(conn
+        .query("
+            SELECT id, author, created, last_seen, user_agent
+            FROM session
+            WHERE author = $1
+              AND revoked IS NULL
+              AND last_seen > now() - $2::integer * interval '1 day'
+            ORDER BY last_seen DESC",
+            &[&author, &(session::SESSION_DAYS as i32)]))
+        .into_iter()
+        .map(Session::from_row)
+        .collect())
+}
+
+/// Sign a session out. Returns false if the author had no such session.
+pub fn revoke_session(conn: &Connection, author: &str, id: &Uuid) -> Result<bool, Error> {
+    Ok(try!(conn.execute("
+        UPDATE session
+            SET revoked = now()
+        WHERE id = $1
+          AND author = $2
+          AND revoked IS NULL",
+        &[id, &author])) == 1)
+}
+
+/// Forget sign-in links and sessions that can't be used any more, and old wrong passphrases.
+pub fn clean_sessions(conn: &Connection) -> Result<(), Error> {
+    try!(conn.execute("DELETE FROM sign_in_token WHERE expires < now() - interval '1 day'", &[]));
+    try!(conn.execute("DELETE FROM unlock_failure WHERE created < now() - interval '1 day'", &[]));
+    try!(conn.execute("
+        DELETE FROM session
+        WHERE revoked < now() - interval '1 day'
+           OR last_seen < now() - $1::integer * interval '1 day'",
+        &[&(session::SESSION_DAYS as i32)]));
+    Ok(())
+}
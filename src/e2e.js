@@ -0,0 +1,90 @@
+// End-to-end encrypted topics: everything secret happens here, in the browser. See e2e.rs
+// for how notes are sealed; this undoes it, and makes a topic's keys in the first place.
+(function () {
+    "use strict";
+    var PREFIX = "e2e1:";
+    var subtle = window.crypto && window.crypto.subtle;
+    var text = new TextEncoder();
+
+    function bytes(b64) {
+        return Uint8Array.from(atob(b64), function (c) { return c.charCodeAt(0); });
+    }
+
+    function base64(buffer) {
+        return btoa(String.fromCharCode.apply(null, new Uint8Array(buffer)));
+    }
+
+    // the AES key that wraps the topic's private key, from the passphrase
+    async function wrappingKey(passphrase, salt, iterations, usage) {
+        var material = await subtle.importKey("raw", text.encode(passphrase), "PBKDF2", false, ["deriveKey"]);
+        return subtle.deriveKey(
+            { name: "PBKDF2", salt: salt, iterations: iterations, hash: "SHA-256" },
+            material, { name: "AES-GCM", length: 256 }, false, [usage]);
+    }
+
+    async function open(privateKey, sealed) {
+        var envelope = bytes(sealed.slice(PREFIX.length));
+        var ephemeral = envelope.slice(0, 32);
+        var peer = await subtle.importKey("raw", ephemeral, { name: "X25519" }, false, []);
+        var shared = await subtle.deriveBits({ name: "X25519", public: peer }, privateKey, 256);
+        var hkdf = await subtle.importKey("raw", shared, "HKDF", false, ["deriveKey"]);
+        var key = await subtle.deriveKey(
+            { name: "HKDF", hash: "SHA-256", salt: ephemeral, info: text.encode("write-only note") },
+            hkdf, { name: "AES-GCM", length: 256 }, false, ["decrypt"]);
+        var html = await subtle.decrypt({ name: "AES-GCM", iv: envelope.slice(32, 44) }, key, envelope.slice(44));
+        return new TextDecoder().decode(html);
+    }
+
+    var decrypt = document.getElementById("decrypt");
+    if (decrypt) {
+        decrypt.addEventListener("submit", async function (event) {
+            event.preventDefault();
+            var status = decrypt.querySelector(".status");
+            var data = decrypt.dataset;
+            try {
+                var wrapping = await wrappingKey(decrypt.querySelector("input").value,
+                    bytes(data.salt), Number(data.iterations), "decrypt");
+                var pkcs8 = await subtle.decrypt({ name: "AES-GCM", iv: bytes(data.iv) }, wrapping, bytes(data.wrapped));
+                var privateKey = await subtle.importKey("pkcs8", pkcs8, { name: "X25519" }, false, ["deriveBits"]);
+            } catch (e) {
+                status.textContent = "That passphrase didn't work.";
+                return;
+            }
+            var notes = document.querySelectorAll("[data-ciphertext]");
+            for (var i = 0; i < notes.length; i++) {
+                try {
+                    // notes were sanitized before they were sealed, like every other note
+                    notes[i].innerHTML = await open(privateKey, notes[i].dataset.ciphertext);
+                    notes[i].removeAttribute("data-ciphertext");
+                } catch (e) {
+                    notes[i].textContent = "This note couldn't be decrypted.";
+                }
+            }
+            decrypt.hidden = true;
+        });
+    }
+
+    var setup = document.getElementById("encrypt-setup");
+    if (setup) {
+        setup.addEventListener("submit", async function (event) {
+            event.preventDefault();
+            var passphrase = document.getElementById("passphrase").value;
+            if (passphrase !== document.getElementById("passphrase-again").value) {
+                setup.querySelector(".status").textContent = "The passphrases don't match.";
+                return;
+            }
+            var pair = await subtle.generateKey({ name: "X25519" }, true, ["deriveBits"]);
+            var salt = window.crypto.getRandomValues(new Uint8Array(16));
+            var iv = window.crypto.getRandomValues(new Uint8Array(12));
+            var wrapping = await wrappingKey(passphrase, salt, Number(setup.dataset.iterations), "encrypt");
+            var pkcs8 = await subtle.exportKey("pkcs8", pair.privateKey);
+            var fields = setup.elements;
+            fields.public_key.value = base64(await subtle.exportKey("raw", pair.publicKey));
+            fields.wrapped_key.value = base64(await subtle.encrypt({ name: "AES-GCM", iv: iv }, wrapping, pkcs8));
+            fields.salt.value = base64(salt);
+            fields.iv.value = base64(iv);
+            // the passphrase fields have no names, so they never leave the browser
+            setup.submit();
+        });
+    }
+}());
@@ -0,0 +1,107 @@
+//! End-to-end encrypted topics, which only the author's passphrase can open.
+//!
+//! When an author turns encryption on, their browser makes an X25519 key pair, wraps the
+//! private key with AES-GCM under a key derived from their passphrase (PBKDF2), and sends us
+//! the public key and the wrapped private key. From then on every note for the topic is
+//! sealed to the public key as it arrives: a fresh ephemeral key pair, X25519 with the
+//! topic's key, HKDF-SHA256 and AES-256-GCM. Pages ship the sealed notes, the wrapped key and
+//! `e2e.js`, which does all of that backwards in the reader's browser.
+//!
+//! The server never sees the passphrase or the private key, but it still sees each note in
+//! the clear on its way in (in the email, or from the composer), and these stay in the clear
+//! for good: the topic's name, who wrote it, when each note was written, arrived, was edited
+//! or is due to open or fade, roughly how long each note is, and the sender's email headers
+//! that were used while filing it. Search can't see inside sealed notes, and exports and
+//! letters carry them sealed.
+
+use crypto::aead::AeadEncryptor;
+use crypto::aes::KeySize;
+use crypto::aes_gcm::AesGcm;
+use crypto::curve25519::{curve25519, curve25519_base};
+use crypto::hkdf::{hkdf_expand, hkdf_extract};
+use crypto::sha2::Sha256;
+use rand::{OsRng, Rng};
+use rustc_serialize::base64::{FromBase64, ToBase64, STANDARD};
+
+
+/// Marks a post body as sealed, and which scheme sealed it.
+pub const PREFIX: &'static str = "e2e1:";
+
+/// How hard the browser works to turn a passphrase into a key.
+pub const PBKDF2_ITERATIONS: u32 = 600000;
+
+/// HKDF's `info`, so these keys are never mistaken for any other.
+const INFO: &'static [u8] = b"write-only note";
+
+const KEY_BYTES: usize = 32;
+const NONCE_BYTES: usize = 12;
+const TAG_BYTES: usize = 16;
+
+
+/// A topic's public key, sent by the author's browser as base64.
+pub fn public_key(b64: &str) -> Option<Vec<u8>> {
+    b64.from_base64().ok().and_then(|key| if key.len() == KEY_BYTES { Some(key) } else { None })
+}
+
+pub fn is_sealed(body: &str) -> bool {
+    body.starts_with(PREFIX)
+}
+
+/// Seal a note's (already sanitized) HTML so only the holder of `public_key`'s private key
+/// can read it.
+pub fn seal(public_key: &[u8], html: &str) -> String {
+    let mut rng = OsRng::new().expect("the OS can provide randomness");
+    let mut secret = [0u8; KEY_BYTES];
+    rng.fill_bytes(&mut secret);
+    let mut nonce = [0u8; NONCE_BYTES];
+    rng.fill_bytes(&mut nonce);
+
+    let ephemeral = curve25519_base(&secret);
+    let key = note_key(&curve25519(&secret, public_key), &ephemeral);
+
+    let mut sealed = vec![0u8; html.len()];
+    let mut tag = [0u8; TAG_BYTES];
+    AesGcm::new(KeySize::KeySize256, &key, &nonce, &[])
+        .encrypt(html.as_bytes(), &mut sealed, &mut tag);
+
+    let mut envelope = Vec::with_capacity(KEY_BYTES + NONCE_BYTES + sealed.len() + TAG_BYTES);
+    envelope.extend_from_slice(&ephemeral);
+    envelope.extend_from_slice(&nonce);
+    envelope.extend_from_slice(&sealed);
+    envelope.extend_from_slice(&tag);
+    format!("{}{}", PREFIX, envelope.to_base64(STANDARD))
+}
+
+/// The AES key for one note, from the X25519 shared secret, salted with the ephemeral key.
+fn note_key(shared: &[u8], ephemeral: &[u8]) -> [u8; KEY_BYTES] {
+    let mut prk = [0u8; KEY_BYTES];
+    hkdf_extract(Sha256::new(), ephemeral, shared, &mut prk);
+    let mut key = [0u8; KEY_BYTES];
+    hkdf_expand(Sha256::new(), &prk, INFO, &mut key);
+    key
+}
+
+
+#[test]
+fn test_seal() {
+    use crypto::aead::AeadDecryptor;
+
+    let secret = [7u8; KEY_BYTES];
+    let public = curve25519_base(&secret);
+    let html = "<p>dear diary</p>";
+    let sealed = seal(&public, html);
+    assert!(is_sealed(&sealed));
+    assert!(sealed != seal(&public, html));
+
+    let envelope = sealed[PREFIX.len()..].from_base64().unwrap();
+    let (ephemeral, rest) = envelope.split_at(KEY_BYTES);
+    let (nonce, rest) = rest.split_at(NONCE_BYTES);
+    let (ciphertext, tag) = rest.split_at(rest.len() - TAG_BYTES);
+    let key = note_key(&curve25519(&secret, ephemeral), ephemeral);
+    let mut opened = vec![0u8; ciphertext.len()];
+    assert!(AesGcm::new(KeySize::KeySize256, &key, nonce, &[]).decrypt(ciphertext, &mut opened, tag));
+    assert_eq!(String::from_utf8(opened).unwrap(), html);
+
+    assert_eq!(public_key(&public.to_base64(STANDARD)), Some(public.to_vec()));
+    assert_eq!(public_key("c2hvcnQ="), None);
+}
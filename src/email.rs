@@ -1,83 +1,243 @@
+use chrono_tz::Tz;
 use hyper::Client;
 use hyper::header::{Authorization, Basic, Connection, ContentType};
 use hyper::mime::{Mime, TopLevel, SubLevel};
 use hyper::net::HttpsConnector;
 use hyper_rustls;
-use url::percent_encoding::{PATH_SEGMENT_ENCODE_SET, QUERY_ENCODE_SET, utf8_percent_encode};
+use url::form_urlencoded;
+use url::percent_encoding::{PATH_SEGMENT_ENCODE_SET, utf8_percent_encode};
 use uuid::Uuid;
 
+use db::{Fading, Letter};
+use e2e;
+use html;
+use sender;
+use session;
+use settings::Settings;
 
-pub fn welcome(domain: &str, api_key: &str, to: &str, topic: &str, topic_key: &Uuid, user_key: &Uuid, message_id: Option<&str>) {
-    let from = "write-only <note@write-only.space>";
-    let html = {
-        let title = "Welcome to write-only 🌘";
-        let header = tag!(td[style="padding: 1.5em 1em 1em 1em; text-align: center; font-size: 18px"][bgcolor="#000000"]:
-            tag!(a[href="http://write-only.space"][style="color: #ffff00; text-decoration:none"]: "write-only☄space"));
-        let u_link = format!("{}/{}",
-            utf8_percent_encode(domain, PATH_SEGMENT_ENCODE_SET),
-            utf8_percent_encode(&user_key.to_string(), PATH_SEGMENT_ENCODE_SET));
-        let thread_link = format!("{}/t/{}",
-            utf8_percent_encode(domain, PATH_SEGMENT_ENCODE_SET),
-            utf8_percent_encode(&topic_key.to_string(), PATH_SEGMENT_ENCODE_SET));
-        let main = tag!(td[bgcolor="#003344"][style="color: #ffffff; padding: 1em 1em 1em 1em; font-size: 18px"]:
-            tag!(b[style="font-size: 24px; padding: 1em 0 1em 0;"]: title),
-            tag!(p:
-                "You just posted ",
-                tag!(a[href=thread_link][style="font-weight: bold; color: #ffff00; text-decoration:none"]:
-                    "your first note"),
-                " – awesome!"),
-            tag!(p:
-                "Everything you post to write-only is ",
-                tag!(em: "unlisted"),
-                ", which means only people with the link can find it. Here is the special link that shows everything posted from your email address:"),
-            tag!(p:
-                tag!(a[href=u_link][style="font-weight: bold; color: ffff00; text-decoration:none"]:
-                    u_link)),
+
+const LINK_STYLE: &'static str = "font-weight: bold; color: #ffff00; text-decoration:none";
+
+
+/// Welcome a new author, asking them to confirm their address with `confirm_token` if they
+/// haven't yet.
+pub fn welcome(settings: &Settings, to: &str, topic: &str, topic_key: &Uuid, user_key: &Uuid, confirm_token: Option<&str>, message_id: Option<&str>) {
+    let title = "Welcome to write-only 🌘";
+    let confirm = confirm_token.map_or(String::new(), |token| {
+        let link = format!("{}/welcome/{}", settings.base_url, token);
+        join!(
             tag!(p:
-                "Your notes are grouped by the email's subject line, so you can post more about ",
-                tag!(b: topic),
-                " by simply replying to this email, or sending new emails with the same subject."),
-            tag!(p: "That's it!"),
-            tag!(p: "Happy writing ✎"));
-        join![
-            "<!DOCTYPE html PUBLIC \"-//W3C//DTD XHTML 1.0 Transitional//EN\" \"http://www.w3.org/TR/xhtml1/DTD/xhtml1-transitional.dtd\">",
-            tag!(html[xmlns="http://www.w3.org/1999/xhtml"]:
-                tag!(head:
-                    "<meta http-equiv=\"Content-Type\" content=\"text/html; charset=UTF-8\" />",
-                    tag!(title: title),
-                    tag!(meta[name="viewport"][content="width=device-width, initial-scale=1.0"])),
-                tag!(body[style="margin: 0; padding: 0;"]:
-                    tag!(table[border="0"][cellpadding="0"][cellspacing="0"][width="400"]:
-                        tag!(tr: header),
-                        tag!(tr: main))))
-        ]
-    };
-    let mut payload = format!("from={}&to={}&subject={}&html={}&o:tag=welcome",
-        utf8_percent_encode(&from, QUERY_ENCODE_SET),
-        utf8_percent_encode(&to, QUERY_ENCODE_SET),
-        utf8_percent_encode(&topic, QUERY_ENCODE_SET),
-        utf8_percent_encode(&html, QUERY_ENCODE_SET));
-    if let Some(mid) = message_id {
-        payload.push_str(&format!("&h:In-Reply-To={id}&h:References={id}",
-            id = mid));  // assume that the header needs no encoding
+                "First, make sure this is really you: nothing you post is shown until you ",
+                tag!(a[href=link][style=LINK_STYLE]: "confirm your address"),
+                ". If you don't within ", session::CONFIRM_DAYS, " days, your notes are deleted."),
+            tag!(p: "If you didn't send us a note, someone else used your address. Ignore this email and it'll all go away."))
+    });
+    let u_link = format!("{}/{}",
+        settings.base_url,
+        utf8_percent_encode(&user_key.to_string(), PATH_SEGMENT_ENCODE_SET));
+    let thread_link = format!("{}/t/{}",
+        settings.base_url,
+        utf8_percent_encode(&topic_key.to_string(), PATH_SEGMENT_ENCODE_SET));
+    let html = layout(settings, title, join!(
+        confirm,
+        tag!(p:
+            "You just posted ",
+            tag!(a[href=thread_link][style=LINK_STYLE]:
+                "your first note"),
+            " under the topic ",
+            tag!(b: topic),
+            " – awesome!"),
+        tag!(p:
+            "Everything you post to write-only is ",
+            tag!(em: "unlisted"),
+            ", which means only people with the link can find it. Here is the special link that shows everything posted from your email address:"),
+        tag!(p:
+            tag!(a[href=u_link][style=LINK_STYLE]:
+                u_link)),
+        tag!(p:
+            "Your notes are grouped by the email's subject line, so you can post more about ",
+            tag!(b: topic),
+            " by simply replying to this email, or sending new emails with the same subject."),
+        tag!(p:
+            "Dates are shown in the timezone your email came from, as best we can tell. To pick a different one, send an email with ",
+            tag!(b: "!timezone Europe/Paris"),
+            " (or wherever you are) as the subject."),
+        tag!(p:
+            "To seal a note until later, end its subject with ",
+            tag!(b: "[at 2027-01-01]"),
+            ", or with ",
+            tag!(b: "[letter 2027-01-01]"),
+            " to have it mailed back to you that day. To let one fade away, add ",
+            tag!(b: "[fade 30d]"),
+            "."),
+        tag!(p:
+            "To keep a topic to yourself, start its subject with ",
+            tag!(b: "[private]"),
+            ". Private topics only show up when you're signed in."),
+        tag!(p: "That's it!"),
+        tag!(p: "Happy writing ✎")));
+    send(settings, to, topic, &html, "welcome", message_id);
+}
+
+
+pub fn export_link(settings: &Settings, to: &str, user_key: &Uuid) {
+    let title = "Your write-only export 📦";
+    let link = format!("{}/{}/export.zip",
+        settings.base_url,
+        utf8_percent_encode(&user_key.to_string(), PATH_SEGMENT_ENCODE_SET));
+    let html = layout(settings, title, join!(
+        tag!(p: "You asked for a copy of all of your notes."),
+        tag!(p:
+            "This link downloads a zip file with a folder for each topic and a Markdown file for each note: ",
+            tag!(a[href=link][style=LINK_STYLE]: "download your notes")),
+        tag!(p: "Private topics are only included if you're signed in when you download it."),
+        tag!(p: "Happy writing ✎")));
+    send(settings, to, "Your write-only export", &html, "export", None);
+}
+
+
+pub fn sign_in(settings: &Settings, to: &str, token: &str) {
+    let title = "Sign in to write-only 🔑";
+    let link = format!("{}/sign-in/{}", settings.base_url, token);
+    let html = layout(settings, title, join!(
+        tag!(p: "Someone (hopefully you) asked to sign in to write-only as ", to, "."),
+        tag!(p: tag!(a[href=link][style=LINK_STYLE]: "Sign in")),
+        tag!(p: "The link works once, for the next ", session::LINK_MINUTES, " minutes. If you didn't ask for it, you can ignore this email.")));
+    send(settings, to, "Sign in to write-only", &html, "sign-in", None);
+}
+
+
+/// Mail a sealed note back to its author now that it's open, returning whether it was sent.
+pub fn letter(settings: &Settings, letter: &Letter, zone: &Tz) -> bool {
+    let title = "A letter from your past self 💌";
+    let link = format!("{}/t/{}#{}",
+        settings.base_url,
+        utf8_percent_encode(&letter.topic_key.to_string(), PATH_SEGMENT_ENCODE_SET),
+        letter.post.id);
+    let written = letter.post.timestamp.with_timezone(zone).format("%B %e, %Y").to_string();
+    let html = layout(settings, title, join!(
+        tag!(p:
+            "On ", written, " you wrote this under ",
+            tag!(a[href=link][style=LINK_STYLE]: &letter.topic),
+            " and sealed it until today:"),
+        if e2e::is_sealed(&letter.post.body) {
+            tag!(p: "It's encrypted end-to-end, so open it ", tag!(a[href=link][style=LINK_STYLE]: "on the site"), " with your passphrase.")
+        } else {
+            tag!(blockquote: &letter.post.body)
+        },
+        tag!(p: "Happy writing ✎")));
+    send(settings, &letter.author, &letter.topic, &html, "letter", None)
+}
+
+
+/// Warn an author about notes that are about to fade, returning whether it was sent.
+pub fn fading(settings: &Settings, to: &str, posts: &[Fading], zone: &Tz) -> bool {
+    let title = "Some notes are fading 🍂";
+    let items = posts
+        .iter()
+        .map(|post| {
+            let link = format!("{}/t/{}#{}",
+                settings.base_url,
+                utf8_percent_encode(&post.topic_key.to_string(), PATH_SEGMENT_ENCODE_SET),
+                post.post);
+            let when = post.fades_at.with_timezone(zone).format("%B %e at %H:%M").to_string();
+            tag!(li: tag!(a[href=link][style=LINK_STYLE]: &post.topic), " – ", when)
+        })
+        .collect::<Vec<String>>()
+        .join("");
+    let html = layout(settings, title, join!(
+        tag!(p: "These notes will be gone within a day:"),
+        tag!(ul: items),
+        tag!(p: "If you want to keep one, now's the time to copy it somewhere."),
+        tag!(p: "Happy writing ✎")));
+    send(settings, to, "Some notes are fading", &html, "fading", None)
+}
+
+
+/// Ask the real owner of an address whether they sent a note that couldn't be authenticated.
+pub fn confirm_note(settings: &Settings, to: &str, subject: &str, token: &str, message_id: Option<&str>) {
+    let title = "Was this you? 🔍";
+    let link = format!("{}/confirm/{}", settings.base_url, token);
+    let html = layout(settings, title, join!(
+        tag!(p: "We got a note from ", to, " with the subject ", tag!(b: html::escape(subject)),
+            ", but couldn't be sure it really came from you, so it's waiting."),
+        tag!(p: tag!(a[href=link][style=LINK_STYLE]: "Check it, and add it if it's yours")),
+        tag!(p: "If you didn't send it, someone may be using your address: ignore this email and it'll be gone in ",
+            sender::PENDING_DAYS, " days.")));
+    send(settings, to, &format!("Re: {}", subject), &html, "confirm-note", message_id);
+}
+
+
+/// Answer an emailed command, in the same thread.
+pub fn command_reply(settings: &Settings, to: &str, subject: &str, reply: &str, message_id: Option<&str>) {
+    let html = layout(settings, "Got it ⚙", join!(
+        tag!(p: reply),
+        tag!(p: "Happy writing ✎")));
+    send(settings, to, &format!("Re: {}", subject), &html, "command", message_id);
+}
+
+
+/// Wrap the main content of an email in the write-only header and colours.
+fn layout(settings: &Settings, title: &str, content: String) -> String {
+    let home = &settings.base_url;
+    let header = tag!(td[style="padding: 1.5em 1em 1em 1em; text-align: center; font-size: 18px"][bgcolor="#000000"]:
+        tag!(a[href=home][style="color: #ffff00; text-decoration:none"]: "write-only☄space"));
+    let main = tag!(td[bgcolor="#003344"][style="color: #ffffff; padding: 1em 1em 1em 1em; font-size: 18px"]:
+        tag!(b[style="font-size: 24px; padding: 1em 0 1em 0;"]: title),
+        content);
+    join![
+        "<!DOCTYPE html PUBLIC \"-//W3C//DTD XHTML 1.0 Transitional//EN\" \"http://www.w3.org/TR/xhtml1/DTD/xhtml1-transitional.dtd\">",
+        tag!(html[xmlns="http://www.w3.org/1999/xhtml"]:
+            tag!(head:
+                "<meta http-equiv=\"Content-Type\" content=\"text/html; charset=UTF-8\" />",
+                tag!(title: title),
+                tag!(meta[name="viewport"][content="width=device-width, initial-scale=1.0"])),
+            tag!(body[style="margin: 0; padding: 0;"]:
+                tag!(table[border="0"][cellpadding="0"][cellspacing="0"][width="400"]:
+                    tag!(tr: header),
+                    tag!(tr: main))))
+    ]
+}
+
+
+/// Send an email through mailgun, returning whether it was accepted.
+///
+/// `in_reply_to` threads the email under one of the author's own messages.
+fn send(settings: &Settings, to: &str, subject: &str, html: &str, tag: &str, in_reply_to: Option<&str>) -> bool {
+    let from = format!("write-only <{}>", settings.inbound_address);
+    let mut form = form_urlencoded::Serializer::new(String::new());
+    form.append_pair("from", &from)
+        .append_pair("to", to)
+        .append_pair("subject", subject)
+        .append_pair("html", html)
+        .append_pair("o:tag", tag);
+    if let Some(mid) = in_reply_to {
+        form.append_pair("h:In-Reply-To", mid)
+            .append_pair("h:References", mid);
     }
+    let payload = form.finish();
     let response = Client::with_connector(HttpsConnector::new(hyper_rustls::TlsClient::new()))
-        .post(&format!("https://api.mailgun.net/v3/{}/messages", domain))
+        .post(&format!("https://api.mailgun.net/v3/{}/messages", settings.mailgun.domain))
         .header(Authorization(Basic {
             username: "api".to_owned(),
-            password: Some(api_key.to_owned())
+            password: Some(settings.mailgun.key.clone())
         }))
         .header(ContentType(Mime(TopLevel::Application, SubLevel::WwwFormUrlEncoded, vec![])))
         .header(Connection::close())
         .body(&payload)
         .send();
-    if let Ok(r) = response {
-        if r.status.is_success() {
-            println!("sent welcome email to {}", to);
-        } else {
-            println!("failed to send welcome email to {}: {}", to, r.status);
-        }
-    } else {
-        println!("failed to send welcome email to {}", to);
-    };
+    match response {
+        Ok(ref r) if r.status.is_success() => {
+            println!("sent {} email to {}", tag, to);
+            true
+        },
+        Ok(r) => {
+            println!("failed to send {} email to {}: {}", tag, to, r.status);
+            false
+        },
+        Err(_) => {
+            println!("failed to send {} email to {}", tag, to);
+            false
+        },
+    }
 }
@@ -0,0 +1,86 @@
+use std::error::Error;
+use std::fmt;
+
+use iron::status::Status;
+use params;
+use postgres;
+use r2d2;
+
+
+pub type AppResult<T> = Result<T, AppError>;
+
+
+#[derive(Debug)]
+pub enum AppError {
+    /// couldn't get a connection from the pool in time: probably temporary
+    PoolTimeout(r2d2::GetTimeout),
+    Db(postgres::error::Error),
+    Params(params::ParamsError),
+    MissingParam(String),
+    /// a form was posted without its session's CSRF token
+    Forgery,
+}
+
+impl AppError {
+    pub fn status(&self) -> Status {
+        match *self {
+            AppError::PoolTimeout(_) => Status::ServiceUnavailable,
+            AppError::Db(_) => Status::InternalServerError,
+            AppError::Params(_) |
+            AppError::MissingParam(_) => Status::BadRequest,
+            AppError::Forgery => Status::Forbidden,
+        }
+    }
+}
+
+impl fmt::Display for AppError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            AppError::PoolTimeout(ref e) => write!(f, "database pool: {}", e),
+            AppError::Db(ref e) => write!(f, "database: {}", e),
+            AppError::Params(ref e) => write!(f, "request params: {}", e),
+            AppError::MissingParam(ref name) => write!(f, "request params: missing `{}`", name),
+            AppError::Forgery => write!(f, "form posted without a valid CSRF token"),
+        }
+    }
+}
+
+impl Error for AppError {
+    fn description(&self) -> &str {
+        match *self {
+            AppError::PoolTimeout(_) => "timed out waiting for a database connection",
+            AppError::Db(_) => "database error",
+            AppError::Params(_) => "could not parse request params",
+            AppError::MissingParam(_) => "missing request param",
+            AppError::Forgery => "bad CSRF token",
+        }
+    }
+
+    fn cause(&self) -> Option<&Error> {
+        match *self {
+            AppError::PoolTimeout(ref e) => Some(e),
+            AppError::Db(ref e) => Some(e),
+            AppError::Params(ref e) => Some(e),
+            AppError::MissingParam(_) |
+            AppError::Forgery => None,
+        }
+    }
+}
+
+impl From<r2d2::GetTimeout> for AppError {
+    fn from(err: r2d2::GetTimeout) -> AppError {
+        AppError::PoolTimeout(err)
+    }
+}
+
+impl From<postgres::error::Error> for AppError {
+    fn from(err: postgres::error::Error) -> AppError {
+        AppError::Db(err)
+    }
+}
+
+impl From<params::ParamsError> for AppError {
+    fn from(err: params::ParamsError) -> AppError {
+        AppError::Params(err)
+    }
+}
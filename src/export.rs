@@ -0,0 +1,707 @@
+use std::cmp::min;
+use std::collections::{BTreeMap, HashSet};
+use std::iter;
+
+use chrono::{DateTime, UTC};
+use rustc_serialize::json::{self, Json};
+use uuid::Uuid;
+
+use archive::Zip;
+use db::{Post, Topic};
+use html::escape;
+use mail;
+
+
+/// Everything by one author as a zip: a folder of Markdown files per topic, plus
+/// `manifest.json`.
+///
+/// Folders and files are named and ordered only from the notes themselves, so exporting the
+/// same notes twice gives identical archives.
+pub fn markdown_zip(author: &str, mut topics: Vec<(Topic, Vec<Post>)>) -> Vec<u8> {
+    topics.sort_by(|&(ref a, _), &(ref b, _)| (slug(&a.topic), a.key).cmp(&(slug(&b.topic), b.key)));
+
+    let mut zip = Zip::new();
+    let mut folders = HashSet::new();
+    let mut manifest_topics = vec![];
+    let mut newest = None;
+
+    for (topic, mut posts) in topics {
+        posts.sort_by(|a, b| (a.timestamp, a.id).cmp(&(b.timestamp, b.id)));
+        let folder = unique(&mut folders, slug(&topic.topic));
+        let mut files = HashSet::new();
+        let mut manifest_posts = vec![];
+
+        for post in posts {
+            let file = unique(&mut files, post.timestamp.format("%Y-%m-%d-%H%M%S").to_string());
+            let path = format!("{}/{}.md", folder, file);
+            let document = format!("---\ntopic: {}\ntimestamp: {}\n---\n\n{}\n",
+                Json::String(topic.topic.clone()),
+                timestamp(&post.timestamp),
+                to_markdown(&post.body));
+            zip.add(&path, &post.timestamp, document.as_bytes());
+
+            let mut entry = BTreeMap::new();
+            entry.insert("file".to_string(), Json::String(path));
+            entry.insert("id".to_string(), Json::String(post.id.to_string()));
+            entry.insert("timestamp".to_string(), Json::String(timestamp(&post.timestamp)));
+            manifest_posts.push(Json::Object(entry));
+            if newest.as_ref().map_or(true, |t| post.timestamp > *t) {
+                newest = Some(post.timestamp.clone());
+            }
+        }
+
+        let mut entry = BTreeMap::new();
+        entry.insert("folder".to_string(), Json::String(folder));
+        entry.insert("key".to_string(), Json::String(topic.key.to_string()));
+        entry.insert("posts".to_string(), Json::Array(manifest_posts));
+        entry.insert("topic".to_string(), Json::String(topic.topic));
+        manifest_topics.push(Json::Object(entry));
+    }
+
+    let mut manifest = BTreeMap::new();
+    manifest.insert("author".to_string(), Json::String(author.to_string()));
+    manifest.insert("format".to_string(), Json::String("write-only markdown export v1".to_string()));
+    manifest.insert("topics".to_string(), Json::Array(manifest_topics));
+    let manifest = format!("{}\n", json::as_pretty_json(&Json::Object(manifest)));
+    let modified = newest.unwrap_or(DateTime::from_utc(
+        ::chrono::NaiveDate::from_ymd(1980, 1, 1).and_hms(0, 0, 0), UTC));
+    zip.add("manifest.json", &modified, manifest.as_bytes());
+
+    zip.finish()
+}
+
+
+/// Notes as an mbox of emails from the author, each topic threaded under its first note.
+///
+/// `inbound` is the address the notes were sent to; message ids are made from post ids at its
+/// domain, so importing an export elsewhere keeps the threading intact.
+pub fn mbox(author: &str, inbound: &str, topics: Vec<(Topic, Vec<Post>)>) -> String {
+    let domain = inbound.rsplitn(2, '@').next().unwrap_or(inbound);
+    let mut out = String::new();
+
+    for (topic, mut posts) in topics {
+        posts.sort_by(|a, b| (a.timestamp, a.id).cmp(&(b.timestamp, b.id)));
+        let mut first = None;
+
+        for post in posts {
+            let message_id = format!("<{}@{}>", post.id, domain);
+            let mut headers = vec![
+                ("From", author.to_string()),
+                ("To", inbound.to_string()),
+                ("Subject", mail::encode_word(&topic.topic)),
+                ("Date", post.timestamp.to_rfc2822()),
+                ("Message-Id", message_id.clone()),
+            ];
+            if let Some(ref thread) = first {
+                headers.push(("In-Reply-To", thread.clone()));
+                headers.push(("References", thread.clone()));
+            }
+            if first.is_none() {
+                first = Some(message_id);
+            }
+            out.push_str(&mail::mbox_message(&headers, &post.body, &post.timestamp));
+        }
+    }
+    out
+}
+
+/// The post an exported message was made from, going by its `<post-id@domain>` message id.
+pub fn exported_post_id(message_id: &str) -> Option<Uuid> {
+    message_id.trim_matches(|c| c == '<' || c == '>')
+        .split('@')
+        .next()
+        .and_then(|id| Uuid::parse_str(id).ok())
+}
+
+
+fn timestamp(t: &DateTime<UTC>) -> String {
+    t.format("%Y-%m-%dT%H:%M:%SZ").to_string()
+}
+
+/// A file-name-friendly version of a topic.
+fn slug(topic: &str) -> String {
+    let dashed = topic
+        .to_lowercase()
+        .chars()
+        .map(|c| if c.is_alphanumeric() { c } else { '-' })
+        .collect::<String>();
+    let slug = dashed
+        .split('-')
+        .filter(|part| part.len() > 0)
+        .collect::<Vec<&str>>()
+        .join("-")
+        .chars()
+        .take(60)
+        .collect::<String>();
+    if slug.is_empty() { "untitled".to_string() } else { slug }
+}
+
+/// `name`, or `name-2`, `name-3`... if it has already been used.
+fn unique(used: &mut HashSet<String>, name: String) -> String {
+    let mut candidate = name.clone();
+    let mut n = 1;
+    while used.contains(&candidate) {
+        n += 1;
+        candidate = format!("{}-{}", name, n);
+    }
+    used.insert(candidate.clone());
+    candidate
+}
+
+
+/// Convert a note's HTML (as mailgun hands it over) into Markdown.
+///
+/// Notes are emails, not web pages, so this only knows about the handful of tags that mail
+/// clients produce; anything else is dropped and its text kept.
+pub fn to_markdown(html: &str) -> String {
+    let mut md = Markdown {
+        out: String::new(),
+        pending: 0,
+        space: false,
+        line_start: true,
+        quotes: 0,
+        lists: vec![],
+        links: vec![],
+        pre: false,
+        skip: 0,
+    };
+    let mut rest = html;
+    while !rest.is_empty() {
+        if rest.starts_with("<!--") {
+            rest = rest.find("-->").map_or("", |end| &rest[end + 3..]);
+        } else if rest.starts_with('<') {
+            let end = match rest.find('>') {
+                Some(end) => end,
+                None => {
+                    // never closed, so it's text that happens to have a < in it
+                    md.text(&decode_entities(rest));
+                    break;
+                },
+            };
+            md.tag(&rest[1..end]);
+            rest = &rest[end + 1..];
+        } else {
+            let end = rest.find('<').unwrap_or(rest.len());
+            md.text(&decode_entities(&rest[..end]));
+            rest = &rest[end..];
+        }
+    }
+    md.out.trim().to_string()
+}
+
+
+struct Markdown {
+    out: String,
+    /// newlines owed before the next bit of content
+    pending: usize,
+    /// a space owed before the next bit of content on this line
+    space: bool,
+    /// at the start of a line or just after a list/heading marker
+    line_start: bool,
+    quotes: usize,
+    /// for each open list: the next number if it's ordered
+    lists: Vec<Option<u32>>,
+    links: Vec<Option<String>>,
+    pre: bool,
+    /// depth inside tags whose contents aren't text, like <style>
+    skip: usize,
+}
+
+impl Markdown {
+    fn block(&mut self) {
+        self.pending = 2;
+    }
+
+    fn line(&mut self) {
+        if self.pending < 1 {
+            self.pending = 1;
+        }
+    }
+
+    fn quote_prefix(&self) -> String {
+        iter::repeat("> ").take(self.quotes).collect()
+    }
+
+    fn write(&mut self, s: &str) {
+        if self.out.is_empty() {
+            self.out.push_str(&self.quote_prefix());
+            self.line_start = true;
+        } else if self.pending > 0 {
+            let prefix = self.quote_prefix();
+            for _ in 0..self.pending {
+                self.out.push('\n');
+                self.out.push_str(&prefix);
+            }
+            self.line_start = true;
+        }
+        if self.space && !self.line_start {
+            self.out.push(' ');
+        }
+        self.pending = 0;
+        self.space = false;
+        self.out.push_str(s);
+        self.line_start = false;
+    }
+
+    /// Closing markers hug the text before them, even if it ended with a space.
+    fn close(&mut self, s: &str) {
+        self.out.push_str(s);
+    }
+
+    fn text(&mut self, text: &str) {
+        if self.skip > 0 {
+            return;
+        }
+        if self.pre {
+            let prefix = format!("\n{}", self.quote_prefix());
+            self.write(&text.replace('\n', &prefix));
+            return;
+        }
+        let words = text.split_whitespace().collect::<Vec<&str>>().join(" ");
+        if text.starts_with(char::is_whitespace) {
+            self.space = true;
+        }
+        if !words.is_empty() {
+            self.write(&escape_markdown(&words));
+            self.space = text.ends_with(char::is_whitespace);
+        }
+    }
+
+    fn tag(&mut self, tag: &str) {
+        let closing = tag.starts_with('/');
+        let tag = tag.trim_left_matches('/');
+        let name_end = tag.find(|c: char| c.is_whitespace() || c == '/').unwrap_or(tag.len());
+        let name = tag[..name_end].to_lowercase();
+        let attrs = &tag[name_end..];
+
+        match (&name[..], closing) {
+            ("script", false) | ("style", false) | ("head", false) | ("title", false) => self.skip += 1,
+            ("script", true) | ("style", true) | ("head", true) | ("title", true) =>
+                self.skip = self.skip.saturating_sub(1),
+            _ if self.skip > 0 => {},
+
+            ("b", false) | ("strong", false) => self.write("**"),
+            ("b", true) | ("strong", true) => self.close("**"),
+            ("i", false) | ("em", false) => self.write("_"),
+            ("i", true) | ("em", true) => self.close("_"),
+            ("s", false) | ("strike", false) | ("del", false) => self.write("~~"),
+            ("s", true) | ("strike", true) | ("del", true) => self.close("~~"),
+            ("code", false) if !self.pre => self.write("`"),
+            ("code", true) if !self.pre => self.close("`"),
+
+            ("a", false) => {
+                let href = attr(attrs, "href");
+                if href.is_some() {
+                    self.write("[");
+                }
+                self.links.push(href);
+            },
+            ("a", true) => if let Some(Some(href)) = self.links.pop() {
+                self.close(&format!("]({})", href));
+            },
+            ("img", _) => if let Some(src) = attr(attrs, "src") {
+                let alt = attr(attrs, "alt").unwrap_or(String::new());
+                self.write(&format!("![{}]({})", escape_markdown(&alt), src));
+            },
+
+            ("br", _) => {
+                self.close("  ");
+                self.line();
+                self.space = false;
+            },
+            ("hr", _) => {
+                self.block();
+                self.write("---");
+                self.block();
+            },
+            ("p", _) | ("div", _) | ("table", _) | ("tr", _) => self.block(),
+            ("h1", false) | ("h2", false) | ("h3", false) |
+            ("h4", false) | ("h5", false) | ("h6", false) => {
+                let level = name[1..].parse().unwrap_or(1);
+                self.block();
+                self.write(&format!("{} ", iter::repeat("#").take(level).collect::<String>()));
+                self.line_start = true;
+            },
+            ("h1", true) | ("h2", true) | ("h3", true) |
+            ("h4", true) | ("h5", true) | ("h6", true) => self.block(),
+
+            ("blockquote", false) => {
+                self.block();
+                self.quotes += 1;
+            },
+            ("blockquote", true) => {
+                self.quotes = self.quotes.saturating_sub(1);
+                self.block();
+            },
+            ("pre", false) => {
+                self.block();
+                self.write("```");
+                self.line();
+                self.pre = true;
+            },
+            ("pre", true) => {
+                self.pre = false;
+                self.line();
+                self.write("```");
+                self.block();
+            },
+
+            ("ul", false) | ("ol", false) => {
+                if self.lists.is_empty() { self.block() } else { self.line() }
+                self.lists.push(if name == "ol" { Some(1) } else { None });
+            },
+            ("ul", true) | ("ol", true) => {
+                self.lists.pop();
+                if self.lists.is_empty() { self.block() } else { self.line() }
+            },
+            ("li", false) => {
+                self.line();
+                let depth = self.lists.len().saturating_sub(1);
+                let marker = match self.lists.last_mut() {
+                    Some(&mut Some(ref mut n)) => {
+                        *n += 1;
+                        format!("{}. ", *n - 1)
+                    },
+                    _ => "- ".to_string(),
+                };
+                self.write(&format!("{}{}", iter::repeat("  ").take(depth).collect::<String>(), marker));
+                self.line_start = true;
+            },
+            ("li", true) => self.line(),
+
+            _ => {},
+        }
+    }
+}
+
+
+/// Turn Markdown back into HTML, for notes edited on the site.
+///
+/// This knows the same small part of Markdown that `to_markdown` writes, so a note keeps its
+/// formatting through an edit; anything else stays as text. A single line break is kept as
+/// one, since that's what someone typing a note means by it.
+pub fn from_markdown(md: &str) -> String {
+    let md = md.replace("\r\n", "\n");
+    blocks(&md.split('\n').collect::<Vec<&str>>())
+}
+
+fn blocks(lines: &[&str]) -> String {
+    let mut out = String::new();
+    let mut i = 0;
+    while i < lines.len() {
+        let line = lines[i];
+        let trimmed = line.trim();
+        if trimmed.is_empty() {
+            i += 1;
+        } else if line.starts_with('>') {
+            let mut quoted = vec![];
+            while i < lines.len() && lines[i].starts_with('>') {
+                let inner = &lines[i][1..];
+                quoted.push(if inner.starts_with(' ') { &inner[1..] } else { inner });
+                i += 1;
+            }
+            out.push_str(&format!("<blockquote>{}</blockquote>", blocks(&quoted)));
+        } else if trimmed.starts_with("```") {
+            let mut code = vec![];
+            i += 1;
+            while i < lines.len() && !lines[i].trim().starts_with("```") {
+                code.push(lines[i]);
+                i += 1;
+            }
+            i += 1;
+            out.push_str(&format!("<pre>{}</pre>", escape(&code.join("\n"))));
+        } else if trimmed == "---" {
+            out.push_str("<hr>");
+            i += 1;
+        } else if let Some((level, text)) = heading(line) {
+            out.push_str(&format!("<h{n}>{}</h{n}>", inline(text), n=level));
+            i += 1;
+        } else if list_item(line).is_some() {
+            let mut items = vec![];
+            while let Some(item) = lines.get(i).and_then(|line| list_item(line)) {
+                items.push(item);
+                i += 1;
+            }
+            let mut next = 0;
+            while next < items.len() {
+                out.push_str(&list(&items, &mut next));
+            }
+        } else {
+            let mut para = vec![];
+            while i < lines.len() && !lines[i].trim().is_empty() && (para.is_empty() || !starts_block(lines[i])) {
+                para.push(lines[i].trim());
+                i += 1;
+            }
+            out.push_str(&format!("<p>{}</p>", inline(&para.join("\n"))));
+        }
+    }
+    out
+}
+
+fn starts_block(line: &str) -> bool {
+    line.starts_with('>') || line.trim().starts_with("```") || line.trim() == "---"
+        || heading(line).is_some() || list_item(line).is_some()
+}
+
+fn heading(line: &str) -> Option<(usize, &str)> {
+    let level = line.find(|c: char| c != '#').unwrap_or(line.len());
+    if level >= 1 && level <= 6 && line[level..].starts_with(' ') {
+        Some((level, line[level + 1..].trim()))
+    } else {
+        None
+    }
+}
+
+/// A list item's depth (two spaces per level), whether its list is numbered, and its text.
+fn list_item(line: &str) -> Option<(usize, bool, &str)> {
+    let text = line.trim_left_matches(' ');
+    let depth = (line.len() - text.len()) / 2;
+    if text.starts_with("- ") {
+        return Some((depth, false, &text[2..]));
+    }
+    let digits = text.find(|c: char| !c.is_digit(10)).unwrap_or(0);
+    if digits > 0 && text[digits..].starts_with(". ") {
+        Some((depth, true, &text[digits + 2..]))
+    } else {
+        None
+    }
+}
+
+/// One list from `items[*next]` on, with any deeper lists nested in its items.
+fn list(items: &[(usize, bool, &str)], next: &mut usize) -> String {
+    let (depth, ordered, _) = items[*next];
+    let mut out = String::new();
+    while *next < items.len() && items[*next].0 == depth && items[*next].1 == ordered {
+        out.push_str("<li>");
+        out.push_str(&inline(items[*next].2));
+        *next += 1;
+        while *next < items.len() && items[*next].0 > depth {
+            out.push_str(&list(items, next));
+        }
+        out.push_str("</li>");
+    }
+    if ordered { tag!(ol: out) } else { tag!(ul: out) }
+}
+
+const EMPHASIS: &'static [(&'static str, &'static str)] =
+    &[("**", "b"), ("~~", "s"), ("_", "i"), ("`", "code")];
+
+fn inline(md: &str) -> String {
+    let mut out = String::with_capacity(md.len());
+    let mut open: Vec<(&str, &str)> = vec![];
+    let mut rest = md;
+    while let Some(c) = rest.chars().next() {
+        let emphasis = EMPHASIS.iter().find(|&&(marker, _)| rest.starts_with(marker));
+        let image = if c == '!' { link(&rest[1..]) } else { None };
+        let linked = if c == '[' { link(rest) } else { None };
+        if c == '\\' && rest.len() > 1 {
+            let escaped = rest[1..].chars().next().unwrap();
+            out.push_str(&escape(&escaped.to_string()));
+            rest = &rest[1 + escaped.len_utf8()..];
+        } else if let Some(&(marker, name)) = emphasis {
+            rest = &rest[marker.len()..];
+            if open.last().map_or(false, |&(m, _)| m == marker) {
+                open.pop();
+                out.push_str(&format!("</{}>", name));
+            } else if !open.iter().any(|&(m, _)| m == marker) && find_unescaped(rest, marker).is_some() {
+                open.push((marker, name));
+                out.push_str(&format!("<{}>", name));
+            } else {
+                out.push_str(marker);
+            }
+        } else if let Some((text, url, after)) = image {
+            let alt = escape(&unescape_markdown(text));
+            let src = escape(url);
+            out.push_str(&format!("<img src=\"{}\" alt=\"{}\">", src, alt));
+            rest = after;
+        } else if let Some((text, url, after)) = linked {
+            let href = escape(url);
+            out.push_str(&format!("<a href=\"{}\">{}</a>", href, inline(text)));
+            rest = after;
+        } else if c == '\n' {
+            out.push_str("<br>");
+            rest = &rest[1..];
+        } else {
+            out.push_str(&escape(&c.to_string()));
+            rest = &rest[c.len_utf8()..];
+        }
+    }
+    while let Some((_, name)) = open.pop() {
+        out.push_str(&format!("</{}>", name));
+    }
+    out
+}
+
+/// `[text](url)` at the start of `md`: the text, the url, and what comes after.
+fn link(md: &str) -> Option<(&str, &str, &str)> {
+    if !md.starts_with('[') {
+        return None;
+    }
+    let close = match find_unescaped(&md[1..], "](") {
+        Some(i) => i + 1,
+        None => return None,
+    };
+    let url_start = close + 2;
+    md[url_start..].find(')').map(|end| (&md[1..close], &md[url_start..url_start + end], &md[url_start + end + 1..]))
+}
+
+/// Where `marker` first appears in `md` other than after a backslash.
+fn find_unescaped(md: &str, marker: &str) -> Option<usize> {
+    let mut i = 0;
+    while i < md.len() {
+        if md[i..].starts_with('\\') {
+            i += 1 + md[i + 1..].chars().next().map_or(0, |c| c.len_utf8());
+        } else if md[i..].starts_with(marker) {
+            return Some(i);
+        } else {
+            i += md[i..].chars().next().map_or(1, |c| c.len_utf8());
+        }
+    }
+    None
+}
+
+fn unescape_markdown(md: &str) -> String {
+    let mut out = String::with_capacity(md.len());
+    let mut escaped = false;
+    for c in md.chars() {
+        if c == '\\' && !escaped {
+            escaped = true;
+        } else {
+            out.push(c);
+            escaped = false;
+        }
+    }
+    out
+}
+
+
+/// The (entity-decoded) value of an attribute in a tag's attribute text.
+pub fn attr(attrs: &str, name: &str) -> Option<String> {
+    let mut rest = attrs.trim_left();
+    while !rest.is_empty() {
+        let key_end = rest.find(|c: char| c == '=' || c.is_whitespace()).unwrap_or(rest.len());
+        let key = rest[..key_end].to_lowercase();
+        rest = rest[key_end..].trim_left();
+        let mut value = None;
+        if rest.starts_with('=') {
+            rest = rest[1..].trim_left();
+            let (v, after) = match rest.chars().next() {
+                Some(q) if q == '"' || q == '\'' => {
+                    let end = rest[1..].find(q).map_or(rest.len(), |i| i + 1);
+                    (&rest[1..end], &rest[min(end + 1, rest.len())..])
+                },
+                _ => {
+                    let end = rest.find(char::is_whitespace).unwrap_or(rest.len());
+                    (&rest[..end], &rest[end..])
+                },
+            };
+            value = Some(v);
+            rest = after.trim_left();
+        }
+        if key == name {
+            return value.map(decode_entities);
+        }
+    }
+    None
+}
+
+fn escape_markdown(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '\\' | '*' | '_' | '`' | '[' | ']' => escaped.push('\\'),
+            _ => {},
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+pub fn decode_entities(text: &str) -> String {
+    let mut decoded = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(amp) = rest.find('&') {
+        decoded.push_str(&rest[..amp]);
+        rest = &rest[amp..];
+        let replacement = rest.find(';')
+            .and_then(|end| if end <= 10 { Some(end) } else { None })
+            .and_then(|end| {
+                let name = &rest[1..end];
+                let c = match name {
+                    "amp" => Some('&'),
+                    "lt" => Some('<'),
+                    "gt" => Some('>'),
+                    "quot" => Some('"'),
+                    "apos" => Some('\''),
+                    "nbsp" => Some(' '),
+                    _ if name.starts_with("#x") || name.starts_with("#X") =>
+                        u32::from_str_radix(&name[2..], 16).ok().and_then(::std::char::from_u32),
+                    _ if name.starts_with('#') =>
+                        name[1..].parse().ok().and_then(::std::char::from_u32),
+                    _ => None,
+                };
+                c.map(|c| (c, end))
+            });
+        match replacement {
+            Some((c, end)) => {
+                decoded.push(c);
+                rest = &rest[end + 1..];
+            },
+            None => {
+                decoded.push('&');
+                rest = &rest[1..];
+            },
+        }
+    }
+    decoded.push_str(rest);
+    decoded
+}
+
+
+#[test]
+fn test_to_markdown() {
+    let cases = [
+        ("plain", "plain"),
+        ("<div>one</div><div>two</div>", "one\n\ntwo"),
+        ("line<br>break", "line  \nbreak"),
+        ("some <b>bold</b> and <i>slanted </i>words", "some **bold** and _slanted_ words"),
+        ("<a href=\"https://example.com/?a=1&amp;b=2\">a link</a>", "[a link](https://example.com/?a=1&b=2)"),
+        ("<ul><li>one</li><li>two<ol><li>inner</li></ol></li></ul>", "- one\n- two\n  1. inner"),
+        ("<h2>Title</h2><p>text</p>", "## Title\n\ntext"),
+        ("<blockquote><p>quoted</p><p>twice</p></blockquote>after", "> quoted\n> \n> twice\n\nafter"),
+        ("<pre>let x = 1;\nx * 2</pre>", "```\nlet x = 1;\nx * 2\n```"),
+        ("5 &lt; 6 &amp;&amp; 2*3 &#8212; &unknown; &", "5 < 6 && 2\\*3 \u{2014} &unknown; &"),
+        ("<style>p { color: red }</style><!-- hidden -->shown", "shown"),
+        ("<img src=\"cat.jpg\" alt=\"a cat\">", "![a cat](cat.jpg)"),
+        ("ends with <", "ends with <"),
+        ("<p>1 <é and_more", "1 <é and\\_more"),
+    ];
+    for &(html, md) in cases.iter() {
+        assert_eq!(to_markdown(html), md, "converting {:?}", html);
+    }
+}
+
+#[test]
+fn test_from_markdown() {
+    // what to_markdown writes comes back as the same (cleaned) HTML
+    let notes = [
+        "<p>plain</p>",
+        "<p>some <b>bold</b> and <i>slanted</i> words</p>",
+        "<p>line<br>break</p>",
+        "<p><a href=\"https://example.com/?a=1&amp;b=2\">a link</a> and <s>struck</s> <code>x_y</code></p>",
+        "<ul><li>one</li><li>two<ol><li>inner</li></ol></li></ul>",
+        "<h2>Title</h2><p>text</p>",
+        "<blockquote><p>quoted</p><p>twice</p></blockquote><p>after</p>",
+        "<pre>let x = 1;\nx * 2</pre>",
+        "<p>5 &lt; 6 &amp;&amp; 2*3 [not a link] \\ back_slash</p>",
+        "<p>a</p><hr><p>b</p>",
+        "<p><img src=\"https://example.com/cat.jpg\" alt=\"a cat\"></p>",
+    ];
+    for html in notes.iter() {
+        assert_eq!(&from_markdown(&to_markdown(html)), html, "round-tripping {:?}", html);
+    }
+
+    // and what people type means what it looks like
+    assert_eq!(from_markdown("snake_case\r\nand <b>"), "<p>snake_case<br>and &lt;b&gt;</p>");
+    assert_eq!(from_markdown("**bold\n\nnot bold**"), "<p>**bold</p><p>not bold**</p>");
+}
@@ -48,6 +48,15 @@ macro_rules! tag {
 }
 
 
+pub fn escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&#39;")
+}
+
+
 #[test]
 fn test_tag() {
     assert_eq!(&tag!(br), "<br />");
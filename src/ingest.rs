@@ -0,0 +1,152 @@
+use chrono::{DateTime, Duration, TimeZone, UTC};
+use postgres::Connection;
+use postgres::error::Error;
+use uuid::Uuid;
+
+use db::{self, Visibility};
+use e2e;
+use email;
+use mail::{self, Headers};
+use sanitize;
+use session;
+use subject;
+use zone;
+
+
+/// What happens when a note comes from an address we haven't seen before.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Newcomer {
+    /// welcome them by email, and keep their notes hidden until they follow the confirmation
+    /// link in it, in case someone else put their address on the note
+    Confirm,
+    /// welcome them by email: they've already shown the address is theirs
+    Welcome,
+    /// no email at all (like for imports), so there's nothing to confirm with either
+    Quiet,
+}
+
+
+/// A note on its way in, however it arrived.
+pub struct Note {
+    pub sender: String,
+    pub subject: String,
+    pub html: String,
+    /// plain text version, for finding a topic when there's no subject
+    pub text: String,
+    pub headers: Headers,
+}
+
+
+/// How far in the future a Date header can be before we stop believing it (clocks drift).
+const MAX_CLOCK_SKEW_MINUTES: i64 = 10;
+
+/// How long a note can plausibly sit in an outbox before it's delivered.
+const MAX_DELIVERY_DAYS: i64 = 30;
+
+
+/// When a freshly-received note was written, if its headers say so believably.
+///
+/// Prefers the `Date` header, then the time our mail provider got the message (`delivered`).
+/// Dates from the future or from long ago are more likely broken clocks than late mail.
+pub fn authored(headers: &Headers, delivered: Option<DateTime<UTC>>, now: &DateTime<UTC>) -> Option<DateTime<UTC>> {
+    let plausible = |t: &DateTime<UTC>| *t <= now.clone() + Duration::minutes(MAX_CLOCK_SKEW_MINUTES)
+        && *t >= now.clone() - Duration::days(MAX_DELIVERY_DAYS);
+    mail::date(headers)
+        .map(|d| d.with_timezone(&UTC))
+        .into_iter()
+        .chain(delivered)
+        .find(|t| plausible(t))
+}
+
+
+/// File a note under its author and topic, creating either if they're new, and return the
+/// topic's key.
+///
+/// The note is dated when it was `authored`, if known, or else when it arrived, and sealed,
+/// set to fade or made private if its subject says so. Notes for end-to-end encrypted topics
+/// are stored sealed to the topic's public key. Brand new authors are welcomed, and maybe
+/// asked to confirm their address, as `newcomer` says.
+pub fn ingest(conn: &Connection, note: &Note, authored: Option<&DateTime<UTC>>, newcomer: Newcomer) -> Result<Uuid, Error> {
+    let added = try!(db::add_author(conn, &note.sender, newcomer != Newcomer::Confirm));
+    if added {
+        // a first guess at where they are, until they tell us with a command
+        if let Some(zone) = mail::date(&note.headers).and_then(|d| zone::from_offset(d.offset())) {
+            try!(db::set_timezone(conn, &note.sender, &zone));
+        }
+    }
+
+    let (subject, private) = subject::private_tag(&note.subject);
+    let (rest, suffixes) = subject::suffixes(&subject);
+    let mut topic = subject::topic_name(&rest);
+    if topic.is_empty() {
+        let rule = try!(db::empty_subject_rule(conn, &note.sender));
+        let zone = try!(db::author_timezone(conn, &note.sender));
+        topic = subject::topic_for_empty(rule, &note.text, authored.unwrap_or(&UTC::now()), &zone);
+    }
+
+    let (topic_id, topic_key) = try!(db::add_topic(conn, &note.sender, &topic));
+    if private {
+        try!(db::set_topic_visibility(conn, &note.sender, &topic, Visibility::Private));
+    }
+    let mut body = sanitize::clean(&note.html);
+    if let Some(public_key) = try!(db::topic_public_key(conn, &topic_id)) {
+        body = e2e::seal(&public_key, &body);
+    }
+    let post_id = try!(db::add_post(conn, &topic_id, &body, authored));
+    if let Some(message_id) = mail::header(&note.headers, "Message-Id") {
+        try!(db::set_message_id(conn, &post_id, message_id));
+    }
+
+    let mut opens = UTC::now();
+    if let Some(schedule) = suffixes.schedule {
+        let zone = try!(db::author_timezone(conn, &note.sender));
+        let reveal_at = zone.from_local_datetime(&schedule.at)
+            .earliest()
+            .map(|t| t.with_timezone(&UTC))
+            .unwrap_or(DateTime::from_utc(schedule.at, UTC));
+        if reveal_at > UTC::now() {
+            try!(db::seal_post(conn, &post_id, &reveal_at, schedule.letter));
+            opens = reveal_at;
+        }
+    }
+    // a sealed note's time starts when it opens
+    if let Some(fades_at) = suffixes.fade.and_then(|lifetime| opens.checked_add(lifetime)) {
+        try!(db::fade_post(conn, &post_id, &fades_at));
+    }
+
+    // if it's a new user, send a welcome email
+    if added && newcomer != Newcomer::Quiet {
+        // grab the user key for their special link
+        let user_key = try!(db::author_key(conn, &note.sender))
+            .unwrap();  // guarded by the user check / creation
+        let token = if newcomer == Newcomer::Confirm {
+            let token = session::new_token();
+            try!(db::set_confirm_token(conn, &note.sender, &session::hash_token(&token)));
+            Some(token)
+        } else {
+            None
+        };
+        let message_id = mail::header(&note.headers, "Message-Id");
+        email::welcome(&::SETTINGS, &note.sender, &topic, &topic_key, &user_key, token.as_ref().map(|t| &t[..]), message_id);
+    }
+
+    Ok(topic_key)
+}
+
+
+#[test]
+fn test_authored() {
+    use chrono::TimeZone;
+    let now = UTC.ymd(2016, 10, 18).and_hms(21, 30, 0);
+    let dated = |date: &str| vec![("Date".to_string(), date.to_string())];
+
+    let offline = dated("Sun, 16 Oct 2016 09:00:00 -0400");
+    assert_eq!(authored(&offline, None, &now), Some(UTC.ymd(2016, 10, 16).and_hms(13, 0, 0)));
+
+    let delivered = Some(UTC.ymd(2016, 10, 18).and_hms(21, 29, 0));
+    let future = dated("Wed, 18 Oct 2017 09:00:00 +0000");
+    assert_eq!(authored(&future, delivered.clone(), &now), delivered);
+    let ancient = dated("Thu, 01 Jan 1970 00:00:00 +0000");
+    assert_eq!(authored(&ancient, None, &now), None);
+    assert_eq!(authored(&vec![], None, &now), None);
+}
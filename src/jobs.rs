@@ -0,0 +1,77 @@
+//! Chores that run in the background while the site is up.
+
+use std::thread;
+use std::time::Duration;
+
+use postgres::Connection;
+use postgres::error::Error;
+
+use db::{self, PostgresPool};
+use email;
+
+
+const INTERVAL_SECONDS: u64 = 60;
+
+
+/// Run the chores every minute, forever, on their own thread.
+pub fn start(pool: PostgresPool) {
+    thread::spawn(move || loop {
+        match pool.get() {
+            Ok(conn) => if let Err(e) = run(&conn) {
+                println!("background jobs failed: {}", e);
+            },
+            Err(e) => println!("background jobs couldn't get a connection: {}", e),
+        }
+        thread::sleep(Duration::from_secs(INTERVAL_SECONDS));
+    });
+}
+
+
+fn run(conn: &Connection) -> Result<(), Error> {
+    try!(warn_fading(conn));
+    let faded = try!(db::sweep_faded(conn));
+    if faded > 0 {
+        println!("{} notes faded away", faded);
+    }
+
+    let (authors, topics, posts) = try!(db::purge_trash(conn, ::SETTINGS.trash_days));
+    if authors + topics + posts > 0 {
+        println!("emptied the trash of {} authors, {} topics and {} notes", authors, topics, posts);
+    }
+
+    try!(db::clean_sessions(conn));
+    let unconfirmed = try!(db::clean_pending_notes(conn));
+    if unconfirmed > 0 {
+        println!("forgot {} quarantined notes that were never confirmed", unconfirmed);
+    }
+    let unconfirmed = try!(db::purge_unconfirmed_authors(conn));
+    if unconfirmed > 0 {
+        println!("forgot {} new authors who never confirmed their address", unconfirmed);
+    }
+
+    for letter in try!(db::due_letters(conn)) {
+        let zone = try!(db::author_timezone(conn, &letter.author));
+        if email::letter(&::SETTINGS, &letter, &zone) {
+            try!(db::letter_sent(conn, &letter.post.id));
+        }
+    }
+    Ok(())
+}
+
+/// Let authors who asked know about notes fading in the next day, one email each.
+fn warn_fading(conn: &Connection) -> Result<(), Error> {
+    let mut fading = try!(db::fading_soon(conn)).into_iter().peekable();
+    while let Some(first) = fading.next() {
+        let mut posts = vec![first];
+        while fading.peek().map_or(false, |next| next.author == posts[0].author) {
+            posts.push(fading.next().unwrap());
+        }
+        let zone = try!(db::author_timezone(conn, &posts[0].author));
+        if email::fading(&::SETTINGS, &posts[0].author, &posts, &zone) {
+            for post in &posts {
+                try!(db::fade_warned(conn, &post.post));
+            }
+        }
+    }
+    Ok(())
+}
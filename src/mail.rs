@@ -0,0 +1,371 @@
+use std::ascii::AsciiExt;
+
+use chrono::{DateTime, FixedOffset, UTC};
+use rustc_serialize::base64::{self, FromBase64, ToBase64};
+use rustc_serialize::json::Json;
+
+use html;
+
+
+pub type Headers = Vec<(String, String)>;
+
+
+/// The first header called `name` (ignoring case).
+pub fn header<'a>(headers: &'a Headers, name: &str) -> Option<&'a str> {
+    headers
+        .iter()
+        .find(|&&(ref n, _)| n.eq_ignore_ascii_case(name))
+        .map(|&(_, ref value)| &value[..])
+}
+
+/// Mailgun posts the message headers as a json list of `[name, value]` pairs.
+pub fn headers_from_json(raw: &str) -> Headers {
+    match Json::from_str(raw) {
+        Ok(Json::Array(pairs)) => pairs
+            .iter()
+            .filter_map(|pair| match *pair {
+                Json::Array(ref nv) if nv.len() == 2 =>
+                    match (nv[0].as_string(), nv[1].as_string()) {
+                        (Some(n), Some(v)) => Some((n.to_string(), v.to_string())),
+                        _ => None,
+                    },
+                _ => None,
+            })
+            .collect(),
+        _ => vec![],
+    }
+}
+
+pub fn date(headers: &Headers) -> Option<DateTime<FixedOffset>> {
+    header(headers, "Date")
+        .and_then(|d| DateTime::parse_from_rfc2822(d.trim()).ok())
+}
+
+/// Just the address from something like `Phil <phil@example.com>`.
+pub fn address(from: &str) -> String {
+    match (from.rfind('<'), from.rfind('>')) {
+        (Some(start), Some(end)) if start < end => from[start + 1..end].trim().to_string(),
+        _ => from.trim().to_string(),
+    }
+}
+
+
+/// A parsed RFC 822 message, as found in an mbox or Maildir.
+pub struct Message {
+    pub headers: Headers,
+    body: Vec<u8>,
+}
+
+impl Message {
+    pub fn parse(raw: &[u8]) -> Message {
+        let (head, body) = split_head(raw);
+        let mut headers: Headers = vec![];
+        for line in String::from_utf8_lossy(head).lines() {
+            if line.starts_with(' ') || line.starts_with('\t') {
+                if let Some(&mut (_, ref mut value)) = headers.last_mut() {
+                    value.push(' ');
+                    value.push_str(line.trim());
+                }
+            } else if let Some(colon) = line.find(':') {
+                headers.push((line[..colon].trim().to_string(), line[colon + 1..].trim().to_string()));
+            }
+        }
+        Message { headers: headers, body: body.to_vec() }
+    }
+
+    pub fn subject(&self) -> String {
+        header(&self.headers, "Subject").map(decode_words).unwrap_or(String::new())
+    }
+
+    pub fn sender(&self) -> Option<String> {
+        header(&self.headers, "From").map(|from| address(&decode_words(from)))
+    }
+
+    /// The message's HTML, made from its text if it doesn't have any.
+    pub fn html(&self) -> String {
+        match self.part("text/html") {
+            Some(html) => html,
+            None => text_to_html(&self.text()),
+        }
+    }
+
+    pub fn text(&self) -> String {
+        self.part("text/plain").unwrap_or(String::new())
+    }
+
+    fn part(&self, wanted: &str) -> Option<String> {
+        let (mime, params) = content_type(&self.headers);
+        if mime.starts_with("multipart/") {
+            let boundary = match params.iter().find(|&&(ref k, _)| k == "boundary") {
+                Some(&(_, ref b)) => format!("--{}", b),
+                None => return None,
+            };
+            split_parts(&self.body, &boundary)
+                .into_iter()
+                .filter_map(|part| Message::parse(part).part(wanted))
+                .next()
+        } else if mime == wanted {
+            let charset = params.iter()
+                .find(|&&(ref k, _)| k == "charset")
+                .map(|&(_, ref v)| v.to_lowercase())
+                .unwrap_or("utf-8".to_string());
+            let encoding = header(&self.headers, "Content-Transfer-Encoding")
+                .unwrap_or("7bit")
+                .to_lowercase();
+            Some(decode_charset(&decode_transfer(&self.body, &encoding), &charset))
+        } else {
+            None
+        }
+    }
+}
+
+
+fn split_head(raw: &[u8]) -> (&[u8], &[u8]) {
+    for i in 0..raw.len() {
+        if raw[i..].starts_with(b"\r\n\r\n") {
+            return (&raw[..i], &raw[i + 4..]);
+        }
+        if raw[i..].starts_with(b"\n\n") {
+            return (&raw[..i], &raw[i + 2..]);
+        }
+    }
+    (raw, &[])
+}
+
+fn split_parts<'a>(text: &'a [u8], boundary: &str) -> Vec<&'a [u8]> {
+    let boundary = boundary.as_bytes();
+    let mut parts = vec![];
+    let mut start: Option<usize> = None;
+    let mut line_start = 0;
+    while line_start < text.len() {
+        let line_end = text[line_start..].iter().position(|&b| b == b'\n')
+            .map_or(text.len(), |i| line_start + i + 1);
+        let line = &text[line_start..line_end];
+        if line.starts_with(boundary) {
+            if let Some(s) = start {
+                parts.push(&text[s..line_start]);
+            }
+            if line[boundary.len()..].starts_with(b"--") {
+                return parts;
+            }
+            start = Some(line_end);
+        }
+        line_start = line_end;
+    }
+    parts
+}
+
+fn content_type(headers: &Headers) -> (String, Vec<(String, String)>) {
+    let raw = header(headers, "Content-Type").unwrap_or("text/plain");
+    let mut pieces = raw.split(';');
+    let mime = pieces.next().unwrap_or("").trim().to_lowercase();
+    let params = pieces
+        .filter_map(|p| p.find('=').map(|eq| (
+            p[..eq].trim().to_lowercase(),
+            p[eq + 1..].trim().trim_matches('"').to_string())))
+        .collect();
+    (mime, params)
+}
+
+fn decode_transfer(body: &[u8], encoding: &str) -> Vec<u8> {
+    match encoding {
+        "base64" => {
+            let cleaned: Vec<u8> = body.iter().cloned().filter(|&b| b != b'\n' && b != b'\r').collect();
+            cleaned.from_base64().unwrap_or(body.to_vec())
+        },
+        "quoted-printable" => decode_quoted_printable(body, false),
+        _ => body.to_vec(),
+    }
+}
+
+/// `underscores` is for RFC 2047 "Q" words, where `_` means a space.
+fn decode_quoted_printable(body: &[u8], underscores: bool) -> Vec<u8> {
+    let mut out = Vec::with_capacity(body.len());
+    let mut i = 0;
+    while i < body.len() {
+        match body[i] {
+            b'=' if body[i + 1..].starts_with(b"\r\n") => i += 3,
+            b'=' if body[i + 1..].starts_with(b"\n") => i += 2,
+            b'=' if i + 3 <= body.len() => {
+                let hex = String::from_utf8_lossy(&body[i + 1..i + 3]).into_owned();
+                match u8::from_str_radix(&hex, 16) {
+                    Ok(b) => {
+                        out.push(b);
+                        i += 3;
+                    },
+                    Err(_) => {
+                        out.push(b'=');
+                        i += 1;
+                    },
+                }
+            },
+            b'_' if underscores => {
+                out.push(b' ');
+                i += 1;
+            },
+            b => {
+                out.push(b);
+                i += 1;
+            },
+        }
+    }
+    out
+}
+
+fn decode_charset(bytes: &[u8], charset: &str) -> String {
+    match charset {
+        "iso-8859-1" | "latin1" | "windows-1252" | "us-ascii" =>
+            bytes.iter().map(|&b| b as char).collect(),
+        _ => String::from_utf8_lossy(bytes).into_owned(),
+    }
+}
+
+/// Decode RFC 2047 encoded-words like `=?UTF-8?B?8J+MmA==?=` in a header.
+pub fn decode_words(value: &str) -> String {
+    let mut out = String::new();
+    let mut rest = value;
+    let mut last_was_word = false;
+    while let Some(start) = rest.find("=?") {
+        let decoded = rest[start + 2..].splitn(4, '?').collect::<Vec<&str>>();
+        let word = if decoded.len() == 4 && decoded[3].starts_with('=') {
+            let (charset, encoding, text) = (decoded[0].to_lowercase(), decoded[1].to_lowercase(), decoded[2]);
+            let bytes = match &encoding[..] {
+                "b" => text.as_bytes().from_base64().ok(),
+                "q" => Some(decode_quoted_printable(text.as_bytes(), true)),
+                _ => None,
+            };
+            let len = 2 + decoded[0].len() + 1 + decoded[1].len() + 1 + text.len() + 2;
+            bytes.map(|b| (decode_charset(&b, &charset), len))
+        } else {
+            None
+        };
+        match word {
+            Some((text, len)) => {
+                let between = &rest[..start];
+                if !(last_was_word && between.trim().is_empty()) {
+                    out.push_str(between);
+                }
+                out.push_str(&text);
+                rest = &rest[start + len..];
+                last_was_word = true;
+            },
+            None => {
+                out.push_str(&rest[..start + 2]);
+                rest = &rest[start + 2..];
+                last_was_word = false;
+            },
+        }
+    }
+    out.push_str(rest);
+    out
+}
+
+/// Encode a header value as an RFC 2047 word, if it needs it.
+pub fn encode_word(value: &str) -> String {
+    if value.chars().all(|c| c.is_ascii() && !c.is_control()) {
+        value.to_string()
+    } else {
+        format!("=?UTF-8?B?{}?=", value.as_bytes().to_base64(base64::STANDARD))
+    }
+}
+
+/// Plain text as HTML: blank lines make paragraphs, single newlines make line breaks.
+pub fn text_to_html(text: &str) -> String {
+    text.split("\n\n")
+        .map(|para| para.trim())
+        .filter(|para| para.len() > 0)
+        .map(|para| tag!(p: html::escape(para).replace('\n', "<br>")))
+        .collect::<Vec<String>>()
+        .join("")
+}
+
+
+/// Split an mbox file into its messages, undoing `>From ` quoting.
+pub fn split_mbox(raw: &[u8]) -> Vec<Vec<u8>> {
+    let mut messages: Vec<Vec<u8>> = vec![];
+    let mut current: Option<Vec<u8>> = None;
+    for line in raw.split(|&b| b == b'\n') {
+        if line.starts_with(b"From ") {
+            if let Some(message) = current.take() {
+                messages.push(message);
+            }
+            current = Some(vec![]);
+            continue;
+        }
+        if let Some(ref mut message) = current {
+            let quoted = line.iter().position(|&b| b != b'>').map_or(false, |i| i > 0 && line[i..].starts_with(b"From "));
+            message.extend_from_slice(if quoted { &line[1..] } else { line });
+            message.push(b'\n');
+        }
+    }
+    if let Some(message) = current {
+        messages.push(message);
+    }
+    messages
+}
+
+/// One message in mbox form, ready to be appended to an mbox file.
+///
+/// The body is base64'd, so there's nothing in it that could look like a `From ` line.
+pub fn mbox_message(headers: &[(&str, String)], html: &str, date: &DateTime<UTC>) -> String {
+    let mut message = format!("From write-only {}\n", date.format("%a %b %e %H:%M:%S %Y"));
+    for &(name, ref value) in headers {
+        message.push_str(&format!("{}: {}\n", name, value));
+    }
+    message.push_str("MIME-Version: 1.0\n");
+    message.push_str("Content-Type: text/html; charset=utf-8\n");
+    message.push_str("Content-Transfer-Encoding: base64\n\n");
+    let encoded = html.as_bytes().to_base64(base64::Config {
+        char_set: base64::CharacterSet::Standard,
+        newline: base64::Newline::LF,
+        pad: true,
+        line_length: Some(76),
+    });
+    message.push_str(&encoded);
+    message.push_str("\n\n");
+    message
+}
+
+
+#[test]
+fn test_message() {
+    let raw = b"From: =?UTF-8?Q?Ph=C3=AFl?= <phil@example.com>\n\
+                Subject: Re: =?UTF-8?B?Y2Fmw6k=?=\n =?UTF-8?Q?_notes?=\n\
+                Date: Tue, 18 Oct 2016 21:30:12 -0400\n\
+                Content-Type: multipart/alternative; boundary=\"xyz\"\n\
+                \n\
+                preamble\n\
+                --xyz\n\
+                Content-Type: text/plain; charset=utf-8\n\
+                \n\
+                hello\n\
+                --xyz\n\
+                Content-Type: text/html; charset=\"utf-8\"\n\
+                Content-Transfer-Encoding: quoted-printable\n\
+                \n\
+                <p>h=C3=A9llo =\n\
+                there</p>\n\
+                --xyz--\n";
+    let message = Message::parse(raw);
+    assert_eq!(message.sender(), Some("phil@example.com".to_string()));
+    assert_eq!(message.subject(), "Re: café notes");
+    assert_eq!(message.text(), "hello\n");
+    assert_eq!(message.html(), "<p>héllo there</p>\n");
+    assert_eq!(date(&message.headers).unwrap().to_rfc2822(), "Tue, 18 Oct 2016 21:30:12 -0400");
+}
+
+#[test]
+fn test_mbox_round_trip() {
+    use chrono::TimeZone;
+    let when = UTC.ymd(2016, 10, 18).and_hms(21, 30, 12);
+    let mbox = [
+        mbox_message(&[("Subject", encode_word("café"))], "<p>one</p>", &when),
+        mbox_message(&[("Subject", encode_word("plain"))], "<p>From the top</p>", &when),
+    ].concat();
+    let messages = split_mbox(mbox.as_bytes());
+    assert_eq!(messages.len(), 2);
+    let first = Message::parse(&messages[0]);
+    assert_eq!(first.subject(), "café");
+    assert_eq!(first.html(), "<p>one</p>");
+    assert_eq!(Message::parse(&messages[1]).html(), "<p>From the top</p>");
+}
@@ -5,6 +5,7 @@ extern crate lazy_static;
 extern crate route;
 
 extern crate chrono;
+extern crate chrono_tz;
 extern crate crypto;
 extern crate hyper;
 extern crate hyper_rustls;
@@ -15,59 +16,58 @@ extern crate persistent;
 extern crate postgres;
 extern crate r2d2;
 extern crate r2d2_postgres;
+extern crate rand;
+extern crate rustc_serialize;
+extern crate toml;
+extern crate unicode_normalization;
 extern crate url;
 extern crate uuid;
 
-use chrono::{DateTime, UTC, offset};
-use iron::{Iron, Chain, Request, Response, IronResult, Plugin};
+use chrono::{DateTime, Duration, NaiveDateTime, UTC};
+use chrono_tz::Tz;
+use iron::{Iron, Chain, Request, Response, IronResult, IronError, Plugin};
+use iron::method::Method;
 use iron::status::Status;
 use iron::mime::Mime;
 use iron::typemap::Key;
 use logger::Logger;
 use params::{FromValue};
 use persistent::Read as PRead;
-use postgres::rows::Row;
-use r2d2_postgres::{SslMode, PostgresConnectionManager};
+use rustc_serialize::base64::{FromBase64, ToBase64, STANDARD};
+use rustc_serialize::json;
 use url::percent_encoding::{PATH_SEGMENT_ENCODE_SET, utf8_percent_encode};
 use uuid::Uuid;
 
 #[macro_use]
 mod html;
 
+mod admin;
+mod archive;
+mod at_rest;
+mod command;
 mod db;
+mod e2e;
 mod email;
+mod error;
+mod export;
+mod ingest;
+mod jobs;
+mod mail;
 mod migrate;
+mod sanitize;
+mod sender;
+mod session;
+mod settings;
+mod subject;
+mod zone;
 
-type PostgresPool = r2d2::Pool<PostgresConnectionManager>;
+use db::{Post, SearchHit, Topic};
+use error::{AppError, AppResult};
+use settings::Settings;
 
 struct PostgresDB;
 impl Key for PostgresDB {
-    type Value = PostgresPool;
-}
-
-
-#[derive(Debug, PartialEq, Eq)]
-struct Topic {
-    key: Uuid,
-    topic: String,
-    latest: DateTime<UTC>,
-}
-
-impl Topic {
-    fn from_row(row: Row) -> Topic {
-        Topic {
-            key: row.get("key"),
-            topic: row.get("topic"),
-            latest: DateTime::from_utc(row.get("latest"), UTC),
-        }
-    }
-}
-
-
-#[derive(Debug, PartialEq, Eq)]
-struct Post {
-    body: String,
-    timestamp: DateTime<UTC>,
+    type Value = db::PostgresPool;
 }
 
 
@@ -102,12 +102,51 @@ impl std::fmt::Display for Title {
 }
 
 
+#[derive(Debug, PartialEq, Eq)]
+enum Missing {
+    Page,
+    MalformedKey,
+    UnknownKey,
+    Removed,
+    Faded,
+}
+
+
+#[derive(Debug, PartialEq, Eq)]
+enum UnlockProblem {
+    Wrong,
+    TooMany,
+}
+
+
 #[derive(Debug, PartialEq, Eq)]
 enum PageContent {
     Home { author_post_times: Vec<DateTime<UTC>> },
-    Topics { author: String, topics: Vec<Topic> },
-    Posts { author: String, topic: Topic, posts: Vec<Post> },
-    NotFound,
+    /// `compose` is a CSRF token, when the author is signed in and can write here
+    Topics { author: String, author_key: Uuid, zone: Tz, topics: Vec<Topic>, compose: Option<String> },
+    /// `url` is where the page is being read: the topic's own url, or a share link
+    Posts { author: String, zone: Tz, topic: Topic, url: String, visibility: db::Visibility, posts: Vec<Post>, sealed: Vec<DateTime<UTC>>, e2e: Option<db::E2eKey>, compose: Option<String> },
+    Encrypt { topic: Topic, csrf: String },
+    Unlock { topic: String, url: String, problem: Option<UnlockProblem> },
+    Search { author: String, author_key: Uuid, zone: Tz, query: String, results: Vec<(Topic, Vec<SearchHit>)> },
+    ExportSent { author: String, author_key: Uuid },
+    SignIn,
+    SignInSent { email: String },
+    ConfirmSignIn { token: String },
+    SignInExpired,
+    ConfirmNote { token: String, note: db::PendingNote },
+    ConfirmAddress { token: String },
+    AddressExpired,
+    /// `topic_key` is where a confirmed note was filed; commands don't have one
+    NoteConfirmed { kept: bool, topic_key: Option<Uuid> },
+    Account { author: String, author_key: Uuid, zone: Tz, sessions: Vec<db::Session>, current: Uuid, csrf: String },
+    EditPost { topic_key: Uuid, post: Post, csrf: String },
+    History { topic_key: Uuid, zone: Tz, post: Post, revisions: Vec<db::Revision>, e2e: Option<db::E2eKey> },
+    Trash { author_key: Uuid, zone: Tz, trashed: Vec<db::Trashed>, csrf: String },
+    Shares { topic: Topic, zone: Tz, links: Vec<db::ShareLink>, csrf: String },
+    NotFound(Missing),
+    ServerError,
+    Unavailable,
 }
 
 
@@ -121,43 +160,134 @@ where I: IntoIterator<Item=T>,
         .join(""))
 }
 
-fn days_ago(t: &DateTime<UTC>) -> String {
-    let now = UTC::now();
-    match (*t - now).num_days() {
+/// Wrap a human-friendly date in a `<time>` carrying the exact instant.
+fn time_tag(t: &DateTime<UTC>, shown: String) -> String {
+    let instant = t.to_rfc3339();
+    tag!(time[datetime=instant]: shown)
+}
+
+/// How many calendar days ago, on the calendar in `zone`.
+fn days_ago(t: &DateTime<UTC>, zone: &Tz) -> String {
+    let day = t.with_timezone(zone).naive_local().date();
+    let today = UTC::now().with_timezone(zone).naive_local().date();
+    time_tag(t, match (day - today).num_days() {
         n if n > 1 => format!("in {} days", n),
         1          => format!("tomorrow"),
         0          => format!("today"),
         -1         => format!("yesterday"),
         n          => format!("{} days ago", n.abs()),
-    }
+    })
+}
+
+fn show_date(t: &DateTime<UTC>, zone: &Tz) -> String {
+    time_tag(t, t.with_timezone(zone).format("%Y %B %e").to_string())
+}
+
+fn topic_url(key: &Uuid) -> String {
+    format!("/t/{}",
+        utf8_percent_encode(&format!("{}", key), PATH_SEGMENT_ENCODE_SET))
+}
+
+fn share_url(key: &Uuid) -> String {
+    format!("/s/{}", key)
 }
 
 fn link_topic(topic: &Topic) -> String {
-    let link = format!("/t/{}",
-        utf8_percent_encode(&format!("{}", topic.key), PATH_SEGMENT_ENCODE_SET));
+    let link = topic_url(&topic.key);
     let title = format!("Notes on {}", topic.topic);
     tag!(a[href=link][title=title]: topic.topic)
 }
 
-fn link_topic_latest(topic: &Topic) -> String {
-    tag!(p: link_topic(&topic), " ", days_ago(&topic.latest))
+fn link_topic_latest(topic: &Topic, zone: &Tz) -> String {
+    tag!(p: link_topic(&topic), " ", days_ago(&topic.latest, zone))
+}
+
+fn post_url(topic_key: &Uuid, post: &Uuid, action: &str) -> String {
+    format!("{}/{}/{}", topic_url(topic_key), post, action)
+}
+
+/// A one-button form for a signed-in author.
+fn post_button(action: &str, csrf: &str, label: &str) -> String {
+    tag!(form[class="button"][action=action][method="post"]:
+        tag!(input[type="hidden"][name="csrf"][value=csrf]),
+        tag!(button[type="submit"]: label))
+}
+
+/// A post's body, or a placeholder for `e2e.js` to decrypt it into.
+fn show_body(body: &str) -> String {
+    if e2e::is_sealed(body) {
+        tag!(div["data-ciphertext"=body]: tag!(p[class="sealed"]: "🔒 Encrypted"))
+    } else {
+        body.to_string()
+    }
+}
+
+/// The passphrase form and script that decrypt an encrypted topic's notes in the browser.
+fn decrypt_form(key: &db::E2eKey) -> String {
+    let wrapped = key.wrapped_private_key.to_base64(STANDARD);
+    let salt = key.salt.to_base64(STANDARD);
+    let iv = key.iv.to_base64(STANDARD);
+    let iterations = e2e::PBKDF2_ITERATIONS;
+    join!(
+        tag!(form[id="decrypt"][class="search"]["data-wrapped"=wrapped]["data-salt"=salt]["data-iv"=iv]["data-iterations"=iterations]:
+            tag!(input[type="password"][required="required"][placeholder="Passphrase to read these notes"]["aria-label"="Passphrase"]),
+            tag!(button[type="submit"]: "Decrypt"),
+            tag!(span[class="status"]: "")),
+        tag!(script: include_str!("e2e.js")))
+}
+
+/// A post, with ways to edit or delete it and see its history for its signed-in author, who
+/// has a `csrf` token.
+fn show_post(post: &Post, zone: &Tz, topic_key: &Uuid, csrf: Option<&str>) -> String {
+    let id = post.id.to_string();
+    let owner = csrf.is_some();
+    let edited = match post.edited {
+        Some(ref edited) if owner => {
+            let history = post_url(topic_key, &post.id, "history");
+            tag!(a[href=history][title="Earlier versions"]: "edited ", days_ago(edited, zone))
+        },
+        Some(ref edited) => join!("edited ", days_ago(edited, zone)),
+        None => String::new(),
+    };
+    let edit = match csrf {
+        // sealed notes can only be edited by email: their plaintext isn't here to start from
+        Some(csrf) if e2e::is_sealed(&post.body) =>
+            post_button(&post_url(topic_key, &post.id, "delete"), csrf, "Delete"),
+        Some(csrf) => {
+            let link = post_url(topic_key, &post.id, "edit");
+            join!(tag!(a[href=link]: "Edit"), " · ",
+                post_button(&post_url(topic_key, &post.id, "delete"), csrf, "Delete"))
+        },
+        None => String::new(),
+    };
+    tag!(article[id=id]:
+        tag!(h3: show_date(&post.timestamp, zone)),
+        show_body(&post.body),
+        if edited.is_empty() && edit.is_empty() {
+            String::new()
+        } else {
+            tag!(p[class="edited"]: join_with![" · "; edited, edit])
+        })
 }
 
-fn show_post(post: &Post) -> String {
-    tag!(article:
-        tag!(h3: &post.timestamp.format("%Y %B %e")),
-        post.body)
+fn visitor_counter(src: &str) -> String {
+    if SETTINGS.features.visitor_counter {
+        tag!(img[src=src][style="position:absolute;left:-9999em"][alt="visitor counter"]["aria-hidden"="true"])
+    } else {
+        String::new()
+    }
 }
 
 fn home_page(author_post_times: Vec<DateTime<UTC>>) -> (Title, Status, String) {
     let title = String::from("Write like nobody's reading on write-only.space");
+    let mailto = format!("mailto:{}", SETTINGS.inbound_address);
     (Title::Replace(title), Status::Ok,
         tag!(main:
             tag!(h1: "Write like nobody's reading"),
             tag!(p:
                 "write-only is a tiny island in cyberspace where no one visits. You can write notes by emailing them to ",
-                tag!(a[href="mailto:note@write-only.space"]:
-                    "note@write-only.space"),
+                tag!(a[href=mailto]:
+                    SETTINGS.inbound_address),
                 " &ndash; no signup required, just email a note to start. ",
                 tag!(strong:
                     "Tip:"),
@@ -166,35 +296,149 @@ fn home_page(author_post_times: Vec<DateTime<UTC>>) -> (Title, Status, String) {
                 "Notes here are ",
                 tag!(strong:
                     "public but unlisted: "),
-                "they won't show up in search engine results, and you need a special link to see them. Anyone with the link can see the the notes."),
+                "they won't show up in search engine results, and you need a special link to see them. Anyone with the link can see the the notes, unless you start a subject with [private] to keep that topic to yourself."),
+            tag!(p:
+                "Already writing here? ",
+                tag!(a[href="/sign-in"]: "Sign in"),
+                " to manage your notes."),
             tag!(h2: "Recent activity:"),
             ul(author_post_times, |when|
-                tag!(p: "posted ", days_ago(when))),
-            tag!(img[src="https://counter.cv2.ca/count.gif"][style="position:absolute;left:-9999em"][alt="visitor counter"]["aria-hidden"="true"])))
+                tag!(p: "posted ", days_ago(when, &Tz::UTC))),
+            visitor_counter("https://counter.cv2.ca/count.gif")))
 }
 
-fn topics_page(author: String, topics: Vec<Topic>) -> (Title, Status, String) {
+fn search_form(author_key: &Uuid, query: &str) -> String {
+    let action = format!("/{}/search", author_key);
+    let value = html::escape(query);
+    tag!(form[class="search"][action=action][method="get"]:
+        tag!(input[type="search"][name="q"][value=value][placeholder="Search these notes"]["aria-label"="Search these notes"]),
+        tag!(button[type="submit"]: "Search"))
+}
+
+fn show_hit(topic_key: &Uuid, hit: &SearchHit, zone: &Tz) -> String {
+    let link = format!("{}#{}", topic_url(topic_key), hit.post);
+    tag!(p:
+        tag!(a[href=link]: show_date(&hit.timestamp, zone)),
+        tag!(br),
+        "…", hit.snippet, "…")
+}
+
+/// Ways to download an author's notes, and to have the link emailed to them if they're the
+/// one signed in (`csrf`).
+fn export_links(author: &str, author_key: &Uuid, csrf: Option<&str>) -> String {
+    let download = format!("/{}/export.zip", author_key);
+    let mbox = format!("/{}/export.mbox", author_key);
+    let email = format!("/{}/export/email", author_key);
+    let send = csrf.map_or(String::new(), |csrf| post_button(&email, csrf, &format!("Email the download link to {}", author)));
+    join!(
+        tag!(h2: "Keep a copy"),
+        tag!(p:
+            tag!(a[href=download][download="write-only.zip"]: "Download every note"),
+            " as a zip of Markdown files, one folder per topic, or ",
+            tag!(a[href=mbox][download="write-only.mbox"]: "as an mbox"),
+            " to open in your mail client."),
+        send)
+}
+
+/// Write a note from the browser, into `topic` or a new one.
+fn compose_form(csrf: &str, topic: Option<&str>) -> String {
+    let (heading, topic_input) = match topic {
+        Some(topic) => {
+            let topic = html::escape(topic);
+            ("Add a note", tag!(input[type="hidden"][name="topic"][value=topic]))
+        },
+        None => ("Write a new note",
+            tag!(input[type="text"][name="topic"][placeholder="Topic, like an email's subject"]["aria-label"="Topic"])),
+    };
+    tag!(form[class="compose"][action="/compose"][method="post"]:
+        tag!(h2: heading),
+        tag!(input[type="hidden"][name="csrf"][value=csrf]),
+        topic_input,
+        tag!(textarea[name="body"][rows="8"][required="required"]["aria-label"="Note"]: ""),
+        tag!(button[type="submit"]: "Post"))
+}
+
+fn topics_page(author: String, author_key: Uuid, zone: Tz, topics: Vec<Topic>, compose: Option<String>) -> (Title, Status, String) {
+    let csrf = compose;
+    let compose = csrf.as_ref().map_or(String::new(), |csrf| compose_form(csrf, None));
     if topics.len() > 0 {
         (Title::Add((&author).to_string()), Status::Ok, join!(
             tag!(h1: "Notes by ", &author),
             tag!(p: "write-only is a tiny island in cyberspace where no one visits. It's intended for writing freely, without the pressure of an audience or Internet Points. It's not for sharing."),
             tag!(p: "So avoid linking to notes, especially on aggregation sites like reddit. If you're not sure, contact ", &author, " first and ask."),
             tag!(main:
+                search_form(&author_key, ""),
+                compose,
                 tag!(h2: "Topics"),
-                ul(topics, link_topic_latest))))
+                ul(topics, |topic| link_topic_latest(topic, &zone)),
+                export_links(&author, &author_key, csrf.as_ref().map(|c| &c[..])))))
     } else {
+        let mailto = format!("mailto:{}", SETTINGS.inbound_address);
         (Title::Nothing, Status::NotFound,
             tag!(main:
                 tag!(h2: "No notes by ", author),
                 tag!(p: "Create notes by emailing ",
-                    tag!(a[href="mailto:note@write-only.space"]: "note@write-only.space"),
+                    tag!(a[href=mailto]: SETTINGS.inbound_address),
                     " if ", author, " is your email address."),
-                tag!(p: "Notes are grouped into threads by the email subject.")))
+                tag!(p: "Notes are grouped into threads by the email subject."),
+                compose))
     }
 }
 
-fn posts_page(author: String, topic: Topic, posts: Vec<Post>) -> (Title, Status, String) {
+/// A hint that there's more to come, without giving away what.
+fn sealed_notes(sealed: &[DateTime<UTC>], zone: &Tz) -> String {
+    let next = match sealed.first() {
+        Some(next) => next,
+        None => return String::new(),
+    };
+    let count = if sealed.len() == 1 {
+        "A sealed note opens ".to_string()
+    } else {
+        format!("{} sealed notes; the next opens ", sealed.len())
+    };
+    tag!(p[class="sealed"]: count, days_ago(next, zone), ".")
+}
+
+fn posts_page(author: String, zone: Tz, topic: Topic, url: String, visibility: db::Visibility, posts: Vec<Post>, sealed: Vec<DateTime<UTC>>, e2e: Option<db::E2eKey>, compose: Option<String>) -> (Title, Status, String) {
+    let private = match visibility {
+        db::Visibility::Private =>
+            tag!(p[class="sealed"]: "This topic is private: only you can see it, while you're signed in. Email \"!unlisted ",
+                html::escape(&topic.topic), "\" to let anyone with its link read it."),
+        db::Visibility::Protected =>
+            tag!(p[class="sealed"]: "This topic is protected: readers need its passphrase as well as its link."),
+        db::Visibility::Unlisted => String::new(),
+    };
+    let encrypted = e2e.as_ref().map_or(String::new(), |key| join!(
+        tag!(p[class="sealed"]: "These notes are encrypted end-to-end: only the topic's passphrase can open them, in your browser."),
+        decrypt_form(key)));
+    let csrf = compose;
+    let compose = csrf.as_ref().map_or(String::new(), |csrf| compose_form(csrf, Some(&topic.topic)));
     if posts.len() > 0 {
+        let mbox = format!("{}/mbox", url);
+        let manage = csrf.as_ref().map_or(String::new(), |csrf| {
+            let shares = format!("{}/shares", topic_url(&topic.key));
+            let passphrase = format!("{}/passphrase", topic_url(&topic.key));
+            let encrypt = if e2e.is_none() {
+                let link = format!("{}/encrypt", topic_url(&topic.key));
+                join!(tag!(a[href=link]: "Encrypt end-to-end"), " · ")
+            } else {
+                String::new()
+            };
+            join!(
+                tag!(p:
+                    tag!(a[href=shares]: "Share links"), " · ",
+                    encrypt,
+                    post_button(&format!("{}/delete", topic_url(&topic.key)), csrf, "Move this topic to the trash")),
+                tag!(form[class="search"][action=passphrase][method="post"]:
+                    tag!(input[type="hidden"][name="csrf"][value=csrf]),
+                    tag!(input[type="password"][name="passphrase"][required="required"][placeholder="A passphrase for readers"]["aria-label"="Passphrase"]),
+                    tag!(button[type="submit"]: if visibility == db::Visibility::Protected { "Change passphrase" } else { "Require a passphrase" })),
+                if visibility == db::Visibility::Protected {
+                    tag!(p: post_button(&passphrase, csrf, "Remove the passphrase"))
+                } else {
+                    String::new()
+                })
+        });
         (Title::Add((&topic.topic).to_string()), Status::Ok, join!(
             tag!(p[class="heads-up"]:
                 tag!(strong: "Heads up:"),
@@ -202,40 +446,400 @@ fn posts_page(author: String, topic: Topic, posts: Vec<Post>) -> (Title, Status,
             tag!(main:
                 tag!(h1: topic.topic),
                 tag!(h2[class="subtitle"]: " by ", &author),
-                ul(posts, &show_post))))
+                private,
+                encrypted,
+                sealed_notes(&sealed, &zone),
+                ul(posts, |post| show_post(post, &zone, &topic.key, csrf.as_ref().map(|c| &c[..]))),
+                compose,
+                tag!(p: tag!(a[href=mbox][download="topic.mbox"]: "Download this topic as an mbox")),
+                manage)))
     } else {
-        let mailto = format!("mailto:note@write-only.space?subject={}",
+        let mailto = format!("mailto:{}?subject={}",
+            SETTINGS.inbound_address,
             utf8_percent_encode(&topic.topic, PATH_SEGMENT_ENCODE_SET));
         (Title::Nothing, Status::NotFound,
             tag!(main:
                 tag!(h2: "No notes on ", &topic.topic, " by ", &author),
+                sealed_notes(&sealed, &zone),
                 tag!(p: tag!(strong: "Are you ", &author, "?")),
                 tag!(p: "Post notes here by emailing them to ",
-                    tag!(a[href=mailto]: "note@write-only.space"),
-                    " with ", tag!(strong: &topic.topic),  " as the subject line.")))
+                    tag!(a[href=mailto]: SETTINGS.inbound_address),
+                    " with ", tag!(strong: &topic.topic),  " as the subject line."),
+                compose))
     }
 }
 
-fn not_found() -> (Title, Status, String) {
+fn search_page(author: String, author_key: Uuid, zone: Tz, query: String, results: Vec<(Topic, Vec<SearchHit>)>) -> (Title, Status, String) {
+    let back = format!("/{}", author_key);
+    let found = if query.trim().is_empty() {
+        String::new()
+    } else if results.is_empty() {
+        tag!(p: "Nothing matched ", tag!(strong: html::escape(&query)), ".")
+    } else {
+        results
+            .iter()
+            .map(|&(ref topic, ref hits)| join!(
+                tag!(h2: link_topic(topic)),
+                ul(hits, |hit| show_hit(&topic.key, hit, &zone))))
+            .collect::<Vec<String>>()
+            .join("")
+    };
+    (Title::Add(format!("Search {}", author)), Status::Ok,
+        tag!(main:
+            tag!(h1: "Search notes by ", tag!(a[href=back]: &author)),
+            search_form(&author_key, &query),
+            found))
+}
+
+fn export_sent_page(author: String, author_key: Uuid) -> (Title, Status, String) {
+    let back = format!("/{}", author_key);
+    (Title::Add(author.clone()), Status::Ok,
+        tag!(main:
+            tag!(h1: "Check your email"),
+            tag!(p: "A link to download every note was sent to ", &author, "."),
+            tag!(p: tag!(a[href=back]: "Back to the notes"))))
+}
+
+fn sign_in_page() -> (Title, Status, String) {
+    (Title::Add("Sign in".to_string()), Status::Ok,
+        tag!(main:
+            tag!(h1: "Sign in"),
+            tag!(p: "There's no password: enter the email address you write from, and we'll send you a link to sign in with."),
+            tag!(form[action="/sign-in"][method="post"]:
+                tag!(input[type="email"][name="email"][required="required"][placeholder="you@example.com"]["aria-label"="Email address"]),
+                tag!(button[type="submit"]: "Email me a link"))))
+}
+
+fn sign_in_sent_page(email: String) -> (Title, Status, String) {
+    (Title::Add("Sign in".to_string()), Status::Ok,
+        tag!(main:
+            tag!(h1: "Check your email"),
+            tag!(p: "If ", html::escape(&email), " has notes here, a sign-in link is on its way. It works once, for the next ", session::LINK_MINUTES, " minutes.")))
+}
+
+fn confirm_sign_in_page(token: String) -> (Title, Status, String) {
+    let action = format!("/sign-in/{}", html::escape(&token));
+    (Title::Add("Sign in".to_string()), Status::Ok,
+        tag!(main:
+            tag!(h1: "Sign in"),
+            tag!(form[action=action][method="post"]:
+                tag!(button[type="submit"]: "Sign in to write-only"))))
+}
+
+fn sign_in_expired_page() -> (Title, Status, String) {
+    (Title::Add("Sign in".to_string()), Status::Gone,
+        tag!(main:
+            tag!(h1: "That link has expired"),
+            tag!(p: "Sign-in links only work once, and only for ", session::LINK_MINUTES, " minutes."),
+            tag!(p: tag!(a[href="/sign-in"]: "Get a new one"))))
+}
+
+fn confirm_address_page(token: String) -> (Title, Status, String) {
+    let action = format!("/welcome/{}", html::escape(&token));
+    (Title::Add("Welcome".to_string()), Status::Ok,
+        tag!(main:
+            tag!(h1: "Welcome to write-only"),
+            tag!(p: "Confirm that this is your address, and your notes will show up at their links."),
+            tag!(form[action=action][method="post"]:
+                tag!(button[type="submit"]: "Confirm my address"))))
+}
+
+fn address_expired_page() -> (Title, Status, String) {
+    let mailto = format!("mailto:{}", SETTINGS.inbound_address);
+    (Title::Add("Welcome".to_string()), Status::Gone,
+        tag!(main:
+            tag!(h1: "That link has expired"),
+            tag!(p: "Confirmation links work once, within ", session::CONFIRM_DAYS,
+                " days of your first note. After that, unconfirmed notes are deleted."),
+            tag!(p: "To start again, just ", tag!(a[href=mailto]: "email a new note"), ".")))
+}
+
+fn confirm_note_page(token: String, note: db::PendingNote) -> (Title, Status, String) {
+    let action = format!("/confirm/{}", html::escape(&token));
+    (Title::Add("Was this you?".to_string()), Status::Ok,
+        tag!(main:
+            tag!(h1: "Was this you?"),
+            tag!(p: "This note says it's from ", tag!(strong: html::escape(&note.sender)),
+                ", but its email couldn't prove it, so it's waiting until you say it's yours."),
+            tag!(h2: html::escape(&note.subject)),
+            tag!(blockquote: sanitize::clean(&note.html)),
+            tag!(form[action=action][method="post"]:
+                tag!(button[type="submit"]: "It's mine, add it"), " ",
+                tag!(button[type="submit"][name="discard"][value="1"]: "I didn't send this"))))
+}
+
+fn note_confirmed_page(kept: bool, topic_key: Option<Uuid>) -> (Title, Status, String) {
+    let message = match (kept, topic_key) {
+        (false, _) => tag!(p: "It's gone. If notes you didn't write keep turning up, someone may be using your address."),
+        (true, Some(key)) => {
+            let link = topic_url(&key);
+            tag!(p: "It's been added to ", tag!(a[href=link]: "its topic"), ".")
+        },
+        (true, None) => tag!(p: "Done. The answer is on its way to your inbox."),
+    };
+    (Title::Add("Thanks".to_string()), Status::Ok,
+        tag!(main:
+            tag!(h1: "Thanks"),
+            message))
+}
+
+fn show_session(session: &db::Session, current: &Uuid, zone: &Tz, csrf: &str) -> String {
+    let revoke = format!("/account/sessions/{}/revoke", session.id);
+    let device = session.user_agent.as_ref().map_or("Unknown browser".to_string(), |ua| html::escape(ua));
+    let this = if session.id == *current { tag!(strong: " (this browser)") } else { String::new() };
+    tag!(p:
+        device, this,
+        tag!(br),
+        "Signed in ", show_date(&session.created, zone), ", last used ", days_ago(&session.last_seen, zone),
+        post_button(&revoke, csrf, "Sign out"))
+}
+
+fn edit_post_page(topic_key: Uuid, post: Post, csrf: String) -> (Title, Status, String) {
+    let action = post_url(&topic_key, &post.id, "edit");
+    let back = format!("{}#{}", topic_url(&topic_key), post.id);
+    let text = html::escape(&export::to_markdown(&post.body));
+    (Title::Add("Edit a note".to_string()), Status::Ok,
+        tag!(main:
+            tag!(h1: "Edit a note"),
+            tag!(form[class="compose"][action=action][method="post"]:
+                tag!(input[type="hidden"][name="csrf"][value=csrf]),
+                tag!(textarea[name="body"][rows="12"][required="required"]["aria-label"="Note"]: text),
+                tag!(button[type="submit"]: "Save")),
+            tag!(p: "Format with Markdown: **bold**, _slanted_, [a link](https://example.com). ",
+                "The current version will be kept in the note's history."),
+            tag!(p: tag!(a[href=back]: "Back to the topic"))))
+}
+
+fn history_page(topic_key: Uuid, zone: Tz, post: Post, revisions: Vec<db::Revision>, e2e: Option<db::E2eKey>) -> (Title, Status, String) {
+    let back = format!("{}#{}", topic_url(&topic_key), post.id);
+    (Title::Add("History".to_string()), Status::Ok,
+        tag!(main:
+            tag!(h1: "History of a note from ", show_date(&post.timestamp, &zone)),
+            tag!(p: "Only you can see this page. ", tag!(a[href=back]: "Back to the topic")),
+            e2e.as_ref().map_or(String::new(), decrypt_form),
+            tag!(h2: "Now"),
+            tag!(article: show_body(&post.body)),
+            ul(revisions, |revision| join!(
+                tag!(h2: "Until ", show_date(&revision.replaced, &zone)),
+                tag!(article: show_body(&revision.body))))))
+}
+
+fn show_trashed(trashed: &db::Trashed, zone: &Tz, csrf: &str, days: u32) -> String {
+    let gone = trashed.deleted + Duration::days(days as i64);
+    let (what, body, restore) = match trashed.post {
+        Some(ref post) => (
+            join!("A note from ", show_date(&post.timestamp, zone), " in ", link_topic(&trashed.topic)),
+            tag!(blockquote: show_body(&post.body)),
+            post_url(&trashed.topic.key, &post.id, "restore")),
+        None => (
+            join!("The topic ", tag!(strong: &trashed.topic.topic), " and its notes"),
+            String::new(),
+            format!("{}/restore", topic_url(&trashed.topic.key))),
+    };
+    join!(
+        tag!(p:
+            what, " · ",
+            "Deleted ", days_ago(&trashed.deleted, zone), ", gone for good ", show_date(&gone, zone), ".",
+            post_button(&restore, csrf, "Restore")),
+        body)
+}
+
+fn trash_page(author_key: Uuid, zone: Tz, trashed: Vec<db::Trashed>, csrf: String, days: u32) -> (Title, Status, String) {
+    let notes = format!("/{}", author_key);
+    (Title::Add("Trash".to_string()), Status::Ok,
+        tag!(main:
+            tag!(h1: "Trash"),
+            tag!(p: "Deleted notes and topics wait here for ", days, " days, then they're gone for good. ",
+                tag!(a[href=notes]: "Back to your notes")),
+            if trashed.is_empty() {
+                tag!(p: "The trash is empty.")
+            } else {
+                ul(trashed, |t| show_trashed(t, &zone, &csrf, days))
+            }))
+}
+
+fn show_share_link(link: &db::ShareLink, topic_key: &Uuid, zone: &Tz, csrf: &str) -> String {
+    let url = format!("{}{}", SETTINGS.base_url, share_url(&link.key));
+    let used = link.last_used.as_ref().map_or("never used".to_string(), |t| join!("last used ", days_ago(t, zone)));
+    let state = match (link.revoked.as_ref(), link.expires.as_ref()) {
+        (Some(revoked), _) => join!("revoked ", days_ago(revoked, zone)),
+        (None, Some(expires)) if *expires <= UTC::now() => join!("expired ", days_ago(expires, zone)),
+        (None, expires) => {
+            let revoke = format!("{}/shares/{}/revoke", topic_url(topic_key), link.key);
+            join!(
+                expires.map_or(String::new(), |t| join!("expires ", show_date(t, zone), " · ")),
+                post_button(&revoke, csrf, "Revoke"))
+        },
+    };
+    tag!(p:
+        tag!(strong: html::escape(&link.label)), tag!(br),
+        tag!(code: url), tag!(br),
+        "Made ", show_date(&link.created, zone), ", ", used, " · ", state)
+}
+
+fn shares_page(topic: Topic, zone: Tz, links: Vec<db::ShareLink>, csrf: String) -> (Title, Status, String) {
+    let action = format!("{}/shares", topic_url(&topic.key));
+    let back = topic_url(&topic.key);
+    (Title::Add("Share links".to_string()), Status::Ok,
+        tag!(main:
+            tag!(h1: "Share links for ", tag!(a[href=back]: &topic.topic)),
+            tag!(p: "Give each person their own link, so you can take it back from just them, and see which one was used."),
+            if links.is_empty() {
+                tag!(p: "No share links yet.")
+            } else {
+                ul(links, |link| show_share_link(link, &topic.key, &zone, &csrf))
+            },
+            tag!(form[class="compose"][action=action][method="post"]:
+                tag!(h2: "Make a new link"),
+                tag!(input[type="hidden"][name="csrf"][value=csrf]),
+                tag!(input[type="text"][name="label"][required="required"][placeholder="Who it's for, like “for my therapist”"]["aria-label"="Label"]),
+                tag!(input[type="number"][name="days"][min="1"][placeholder="Expires after this many days (optional)"]["aria-label"="Expires after days"]),
+                tag!(button[type="submit"]: "Make link"))))
+}
+
+fn encrypt_page(topic: Topic, csrf: String) -> (Title, Status, String) {
+    let action = format!("{}/encrypt", topic_url(&topic.key));
+    let back = topic_url(&topic.key);
+    let iterations = e2e::PBKDF2_ITERATIONS;
+    (Title::Add("Encrypt end-to-end".to_string()), Status::Ok,
+        tag!(main:
+            tag!(h1: "Encrypt ", tag!(a[href=back]: &topic.topic), " end-to-end"),
+            tag!(p: "Your browser will make a key for this topic and lock it with a passphrase that never leaves it. ",
+                "Every note here, and every note you add, is then stored so that only that passphrase can open it, ",
+                "in the browser of whoever knows it. We can't read them, and we can't get them back if you forget it."),
+            tag!(p: "Some things stay in the clear: the topic's name, that it's yours, when each note was written, ",
+                "and roughly how long each one is. Notes are still seen in passing as they arrive, and search ",
+                "can't look inside them. Edit them by replying to your own email, not here. This can't be undone."),
+            tag!(form[id="encrypt-setup"][class="compose"][action=action][method="post"]["data-iterations"=iterations]:
+                tag!(input[type="hidden"][name="csrf"][value=csrf]),
+                tag!(input[type="hidden"][name="public_key"]),
+                tag!(input[type="hidden"][name="wrapped_key"]),
+                tag!(input[type="hidden"][name="salt"]),
+                tag!(input[type="hidden"][name="iv"]),
+                tag!(input[type="password"][id="passphrase"][required="required"][placeholder="Passphrase"]["aria-label"="Passphrase"]),
+                tag!(input[type="password"][id="passphrase-again"][required="required"][placeholder="The same passphrase again"]["aria-label"="Passphrase again"]),
+                tag!(button[type="submit"]: "Encrypt this topic"),
+                tag!(p[class="status"]: "")),
+            tag!(script: include_str!("e2e.js"))))
+}
+
+fn unlock_page(topic: String, url: String, problem: Option<UnlockProblem>) -> (Title, Status, String) {
+    let action = format!("{}/unlock", url);
+    let (status, message) = match problem {
+        None => (Status::Ok, String::new()),
+        Some(UnlockProblem::Wrong) => (Status::Forbidden, tag!(p: tag!(strong: "That's not the passphrase."))),
+        Some(UnlockProblem::TooMany) => (Status::TooManyRequests,
+            tag!(p: tag!(strong: "Too many wrong passphrases."), " Try again in ", session::UNLOCK_FAILURE_MINUTES, " minutes.")),
+    };
+    (Title::Add(topic.clone()), status,
+        tag!(main:
+            tag!(h1: topic),
+            tag!(p: "The author has protected these notes with a passphrase."),
+            message,
+            tag!(form[class="search"][action=action][method="post"]:
+                tag!(input[type="password"][name="passphrase"][required="required"][autofocus="autofocus"]["aria-label"="Passphrase"]),
+                tag!(button[type="submit"]: "Unlock"))))
+}
+
+fn account_page(author: String, author_key: Uuid, zone: Tz, sessions: Vec<db::Session>, current: Uuid, csrf: String) -> (Title, Status, String) {
+    let notes = format!("/{}", author_key);
+    (Title::Add(author.clone()), Status::Ok,
+        tag!(main:
+            tag!(h1: "Signed in as ", &author),
+            tag!(p: tag!(a[href=notes]: "Your notes"), " · ", tag!(a[href="/trash"]: "Trash")),
+            tag!(h2: "Where you're signed in"),
+            ul(sessions, |session| show_session(session, &current, &zone, &csrf)),
+            post_button("/sign-out", &csrf, "Sign out of this browser")))
+}
+
+fn not_found(missing: Missing) -> (Title, Status, String) {
+    let (status, heading, message) = match missing {
+        Missing::Page =>
+            (Status::NotFound, "Nothing here", "nothing at all..."),
+        Missing::MalformedKey =>
+            (Status::NotFound, "Nothing here", "That link looks incomplete. Check that it was copied in full."),
+        Missing::UnknownKey =>
+            (Status::NotFound, "Nothing here", "There are no notes at this link."),
+        Missing::Removed =>
+            (Status::Gone, "Nothing here", "The notes that were here have been removed."),
+        Missing::Faded =>
+            (Status::Gone, "This has faded", "The notes that were here were only meant to last a while, and their time is up."),
+    };
     ( Title::Replace("404".to_string())
-    , Status::NotFound
+    , status
     , tag!(main:
-        tag!(h1: "Nothing here"),
-        tag!(p: "nothing at all..."),
-        tag!(img[src="https://timekeep-server.herokuapp.com/count.gif"][style="position:absolute;left:-9999em"][alt="visitor counter"]["aria-hidden"="true"]))
+        tag!(h1: heading),
+        tag!(p: message),
+        visitor_counter("https://timekeep-server.herokuapp.com/count.gif"))
     )
 }
 
-fn render(page: PageContent) -> IronResult<Response> {
+fn server_error() -> (Title, Status, String) {
+    ( Title::Replace("Oops".to_string())
+    , Status::InternalServerError
+    , tag!(main:
+        tag!(h1: "Something broke"),
+        tag!(p: "Sorry, something went wrong on our end. Your notes are safe; please try again later."))
+    )
+}
+
+fn unavailable() -> (Title, Status, String) {
+    ( Title::Replace("Busy".to_string())
+    , Status::ServiceUnavailable
+    , tag!(main:
+        tag!(h1: "Busy right now"),
+        tag!(p: "write-only is a little overwhelmed at the moment. Please try again in a few seconds."))
+    )
+}
+
+fn render(page: PageContent) -> Response {
     let (title, status, content) = match page {
         PageContent::Home { author_post_times } =>
             home_page(author_post_times),
-        PageContent::Topics { author, topics } =>
-            topics_page(author, topics),
-        PageContent::Posts { author, topic, posts } =>
-            posts_page(author, topic, posts),
-        PageContent::NotFound =>
-            not_found(),
+        PageContent::Topics { author, author_key, zone, topics, compose } =>
+            topics_page(author, author_key, zone, topics, compose),
+        PageContent::Posts { author, zone, topic, url, visibility, posts, sealed, e2e, compose } =>
+            posts_page(author, zone, topic, url, visibility, posts, sealed, e2e, compose),
+        PageContent::Encrypt { topic, csrf } =>
+            encrypt_page(topic, csrf),
+        PageContent::Unlock { topic, url, problem } =>
+            unlock_page(topic, url, problem),
+        PageContent::Search { author, author_key, zone, query, results } =>
+            search_page(author, author_key, zone, query, results),
+        PageContent::ExportSent { author, author_key } =>
+            export_sent_page(author, author_key),
+        PageContent::SignIn =>
+            sign_in_page(),
+        PageContent::SignInSent { email } =>
+            sign_in_sent_page(email),
+        PageContent::ConfirmSignIn { token } =>
+            confirm_sign_in_page(token),
+        PageContent::SignInExpired =>
+            sign_in_expired_page(),
+        PageContent::ConfirmNote { token, note } =>
+            confirm_note_page(token, note),
+        PageContent::ConfirmAddress { token } =>
+            confirm_address_page(token),
+        PageContent::AddressExpired =>
+            address_expired_page(),
+        PageContent::NoteConfirmed { kept, topic_key } =>
+            note_confirmed_page(kept, topic_key),
+        PageContent::Account { author, author_key, zone, sessions, current, csrf } =>
+            account_page(author, author_key, zone, sessions, current, csrf),
+        PageContent::EditPost { topic_key, post, csrf } =>
+            edit_post_page(topic_key, post, csrf),
+        PageContent::History { topic_key, zone, post, revisions, e2e } =>
+            history_page(topic_key, zone, post, revisions, e2e),
+        PageContent::Trash { author_key, zone, trashed, csrf } =>
+            trash_page(author_key, zone, trashed, csrf, SETTINGS.trash_days),
+        PageContent::Shares { topic, zone, links, csrf } =>
+            shares_page(topic, zone, links, csrf),
+        PageContent::NotFound(missing) =>
+            not_found(missing),
+        PageContent::ServerError =>
+            server_error(),
+        PageContent::Unavailable =>
+            unavailable(),
     };
 
     let html = {
@@ -263,111 +867,711 @@ fn render(page: PageContent) -> IronResult<Response> {
             )]
     };
 
-    Ok(Response::with(
+    Response::with(
     ( "text/html".parse::<Mime>().unwrap()
     , status
     , html
-    )))
-}
-
-
-fn index(req: &mut Request) -> IronResult<Response> {
-    let conn = req.get::<persistent::Read<PostgresDB>>().unwrap().get().unwrap();
-    let author_post_times = conn
-        .query("
-            SELECT
-                author,
-                max(post.timestamp) as latest
-            FROM post, topic
-            WHERE post.topic = topic.id
-            GROUP BY topic.author
-            ORDER BY latest DESC", &[])
-        .unwrap()
-        .into_iter()
-        .map(|row| DateTime::from_utc(row.get("latest"), offset::utc::UTC))
-        .collect();
+    ))
+}
 
-    render(PageContent::Home { author_post_times: author_post_times })
+fn error_response(err: AppError) -> IronError {
+    let response = match err.status() {
+        Status::ServiceUnavailable => {
+            let mut r = render(PageContent::Unavailable);
+            r.headers.set_raw("Retry-After", vec![b"5".to_vec()]);
+            r
+        },
+        Status::BadRequest | Status::Forbidden =>
+            Response::with((err.status(), err.to_string())),
+        _ =>
+            render(PageContent::ServerError),
+    };
+    IronError { error: Box::new(err), response: response }
 }
 
 
-fn threads(req: &mut Request, key: &Uuid) -> IronResult<Response> {
-    let conn = req.get::<persistent::Read<PostgresDB>>().unwrap().get().unwrap();
-    let author: String = match conn
-        .query("
-            SELECT email
-            FROM author
-            WHERE key = $1
-        ", &[&key])
-        .unwrap()
-        .into_iter()
-        .map(|row| row.get("email"))
-        .next() {
+fn get_conn(req: &mut Request) -> AppResult<db::PostgresConnection> {
+    let pool = req.get::<PRead<PostgresDB>>()
+        .expect("the postgres pool is linked into the chain");
+    Ok(try!(pool.get()))
+}
+
+fn get_param(data: &params::Map, name: &str) -> AppResult<String> {
+    data.get(name)
+        .and_then(String::from_value)
+        .ok_or_else(|| AppError::MissingParam(name.to_string()))
+}
+
+
+fn missing(conn: &db::PostgresConnection, key: &Uuid) -> AppResult<PageContent> {
+    Ok(PageContent::NotFound(match try!(db::was_removed(conn, key)) {
+        Some(db::Removal::Deleted) => Missing::Removed,
+        Some(db::Removal::Faded) => Missing::Faded,
+        None => Missing::UnknownKey,
+    }))
+}
+
+
+fn index(req: &mut Request) -> AppResult<Response> {
+    let conn = try!(get_conn(req));
+    let author_post_times = try!(db::recent_post_times(&conn));
+
+    Ok(render(PageContent::Home { author_post_times: author_post_times }))
+}
+
+
+fn threads(req: &mut Request, key: &Uuid) -> AppResult<Response> {
+    let conn = try!(get_conn(req));
+    let author = match try!(db::author_by_key(&conn, key)) {
         Some(a) => a,
-        None => return Ok(Response::with((Status::NotFound))),
-    };
-    let topics = conn
-        .query("
-            SELECT
-                topic.topic as topic,
-                topic.key as key,
-                max(post.timestamp) as latest
-            FROM post, topic, author
-            WHERE post.topic = topic.id
-              AND topic.author = author.email
-              AND author.email = $1
-            GROUP BY post.topic, topic.topic, topic.key
-            ORDER BY latest DESC
-        ", &[&author])
-        .unwrap()
-        .into_iter()
-        .map(Topic::from_row)
-        .collect();
-
-    render(PageContent::Topics { author: author, topics: topics })
-}
-
-fn notes(req: &mut Request, topic_key: Uuid) -> IronResult<Response> {
-    let conn = req.get::<persistent::Read<PostgresDB>>().unwrap().get().unwrap();
-
-    let (author, topic): (String, Topic) = match conn
-        .query("
-            SELECT
-                topic.author as author,
-                topic.topic as topic,
-                topic.key as key,
-                topic.timestamp as latest  --nooooooooooo
-            FROM topic, post
-            WHERE post.topic = topic.id
-              AND topic.key = $1
-        ", &[&topic_key])
-        .unwrap()
-        .into_iter()
-        .map(|row| (row.get("author"), Topic::from_row(row)))
-        .next() {
-        Some((author, topic)) => (author, topic),
-        None => return Ok(Response::with((Status::NotFound))),
-    };
-
-    let posts = conn
-        .query("
-            SELECT
-                body,
-                post.timestamp
-            FROM post, topic
-            WHERE post.topic = topic.id
-              AND topic.key = $1
-            ORDER BY post.timestamp DESC
-        ", &[&topic_key])
-        .unwrap()
-        .into_iter()
-        .map(|row| Post {
-            body: row.get("body"),
-            timestamp: DateTime::from_utc(row.get("timestamp"), offset::utc::UTC),
-        })
-        .collect();
+        None => return Ok(render(try!(missing(&conn, key)))),
+    };
+    let zone = try!(db::author_timezone(&conn, &author));
+    let compose = try!(compose_token(req, &conn, &author));
+    let topics = try!(db::author_topics(&conn, &author, compose.is_some()));
+
+    Ok(render(PageContent::Topics { author: author, author_key: *key, zone: zone, topics: topics, compose: compose }))
+}
+
+fn search(req: &mut Request, key: &Uuid) -> AppResult<Response> {
+    let data = try!(req.get::<params::Params>());
+    let query = data.get("q").and_then(String::from_value).unwrap_or(String::new());
+    let conn = try!(get_conn(req));
+    let author = match try!(db::author_by_key(&conn, key)) {
+        Some(a) => a,
+        None => return Ok(render(try!(missing(&conn, key)))),
+    };
+    let zone = try!(db::author_timezone(&conn, &author));
+    let results = if query.trim().is_empty() {
+        vec![]
+    } else {
+        try!(db::search(&conn, &author, &query, try!(signed_in_as(req, &conn, &author))))
+    };
+
+    Ok(render(PageContent::Search { author: author, author_key: *key, zone: zone, query: query, results: results }))
+}
+
+fn notes(req: &mut Request, topic_key: Uuid) -> AppResult<Response> {
+    let conn = try!(get_conn(req));
+    show_topic(req, &conn, &topic_key, topic_url(&topic_key))
+}
+
+/// A topic read through one of its share links, which doesn't give away the topic's own key.
+fn shared(req: &mut Request, share_key: &Uuid) -> AppResult<Response> {
+    let conn = try!(get_conn(req));
+    match try!(db::use_share_link(&conn, share_key)) {
+        Some(topic_key) => show_topic(req, &conn, &topic_key, share_url(share_key)),
+        None => Ok(render(PageContent::NotFound(Missing::UnknownKey))),
+    }
+}
+
+fn show_topic(req: &Request, conn: &db::PostgresConnection, topic_key: &Uuid, url: String) -> AppResult<Response> {
+    let (author, topic, visibility) = match try!(db::topic_by_key(conn, topic_key)) {
+        Some(found) => found,
+        None => return Ok(match try!(db::moved_topic(conn, topic_key)) {
+            Some(moved_to) => redirect(&topic_url(&moved_to)),
+            None => render(try!(missing(conn, topic_key))),
+        }),
+    };
+    let compose = try!(compose_token(req, conn, &author));
+    if compose.is_none() {
+        match visibility {
+            db::Visibility::Private =>
+                return Ok(render(PageContent::NotFound(Missing::UnknownKey))),
+            db::Visibility::Protected => if !try!(unlocked(req, conn, topic_key)) {
+                return Ok(render(PageContent::Unlock { topic: topic.topic, url: url, problem: None }));
+            },
+            db::Visibility::Unlisted => {},
+        }
+    }
+
+    let zone = try!(db::author_timezone(conn, &author));
+    let posts = try!(db::topic_posts(conn, topic_key));
+    let sealed = try!(db::sealed_posts(conn, topic_key));
+    let e2e = try!(db::e2e_key(conn, topic_key));
+
+    Ok(render(PageContent::Posts { author: author, zone: zone, topic: topic, url: url, visibility: visibility, posts: posts, sealed: sealed, e2e: e2e, compose: compose }))
+}
+
+/// Whether the visitor has unlocked a protected topic, with a cookie for the current
+/// passphrase.
+fn unlocked(req: &Request, conn: &db::PostgresConnection, topic_key: &Uuid) -> AppResult<bool> {
+    let hash = match try!(db::passphrase_hash(conn, topic_key)) {
+        Some(hash) => hash,
+        None => return Ok(false),
+    };
+    Ok(req.headers.get_raw("Cookie")
+        .and_then(|raw| session::find_cookie(raw, session::UNLOCK_COOKIE))
+        .map_or(false, |value| session::check_unlock(&SETTINGS.session_secret, topic_key, &hash, &value)))
+}
+
+/// Who's asking, as far as rate limits go: their IP address, or if there's a proxy in front,
+/// the address it added last to X-Forwarded-For (anything before that came from the client).
+fn client_address(req: &Request) -> String {
+    let forwarded = if SETTINGS.behind_proxy {
+        req.headers.get_raw("X-Forwarded-For")
+            .and_then(|values| values.last())
+            .and_then(|value| String::from_utf8(value.clone()).ok())
+            .and_then(|value| value.rsplit(',').next().map(|address| address.trim().to_string()))
+            .and_then(|address| if address.is_empty() { None } else { Some(address) })
+    } else {
+        None
+    };
+    forwarded.unwrap_or_else(|| req.remote_addr.ip().to_string())
+}
+
+/// Check a passphrase for a protected topic read at `url`, remembering it with a cookie.
+fn unlock(req: &mut Request, conn: &db::PostgresConnection, topic_key: &Uuid, url: String) -> AppResult<Response> {
+    if req.method != Method::Post {
+        return Ok(redirect(&url));
+    }
+    let data = try!(req.get::<params::Params>());
+    let topic = match try!(db::topic_by_key(conn, topic_key)) {
+        Some((_, topic, db::Visibility::Protected)) => topic,
+        Some(_) => return Ok(redirect(&url)),
+        None => return Ok(render(try!(missing(conn, topic_key)))),
+    };
+    let hash = try!(db::passphrase_hash(conn, topic_key))
+        .unwrap();  // protected topics always have one
+
+    let client = client_address(req);
+    let failures = try!(db::recent_unlock_failures(conn, topic_key, &client));
+    match session::check_guess(failures, &try!(get_param(&data, "passphrase")), &hash) {
+        session::Guess::Right => {},
+        session::Guess::Wrong => {
+            try!(db::add_unlock_failure(conn, topic_key, &client));
+            return Ok(render(PageContent::Unlock { topic: topic.topic, url: url, problem: Some(UnlockProblem::Wrong) }));
+        },
+        session::Guess::TooMany =>
+            return Ok(render(PageContent::Unlock { topic: topic.topic, url: url, problem: Some(UnlockProblem::TooMany) })),
+    }
+
+    let value = session::unlock_value(&SETTINGS.session_secret, topic_key, &hash);
+    let secure = SETTINGS.base_url.starts_with("https://");
+    let mut response = redirect(&url);
+    response.headers.set_raw("Set-Cookie", vec![session::set_unlock_cookie(&url, &value, secure).into_bytes()]);
+    Ok(response)
+}
 
-    render(PageContent::Posts { author: author, topic: topic, posts: posts })
+fn unlock_topic(req: &mut Request, topic_key: &Uuid) -> AppResult<Response> {
+    let conn = try!(get_conn(req));
+    unlock(req, &conn, topic_key, topic_url(topic_key))
+}
+
+fn unlock_shared(req: &mut Request, share_key: &Uuid) -> AppResult<Response> {
+    let conn = try!(get_conn(req));
+    match try!(db::use_share_link(&conn, share_key)) {
+        Some(topic_key) => unlock(req, &conn, &topic_key, share_url(share_key)),
+        None => Ok(render(PageContent::NotFound(Missing::UnknownKey))),
+    }
+}
+
+/// Set or remove (with an empty one) the passphrase on one of the author's topics.
+fn set_passphrase(req: &mut Request, topic_key: &Uuid) -> AppResult<Response> {
+    if req.method != Method::Post {
+        return Ok(render(PageContent::NotFound(Missing::Page)));
+    }
+    let conn = try!(get_conn(req));
+    let current = match try!(posted_session(req, &conn)) {
+        Some(current) => current,
+        None => return Ok(redirect("/sign-in")),
+    };
+    let data = try!(req.get::<params::Params>());
+    let passphrase = get_param(&data, "passphrase").unwrap_or(String::new());
+    let hash = if passphrase.is_empty() { None } else { Some(session::hash_passphrase(&passphrase)) };
+    if !try!(db::set_passphrase(&conn, &current.author, topic_key, hash.as_ref().map(|h| &h[..]))) {
+        return Ok(render(PageContent::NotFound(Missing::Page)));
+    }
+    Ok(redirect(&topic_url(topic_key)))
+}
+
+fn export_zip(req: &mut Request, key: &Uuid) -> AppResult<Response> {
+    let conn = try!(get_conn(req));
+    let author = match try!(db::author_by_key(&conn, key)) {
+        Some(a) => a,
+        None => return Ok(render(try!(missing(&conn, key)))),
+    };
+    let private = try!(signed_in_as(req, &conn, &author));
+    let zip = export::markdown_zip(&author, try!(db::author_posts(&conn, &author, private)));
+
+    Ok(attachment("application/zip", "write-only.zip", zip))
+}
+
+fn export_mbox(req: &mut Request, key: &Uuid) -> AppResult<Response> {
+    let conn = try!(get_conn(req));
+    let author = match try!(db::author_by_key(&conn, key)) {
+        Some(a) => a,
+        None => return Ok(render(try!(missing(&conn, key)))),
+    };
+    let private = try!(signed_in_as(req, &conn, &author));
+    let mbox = export::mbox(&author, &SETTINGS.inbound_address, try!(db::author_posts(&conn, &author, private)));
+
+    Ok(attachment("application/mbox", "write-only.mbox", mbox.into_bytes()))
+}
+
+fn topic_mbox(req: &mut Request, topic_key: Uuid) -> AppResult<Response> {
+    let conn = try!(get_conn(req));
+    send_topic_mbox(req, &conn, &topic_key)
+}
+
+fn shared_mbox(req: &mut Request, share_key: &Uuid) -> AppResult<Response> {
+    let conn = try!(get_conn(req));
+    match try!(db::use_share_link(&conn, share_key)) {
+        Some(topic_key) => send_topic_mbox(req, &conn, &topic_key),
+        None => Ok(render(PageContent::NotFound(Missing::UnknownKey))),
+    }
+}
+
+fn send_topic_mbox(req: &Request, conn: &db::PostgresConnection, topic_key: &Uuid) -> AppResult<Response> {
+    let (author, topic) = match try!(db::topic_by_key(conn, topic_key)) {
+        Some((author, topic, visibility)) => {
+            let readable = match visibility {
+                db::Visibility::Unlisted => true,
+                db::Visibility::Protected => try!(unlocked(req, conn, topic_key)) || try!(signed_in_as(req, conn, &author)),
+                db::Visibility::Private => try!(signed_in_as(req, conn, &author)),
+            };
+            if !readable {
+                return Ok(render(PageContent::NotFound(Missing::UnknownKey)));
+            }
+            (author, topic)
+        },
+        None => return Ok(match try!(db::moved_topic(conn, topic_key)) {
+            Some(moved_to) => redirect(&format!("{}/mbox", topic_url(&moved_to))),
+            None => render(try!(missing(conn, topic_key))),
+        }),
+    };
+    let posts = try!(db::topic_posts(conn, topic_key));
+    let mbox = export::mbox(&author, &SETTINGS.inbound_address, vec![(topic, posts)]);
+
+    Ok(attachment("application/mbox", "topic.mbox", mbox.into_bytes()))
+}
+
+fn attachment(mime: &str, filename: &str, body: Vec<u8>) -> Response {
+    let mut response = Response::with(
+    ( mime.parse::<Mime>().unwrap()
+    , Status::Ok
+    , body
+    ));
+    response.headers.set_raw("Content-Disposition",
+        vec![format!("attachment; filename=\"{}\"", filename).into_bytes()]);
+    response
+}
+
+fn export_email(req: &mut Request, key: &Uuid) -> AppResult<Response> {
+    if req.method != Method::Post {
+        return Ok(render(PageContent::NotFound(Missing::Page)));
+    }
+    let conn = try!(get_conn(req));
+    let author = match try!(db::author_by_key(&conn, key)) {
+        Some(a) => a,
+        None => return Ok(render(try!(missing(&conn, key)))),
+    };
+    // only to the author, from their own session, so nobody else can fill their inbox
+    match try!(posted_session(req, &conn)) {
+        Some(ref current) if current.author == author => {},
+        Some(_) => return Ok(render(PageContent::NotFound(Missing::Page))),
+        None => return Ok(redirect("/sign-in")),
+    }
+    email::export_link(&SETTINGS, &author, key);
+
+    Ok(render(PageContent::ExportSent { author: author, author_key: *key }))
+}
+
+
+fn redirect(path: &str) -> Response {
+    let mut response = Response::with(Status::SeeOther);
+    response.headers.set_raw("Location", vec![path.as_bytes().to_vec()]);
+    response
+}
+
+fn set_session_cookie(response: &mut Response, value: Option<&str>) {
+    let secure = SETTINGS.base_url.starts_with("https://");
+    response.headers.set_raw("Set-Cookie", vec![session::set_cookie(value, secure).into_bytes()]);
+}
+
+/// The session of whoever's signed in, if anyone is.
+fn current_session(req: &Request, conn: &db::PostgresConnection) -> AppResult<Option<db::Session>> {
+    let id = req.headers.get_raw("Cookie")
+        .and_then(|raw| session::find_cookie(raw, session::COOKIE))
+        .and_then(|value| session::verify(&SETTINGS.session_secret, &value));
+    match id {
+        Some(id) => Ok(try!(db::live_session(conn, &id))),
+        None => Ok(None),
+    }
+}
+
+/// Whether the visitor holds `author`'s session, and so can see their private topics.
+fn signed_in_as(req: &Request, conn: &db::PostgresConnection, author: &str) -> AppResult<bool> {
+    Ok(try!(current_session(req, conn)).map_or(false, |current| current.author == author))
+}
+
+/// A CSRF token for the composer, if `author` is the one signed in.
+fn compose_token(req: &Request, conn: &db::PostgresConnection, author: &str) -> AppResult<Option<String>> {
+    Ok(try!(current_session(req, conn))
+        .and_then(|current| if current.author == author {
+            Some(session::csrf_token(&SETTINGS.session_secret, &current.id))
+        } else {
+            None
+        }))
+}
+
+/// Post a note from the browser, the same way as one that came in by email.
+fn compose(req: &mut Request) -> AppResult<Response> {
+    if req.method != Method::Post {
+        return Ok(render(PageContent::NotFound(Missing::Page)));
+    }
+    let data = try!(req.get::<params::Params>());
+    let conn = try!(get_conn(req));
+    let current = match try!(current_session(req, &conn)) {
+        Some(current) => current,
+        None => return Ok(redirect("/sign-in")),
+    };
+    if !session::check_csrf(&SETTINGS.session_secret, &current.id, &try!(get_param(&data, "csrf"))) {
+        return Err(AppError::Forgery);
+    }
+
+    let body = try!(get_param(&data, "body"));
+    let note = ingest::Note {
+        sender: current.author,
+        subject: get_param(&data, "topic").unwrap_or(String::new()),
+        html: mail::text_to_html(&body),
+        text: body,
+        headers: vec![],
+    };
+    let topic_key = try!(ingest::ingest(&conn, &note, None, newcomer(true)));
+
+    Ok(redirect(&topic_url(&topic_key)))
+}
+
+/// Change a note from the browser, keeping the old version in its history.
+fn edit_post(req: &mut Request, topic_key: &Uuid, post_id: &Uuid) -> AppResult<Response> {
+    let conn = try!(get_conn(req));
+    let current = match try!(current_session(req, &conn)) {
+        Some(current) => current,
+        None => return Ok(redirect("/sign-in")),
+    };
+    let post = match try!(db::author_post(&conn, &current.author, post_id))
+        .and_then(|(key, post)| if key == *topic_key && !e2e::is_sealed(&post.body) { Some(post) } else { None }) {
+        Some(post) => post,
+        None => return Ok(render(PageContent::NotFound(Missing::Page))),
+    };
+    let csrf = session::csrf_token(&SETTINGS.session_secret, &current.id);
+    if req.method != Method::Post {
+        return Ok(render(PageContent::EditPost { topic_key: *topic_key, post: post, csrf: csrf }));
+    }
+
+    let data = try!(req.get::<params::Params>());
+    if !session::check_csrf(&SETTINGS.session_secret, &current.id, &try!(get_param(&data, "csrf"))) {
+        return Err(AppError::Forgery);
+    }
+    let text = try!(get_param(&data, "body")).replace("\r\n", "\n");
+    // saving the form untouched shouldn't turn HTML that Markdown can't say into something else
+    if text.trim() != export::to_markdown(&post.body) {
+        let body = sanitize::clean(&export::from_markdown(&text));
+        try!(db::edit_post(&conn, &current.author, post_id, &body));
+    }
+
+    Ok(redirect(&format!("{}#{}", topic_url(topic_key), post_id)))
+}
+
+/// Earlier versions of a note, for its author's eyes only.
+fn post_history(req: &mut Request, topic_key: &Uuid, post_id: &Uuid) -> AppResult<Response> {
+    let conn = try!(get_conn(req));
+    let current = match try!(current_session(req, &conn)) {
+        Some(current) => current,
+        None => return Ok(render(PageContent::NotFound(Missing::Page))),
+    };
+    let post = match try!(db::author_post(&conn, &current.author, post_id))
+        .and_then(|(key, post)| if key == *topic_key { Some(post) } else { None }) {
+        Some(post) => post,
+        None => return Ok(render(PageContent::NotFound(Missing::Page))),
+    };
+    let zone = try!(db::author_timezone(&conn, &current.author));
+    let revisions = try!(db::post_revisions(&conn, post_id));
+    let e2e = try!(db::e2e_key(&conn, topic_key));
+
+    Ok(render(PageContent::History { topic_key: *topic_key, zone: zone, post: post, revisions: revisions, e2e: e2e }))
+}
+
+/// The signed-in session behind a posted form, once its CSRF token checks out.
+fn posted_session(req: &mut Request, conn: &db::PostgresConnection) -> AppResult<Option<db::Session>> {
+    let data = try!(req.get::<params::Params>());
+    let current = match try!(current_session(req, conn)) {
+        Some(current) => current,
+        None => return Ok(None),
+    };
+    if !session::check_csrf(&SETTINGS.session_secret, &current.id, &try!(get_param(&data, "csrf"))) {
+        return Err(AppError::Forgery);
+    }
+    Ok(Some(current))
+}
+
+fn trash(req: &mut Request) -> AppResult<Response> {
+    let conn = try!(get_conn(req));
+    let current = match try!(current_session(req, &conn)) {
+        Some(current) => current,
+        None => return Ok(redirect("/sign-in")),
+    };
+    let author_key = try!(db::author_key(&conn, &current.author))
+        .unwrap();  // sessions go when their author does
+    let zone = try!(db::author_timezone(&conn, &current.author));
+    let trashed = try!(db::trash(&conn, &current.author));
+    let csrf = session::csrf_token(&SETTINGS.session_secret, &current.id);
+
+    Ok(render(PageContent::Trash { author_key: author_key, zone: zone, trashed: trashed, csrf: csrf }))
+}
+
+fn delete_post(req: &mut Request, topic_key: &Uuid, post_id: &Uuid) -> AppResult<Response> {
+    if req.method != Method::Post {
+        return Ok(render(PageContent::NotFound(Missing::Page)));
+    }
+    let conn = try!(get_conn(req));
+    let current = match try!(posted_session(req, &conn)) {
+        Some(current) => current,
+        None => return Ok(redirect("/sign-in")),
+    };
+    if !try!(db::trash_post(&conn, &current.author, post_id)) {
+        return Ok(render(PageContent::NotFound(Missing::Page)));
+    }
+    Ok(redirect(&topic_url(topic_key)))
+}
+
+fn delete_topic(req: &mut Request, topic_key: &Uuid) -> AppResult<Response> {
+    if req.method != Method::Post {
+        return Ok(render(PageContent::NotFound(Missing::Page)));
+    }
+    let conn = try!(get_conn(req));
+    let current = match try!(posted_session(req, &conn)) {
+        Some(current) => current,
+        None => return Ok(redirect("/sign-in")),
+    };
+    if !try!(db::trash_topic(&conn, &current.author, topic_key)) {
+        return Ok(render(PageContent::NotFound(Missing::Page)));
+    }
+    Ok(redirect("/trash"))
+}
+
+fn restore_post(req: &mut Request, topic_key: &Uuid, post_id: &Uuid) -> AppResult<Response> {
+    if req.method != Method::Post {
+        return Ok(render(PageContent::NotFound(Missing::Page)));
+    }
+    let conn = try!(get_conn(req));
+    let current = match try!(posted_session(req, &conn)) {
+        Some(current) => current,
+        None => return Ok(redirect("/sign-in")),
+    };
+    if !try!(db::restore_post(&conn, &current.author, post_id)) {
+        return Ok(render(PageContent::NotFound(Missing::Page)));
+    }
+    Ok(redirect(&format!("{}#{}", topic_url(topic_key), post_id)))
+}
+
+fn restore_topic(req: &mut Request, topic_key: &Uuid) -> AppResult<Response> {
+    if req.method != Method::Post {
+        return Ok(render(PageContent::NotFound(Missing::Page)));
+    }
+    let conn = try!(get_conn(req));
+    let current = match try!(posted_session(req, &conn)) {
+        Some(current) => current,
+        None => return Ok(redirect("/sign-in")),
+    };
+    if !try!(db::restore_topic(&conn, &current.author, topic_key)) {
+        return Ok(render(PageContent::NotFound(Missing::Page)));
+    }
+    Ok(redirect(&topic_url(topic_key)))
+}
+
+/// List a topic's share links, or make a new one.
+fn shares(req: &mut Request, topic_key: &Uuid) -> AppResult<Response> {
+    let conn = try!(get_conn(req));
+    let current = match try!(current_session(req, &conn)) {
+        Some(current) => current,
+        None => return Ok(redirect("/sign-in")),
+    };
+    let topic = match try!(db::topic_by_key(&conn, topic_key)) {
+        Some((author, topic, _)) => if author == current.author {
+            topic
+        } else {
+            return Ok(render(PageContent::NotFound(Missing::Page)));
+        },
+        None => return Ok(render(PageContent::NotFound(Missing::Page))),
+    };
+
+    if req.method == Method::Post {
+        let data = try!(req.get::<params::Params>());
+        if !session::check_csrf(&SETTINGS.session_secret, &current.id, &try!(get_param(&data, "csrf"))) {
+            return Err(AppError::Forgery);
+        }
+        let label = try!(get_param(&data, "label")).trim().to_string();
+        let days = get_param(&data, "days").ok()
+            .and_then(|d| d.trim().parse::<i32>().ok())
+            .and_then(|d| if d > 0 { Some(d) } else { None });
+        try!(db::add_share_link(&conn, &current.author, topic_key, &label, days));
+        return Ok(redirect(&format!("{}/shares", topic_url(topic_key))));
+    }
+
+    let zone = try!(db::author_timezone(&conn, &current.author));
+    let links = try!(db::share_links(&conn, topic_key));
+    let csrf = session::csrf_token(&SETTINGS.session_secret, &current.id);
+
+    Ok(render(PageContent::Shares { topic: topic, zone: zone, links: links, csrf: csrf }))
+}
+
+/// Turn on end-to-end encryption for one of the author's topics, with the keys their
+/// browser made.
+fn encrypt(req: &mut Request, topic_key: &Uuid) -> AppResult<Response> {
+    let conn = try!(get_conn(req));
+    let current = match try!(current_session(req, &conn)) {
+        Some(current) => current,
+        None => return Ok(redirect("/sign-in")),
+    };
+    let topic = match try!(db::topic_by_key(&conn, topic_key)) {
+        Some((author, topic, _)) => if author == current.author {
+            topic
+        } else {
+            return Ok(render(PageContent::NotFound(Missing::Page)));
+        },
+        None => return Ok(render(PageContent::NotFound(Missing::Page))),
+    };
+    if try!(db::e2e_key(&conn, topic_key)).is_some() {
+        return Ok(redirect(&topic_url(topic_key)));
+    }
+
+    if req.method == Method::Post {
+        let data = try!(req.get::<params::Params>());
+        if !session::check_csrf(&SETTINGS.session_secret, &current.id, &try!(get_param(&data, "csrf"))) {
+            return Err(AppError::Forgery);
+        }
+        // anything malformed counts as missing: only e2e.js fills these in
+        let bytes = |name: &str, len: Option<usize>| get_param(&data, name).ok()
+            .and_then(|b64| b64.from_base64().ok())
+            .and_then(|b| if !b.is_empty() && len.map_or(true, |len| b.len() == len) { Some(b) } else { None })
+            .ok_or_else(|| AppError::MissingParam(name.to_string()));
+        let public_key = try!(e2e::public_key(&try!(get_param(&data, "public_key")))
+            .ok_or_else(|| AppError::MissingParam("public_key".to_string())));
+        let key = db::E2eKey {
+            public_key: public_key,
+            wrapped_private_key: try!(bytes("wrapped_key", None)),
+            salt: try!(bytes("salt", Some(16))),
+            iv: try!(bytes("iv", Some(12))),
+        };
+        let public_key = key.public_key.clone();
+        try!(db::encrypt_topic(&conn, &current.author, topic_key, &key, &|body| e2e::seal(&public_key, body)));
+        return Ok(redirect(&topic_url(topic_key)));
+    }
+
+    let csrf = session::csrf_token(&SETTINGS.session_secret, &current.id);
+    Ok(render(PageContent::Encrypt { topic: topic, csrf: csrf }))
+}
+
+fn revoke_share_link(req: &mut Request, topic_key: &Uuid, share_key: &Uuid) -> AppResult<Response> {
+    if req.method != Method::Post {
+        return Ok(render(PageContent::NotFound(Missing::Page)));
+    }
+    let conn = try!(get_conn(req));
+    let current = match try!(posted_session(req, &conn)) {
+        Some(current) => current,
+        None => return Ok(redirect("/sign-in")),
+    };
+    try!(db::revoke_share_link(&conn, &current.author, share_key));
+    Ok(redirect(&format!("{}/shares", topic_url(topic_key))))
+}
+
+fn sign_in(req: &mut Request) -> AppResult<Response> {
+    if req.method != Method::Post {
+        return Ok(render(PageContent::SignIn));
+    }
+    let data = try!(req.get::<params::Params>());
+    let address = try!(get_param(&data, "email")).trim().to_string();
+    let conn = try!(get_conn(req));
+
+    // the page says the same thing either way, so it can't be used to find out who writes here
+    if try!(db::author_key(&conn, &address)).is_some()
+        && try!(db::recent_sign_in_tokens(&conn, &address)) < session::MAX_LINKS {
+        let token = session::new_token();
+        try!(db::add_sign_in_token(&conn, &address, &session::hash_token(&token)));
+        email::sign_in(&SETTINGS, &address, &token);
+    }
+
+    Ok(render(PageContent::SignInSent { email: address }))
+}
+
+fn use_sign_in(req: &mut Request, token: &str) -> AppResult<Response> {
+    if req.method != Method::Post {
+        // mail scanners follow links, so using one up takes a button press
+        return Ok(render(PageContent::ConfirmSignIn { token: token.to_string() }));
+    }
+    let user_agent = req.headers.get_raw("User-Agent")
+        .and_then(|raw| raw.get(0))
+        .map(|ua| String::from_utf8_lossy(ua).into_owned());
+    let conn = try!(get_conn(req));
+
+    let author = match try!(db::use_sign_in_token(&conn, &session::hash_token(token))) {
+        Some(author) => author,
+        None => return Ok(render(PageContent::SignInExpired)),
+    };
+    // the link came to their inbox, which is all the welcome email's link would show
+    try!(db::confirm_author(&conn, &author));
+    let id = try!(db::add_session(&conn, &author, user_agent.as_ref().map(|ua| &ua[..])));
+
+    let mut response = redirect("/account");
+    set_session_cookie(&mut response, Some(&session::cookie_value(&SETTINGS.session_secret, &id)));
+    Ok(response)
+}
+
+fn sign_out(req: &mut Request) -> AppResult<Response> {
+    if req.method != Method::Post {
+        return Ok(render(PageContent::NotFound(Missing::Page)));
+    }
+    let conn = try!(get_conn(req));
+    if let Some(current) = try!(posted_session(req, &conn)) {
+        try!(db::revoke_session(&conn, &current.author, &current.id));
+    }
+    let mut response = redirect("/");
+    set_session_cookie(&mut response, None);
+    Ok(response)
+}
+
+fn account(req: &mut Request) -> AppResult<Response> {
+    let conn = try!(get_conn(req));
+    let current = match try!(current_session(req, &conn)) {
+        Some(current) => current,
+        None => return Ok(redirect("/sign-in")),
+    };
+    let author_key = try!(db::author_key(&conn, &current.author))
+        .unwrap();  // sessions are deleted along with their author
+    let zone = try!(db::author_timezone(&conn, &current.author));
+    let sessions = try!(db::author_sessions(&conn, &current.author));
+    let csrf = session::csrf_token(&SETTINGS.session_secret, &current.id);
+
+    Ok(render(PageContent::Account {
+        author: current.author,
+        author_key: author_key,
+        zone: zone,
+        sessions: sessions,
+        current: current.id,
+        csrf: csrf,
+    }))
+}
+
+fn revoke_session(req: &mut Request, id: &Uuid) -> AppResult<Response> {
+    if req.method != Method::Post {
+        return Ok(render(PageContent::NotFound(Missing::Page)));
+    }
+    let conn = try!(get_conn(req));
+    let current = match try!(posted_session(req, &conn)) {
+        Some(current) => current,
+        None => return Ok(redirect("/sign-in")),
+    };
+    try!(db::revoke_session(&conn, &current.author, id));
+
+    if *id == current.id {
+        let mut response = redirect("/");
+        set_session_cookie(&mut response, None);
+        Ok(response)
+    } else {
+        Ok(redirect("/account"))
+    }
 }
 
 
@@ -390,133 +1594,225 @@ fn notes(req: &mut Request, topic_key: Uuid) -> IronResult<Response> {
 
 
 // https://documentation.mailgun.com/user_manual.html#parsed-messages-parameters
-fn receive_email(req: &mut Request) -> IronResult<Response> {
-    let data = req.get::<params::Params>().unwrap();
-    let conn = req.get::<persistent::Read<PostgresDB>>().unwrap().get().unwrap();
-
-    let sender = String::from_value(data.get("sender").unwrap()).unwrap();
-    let topic = {
-        let mut subject = &String::from_value(data.get("subject").unwrap()).unwrap()[..];
-        while subject.len() >= 4 &&
-              subject[..4].to_lowercase() == *"re: " {
-            subject = &subject[4..]
+fn receive_email(req: &mut Request) -> AppResult<Response> {
+    let data = try!(req.get::<params::Params>());
+    let signed = sender::check_webhook(&SETTINGS.mailgun.signing_key,
+        &get_param(&data, "timestamp").unwrap_or(String::new()),
+        &get_param(&data, "token").unwrap_or(String::new()),
+        &get_param(&data, "signature").unwrap_or(String::new()),
+        UTC::now().timestamp());
+    if !signed {
+        return Ok(Response::with((Status::Forbidden, "bad signature")));
+    }
+    let conn = try!(get_conn(req));
+
+    let note = ingest::Note {
+        sender: try!(get_param(&data, "sender")),
+        subject: try!(get_param(&data, "subject")),
+        html: try!(get_param(&data, "stripped-html")),
+        text: get_param(&data, "stripped-text").unwrap_or(String::new()),
+        headers: mail::headers_from_json(&try!(get_param(&data, "message-headers"))),
+    };
+    let delivered = get_param(&data, "timestamp").ok()
+        .and_then(|t| t.parse().ok())
+        .and_then(|secs| NaiveDateTime::from_timestamp_opt(secs, 0))
+        .map(|t| DateTime::from_utc(t, UTC));
+    let authored = ingest::authored(&note.headers, delivered, &UTC::now());
+
+    let verdict = sender::verdict(&note.headers, &note.sender, &SETTINGS.authserv_id);
+    if verdict != sender::Verdict::Pass {
+        match SETTINGS.sender_policy {
+            sender::Policy::Accept => {},
+            // mailgun takes a 406 to mean "don't try again"
+            sender::Policy::Reject =>
+                return Ok(Response::with((Status::NotAcceptable, "sender not authenticated"))),
+            sender::Policy::Quarantine => {
+                try!(quarantine(&conn, &note, authored.as_ref(), verdict));
+                return Ok(received());
+            },
         }
-        subject.to_string()
-    };
-    let body = String::from_value(data.get("stripped-html").unwrap()).unwrap();
-    let ref headers = String::from_value(data.get("message-headers").unwrap()).unwrap();
-
-    let message_id = headers
-        .find("[\"Message-Id\", \"<")
-        .and_then(|start| headers[start..]
-            .find(">")
-            .map(|len| &headers[start+16..start+len+1]));
-
-    // create the author if they don't exist yet
-    let added = conn.execute("
-        INSERT INTO author (email)
-            SELECT $1
-        WHERE NOT EXISTS (
-            SELECT email
-            FROM author
-            WHERE email = $1)",
-        &[&sender]).unwrap();
-
-    // create the topic if it doesn't exist yet
-    conn.execute("
-        INSERT INTO topic (topic, author)
-            SELECT $1, $2
-        WHERE NOT EXISTS (
-            SELECT topic.topic as topic
-            FROM topic
-            WHERE topic.author = $2
-              AND topic.topic = $1)",
-        &[&topic, &sender]).unwrap();
-
-    // grab the topic id for the note
-    let (topic_id, topic_key): (Uuid, Uuid) = conn
-        .query("
-            SELECT id, key
-            FROM topic
-            WHERE topic.topic = $1
-              AND topic.author = $2",
-            &[&topic, &sender])
-        .unwrap()
-        .into_iter()
-        .map(|row| (row.get("id"), row.get("key")))
-        .next()
-        .unwrap();  // guarded by the previous query (what's a race?..)
-
-    // insert the note
-    conn.execute("
-        INSERT INTO post (topic, body)
-        VALUES ($1, $2)",
-        &[&topic_id, &body]).unwrap();
-
-    // if it's a new user, send a welcome email
-    if added == 1 {
-        // grab the user key for their special link
-        let user_key: Uuid = conn
-            .query("SELECT key FROM author WHERE email = $1", &[&sender])
-            .unwrap()
-            .into_iter()
-            .map(|row| row.get("key"))
-            .next()
-            .unwrap();  // guarded by the user check / creation
-        email::welcome(&MAILGUN_DOMAIN, &MAILGUN_KEY, &sender, &topic, &topic_key, &user_key, message_id);
-    }
-
-    let resp = Response::with(
+    }
+    try!(file_note(&conn, &note, authored.as_ref(), newcomer(false)));
+    Ok(received())
+}
+
+fn received() -> Response {
+    Response::with(
     ( "text/html".parse::<Mime>().unwrap()
     , Status::Ok
     , "wooo"
-    ));
-    Ok(resp)
+    ))
 }
 
+/// How to welcome a note's sender if they're new: `proven` if they've already shown that the
+/// address is theirs. Confirming it rides on the welcome email, so there's none without it.
+fn newcomer(proven: bool) -> ingest::Newcomer {
+    if !SETTINGS.features.welcome_email {
+        ingest::Newcomer::Quiet
+    } else if proven {
+        ingest::Newcomer::Welcome
+    } else {
+        ingest::Newcomer::Confirm
+    }
+}
 
-fn env(name: &str, def: &str) -> String {
-    std::env::var(name).unwrap_or(def.to_string())
+/// Run an emailed command, or file a note under its topic, returning the topic's key.
+fn file_note(conn: &db::PostgresConnection, note: &ingest::Note, authored: Option<&DateTime<UTC>>, newcomer: ingest::Newcomer) -> AppResult<Option<Uuid>> {
+    if let Some(command) = command::parse(&subject::normalize(&note.subject)) {
+        let reply = match command {
+            Ok(command) => try!(command::run(conn, note, command)),
+            Err(problem) => problem,
+        };
+        let message_id = mail::header(&note.headers, "Message-Id");
+        email::command_reply(&SETTINGS, &note.sender, &note.subject, &reply, message_id);
+        Ok(None)
+    } else {
+        Ok(Some(try!(ingest::ingest(conn, note, authored, newcomer))))
+    }
 }
 
-lazy_static! {
-    // sandbox account
-    static ref MAILGUN_KEY: String = env("MAILGUN_KEY", "key-7cdbe8cd5fe3a81fff2a24121c7644dc");
-    static ref MAILGUN_DOMAIN: String = env("MAILGUN_DOMAIN", "sandboxdef91d7398f94b818073e4b7a1341be7.mailgun.org");
+/// Hold on to a note whose sender couldn't be authenticated, and ask the real address about
+/// it, unless it already has plenty waiting.
+fn quarantine(conn: &db::PostgresConnection, note: &ingest::Note, authored: Option<&DateTime<UTC>>, verdict: sender::Verdict) -> AppResult<()> {
+    if try!(db::pending_notes_from(conn, &note.sender)) >= sender::MAX_PENDING {
+        return Ok(());
+    }
+    let token = session::new_token();
+    let headers = json::encode(&note.headers)
+        .expect("headers are just strings");
+    try!(db::add_pending_note(conn, &session::hash_token(&token), &note.sender, &note.subject,
+        &note.html, &note.text, &headers, authored, verdict));
+    email::confirm_note(&SETTINGS, &note.sender, &note.subject, &token, mail::header(&note.headers, "Message-Id"));
+    Ok(())
 }
 
-fn get_pool(uri: &str) -> Result<PostgresPool, String> {
-    let config = r2d2::Config::default();
-    let manager = try!(PostgresConnectionManager::new(uri, SslMode::None)
-        .map_err(|err| err.to_string()));
-    r2d2::Pool::new(config, manager)
-        .map_err(|err| err.to_string())
+/// Confirm a new author's address, from the link in their welcome email, and show them their
+/// first topic.
+fn confirm_address(req: &mut Request, token: &str) -> AppResult<Response> {
+    if req.method != Method::Post {
+        // mail scanners follow links, so confirming takes a button press
+        return Ok(render(PageContent::ConfirmAddress { token: token.to_string() }));
+    }
+    let conn = try!(get_conn(req));
+    let author = match try!(db::confirm_author_token(&conn, &session::hash_token(token))) {
+        Some(author) => author,
+        None => return Ok(render(PageContent::AddressExpired)),
+    };
+    match try!(db::first_topic(&conn, &author)) {
+        Some((_, topic_key)) => Ok(redirect(&topic_url(&topic_key))),
+        None => Ok(redirect("/")),
+    }
 }
 
+/// Add (or throw away) a quarantined note, from the link in its confirmation email.
+fn confirm_note(req: &mut Request, token: &str) -> AppResult<Response> {
+    let conn = try!(get_conn(req));
+    let token_hash = session::hash_token(token);
+    if req.method != Method::Post {
+        // mail scanners follow links, so confirming takes a button press
+        return Ok(render(match try!(db::pending_note(&conn, &token_hash)) {
+            Some(note) => PageContent::ConfirmNote { token: token.to_string(), note: note },
+            None => PageContent::NotFound(Missing::Page),
+        }));
+    }
+    let data = try!(req.get::<params::Params>());
+    let pending = match try!(db::take_pending_note(&conn, &token_hash)) {
+        Some(pending) => pending,
+        None => return Ok(render(PageContent::NotFound(Missing::Page))),
+    };
+    if get_param(&data, "discard").is_ok() {
+        return Ok(render(PageContent::NoteConfirmed { kept: false, topic_key: None }));
+    }
 
-fn router(req: &mut Request) -> IronResult<Response> {
-    let path = format!("/{}", req.url.path().join("/"));
+    let note = ingest::Note {
+        sender: pending.sender,
+        subject: pending.subject,
+        html: pending.html,
+        text: pending.text,
+        headers: mail::headers_from_json(&pending.headers),
+    };
+    // following the link in the confirmation email proved the address
+    let topic_key = try!(file_note(&conn, &note, pending.authored.as_ref(), newcomer(true)));
+    Ok(render(PageContent::NoteConfirmed { kept: true, topic_key: topic_key }))
+}
+
+
+lazy_static! {
+    static ref SETTINGS: Settings = Settings::load().unwrap_or_else(|errors| {
+        println!("invalid configuration:");
+        for error in errors {
+            println!("  ✗ {}", error);
+        }
+        std::process::exit(1)
+    });
+}
+
+fn dispatch(req: &mut Request, path: &str) -> AppResult<Response> {
     route!(path, {
     (/)                  => index(req);
     (/"email")           => receive_email(req);
+    (/"sign-in")         => sign_in(req);
+    (/"sign-in"/[token: String]) => use_sign_in(req, &token);
+    (/"confirm"/[token: String]) => confirm_note(req, &token);
+    (/"welcome"/[token: String]) => confirm_address(req, &token);
+    (/"sign-out")        => sign_out(req);
+    (/"account")         => account(req);
+    (/"compose")         => compose(req);
+    (/"trash")           => trash(req);
+    (/"account"/"sessions"/[id: Uuid]/"revoke") => revoke_session(req, &id);
     (/"robots.txt")      => Ok(Response::with((Status::Ok, include_str!("robots.txt"))));
     (/[key: Uuid])       => threads(req, &key);
+    (/[key: Uuid]/"search") => search(req, &key);
+    (/[key: Uuid]/"export.zip") => export_zip(req, &key);
+    (/[key: Uuid]/"export.mbox") => export_mbox(req, &key);
+    (/[key: Uuid]/"export"/"email") => export_email(req, &key);
     (/"t"/[topic: Uuid]) => notes(req, topic);
+    (/"t"/[topic: Uuid]/"mbox") => topic_mbox(req, topic);
+    (/"t"/[topic: Uuid]/"delete") => delete_topic(req, &topic);
+    (/"t"/[topic: Uuid]/"restore") => restore_topic(req, &topic);
+    (/"t"/[topic: Uuid]/"shares") => shares(req, &topic);
+    (/"t"/[topic: Uuid]/"unlock") => unlock_topic(req, &topic);
+    (/"t"/[topic: Uuid]/"passphrase") => set_passphrase(req, &topic);
+    (/"t"/[topic: Uuid]/"encrypt") => encrypt(req, &topic);
+    (/"t"/[topic: Uuid]/"shares"/[share: Uuid]/"revoke") => revoke_share_link(req, &topic, &share);
+    (/"t"/[topic: Uuid]/[post: Uuid]/"edit") => edit_post(req, &topic, &post);
+    (/"t"/[topic: Uuid]/[post: Uuid]/"history") => post_history(req, &topic, &post);
+    (/"t"/[topic: Uuid]/[post: Uuid]/"delete") => delete_post(req, &topic, &post);
+    (/"t"/[topic: Uuid]/[post: Uuid]/"restore") => restore_post(req, &topic, &post);
+    (/"t"/[_key: String]) => Ok(render(PageContent::NotFound(Missing::MalformedKey)));
+    (/"s"/[share: Uuid]) => shared(req, &share);
+    (/"s"/[share: Uuid]/"mbox") => shared_mbox(req, &share);
+    (/"s"/[share: Uuid]/"unlock") => unlock_shared(req, &share);
+    (/"s"/[_key: String]) => Ok(render(PageContent::NotFound(Missing::MalformedKey)));
     });
 
-    render(PageContent::NotFound)
+    Ok(render(PageContent::NotFound(Missing::Page)))
+}
+
+fn router(req: &mut Request) -> IronResult<Response> {
+    let path = format!("/{}", req.url.path().join("/"));
+    dispatch(req, &path).map_err(|err| {
+        println!("error handling {} {}: {}", req.method, path, err);
+        error_response(err)
+    })
 }
 
 
 fn main() {
-    let port = env("PORT", "").parse::<u16>().unwrap_or(8080);
-    let dburl = env("DATABASE_URL", "postgresql://postgres@localhost");
+    let port = SETTINGS.port;  // also validates the configuration before anything else happens
 
     let (logger_before, logger_after) = Logger::new(None);
 
-    let pool = get_pool(&dburl).unwrap();
+    let pool = db::get_pool(&SETTINGS.database_url, SETTINGS.db_pool_size).unwrap();
     migrate::run(pool.get().unwrap()).unwrap();
 
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if args.len() > 0 {
+        std::process::exit(admin::run(&pool.get().unwrap(), &args));
+    }
+
+    jobs::start(pool.clone());
+
     let mut chain = Chain::new(router);
     chain.link_before(logger_before);
     chain.link(PRead::<PostgresDB>::both(pool));
@@ -1,22 +1,65 @@
+use std::collections::HashMap;
+
+use chrono::{DateTime, NaiveDateTime, UTC};
+use chrono_tz::Tz;
 use crypto::digest::Digest;
 use crypto::sha2::Sha256;
+use postgres::GenericConnection;
+use postgres::error::Error;
+use uuid::Uuid;
 
+use at_rest;
 use db;
+use e2e;
+use export;
+use sanitize;
+use subject::{self, EmptySubject};
+use zone;
+
+
+/// Rust code to run right after a migration's SQL, for data changes that SQL can't express.
+type DataMigration = fn(&GenericConnection) -> Result<(), Error>;
 
 
 pub fn run(conn: db::PostgresConnection) -> Result<(), ()> {
     println!("migrations");
     let trans = conn.transaction().unwrap();
-    let all_migrations =
-        [ include_str!("./migrations/init.sql")
-        , include_str!("./migrations/add-author-table.sql")
-        , include_str!("./migrations/unlist-and-alias-authors.sql")
-        , include_str!("./migrations/unlist-topics.sql")
-        , include_str!("./migrations/author-via-topic.sql")
+    let all_migrations: &[(&str, Option<DataMigration>)] =
+        &[ (include_str!("./migrations/init.sql"), None)
+        , (include_str!("./migrations/add-author-table.sql"), None)
+        , (include_str!("./migrations/unlist-and-alias-authors.sql"), None)
+        , (include_str!("./migrations/unlist-topics.sql"), None)
+        , (include_str!("./migrations/author-via-topic.sql"), None)
+        , (include_str!("./migrations/record-removed-keys.sql"), None)
+        , (include_str!("./migrations/cascade-topic-posts.sql"), None)
+        , (include_str!("./migrations/normalize-topics.sql"), Some(normalize_topics))
+        , (include_str!("./migrations/empty-subject-rule.sql"), None)
+        , (include_str!("./migrations/search-posts.sql"), None)
+        , (include_str!("./migrations/arrival-time.sql"), None)
+        , (include_str!("./migrations/author-timezone.sql"), None)
+        , (include_str!("./migrations/scheduled-posts.sql"), None)
+        , (include_str!("./migrations/fading.sql"), None)
+        , (include_str!("./migrations/sessions.sql"), None)
+        , (include_str!("./migrations/sanitize-posts.sql"), Some(sanitize_posts))
+        , (include_str!("./migrations/post-revisions.sql"), None)
+        , (include_str!("./migrations/soft-delete.sql"), None)
+        , (include_str!("./migrations/share-links.sql"), None)
+        , (include_str!("./migrations/private-topics.sql"), None)
+        , (include_str!("./migrations/protected-topics.sql"), None)
+        , (include_str!("./migrations/encrypted-topics.sql"), None)
+        , (include_str!("./migrations/encrypt-at-rest.sql"), None)
+        , (include_str!("./migrations/pending-notes.sql"), None)
+        , (include_str!("./migrations/confirm-authors.sql"), None)
+        , (include_str!("./migrations/pending-notes-at-rest.sql"), None)
+        , (include_str!("./migrations/blind-search.sql"), None)
+        , (include_str!("./migrations/unlock-failure-clients.sql"), None)
+        , (include_str!("./migrations/moved-topics.sql"), None)
+        , (include_str!("./migrations/fade-limit.sql"), None)
+        , (include_str!("./migrations/name-empty-topics.sql"), Some(name_empty_topics))
         ];
     let all_hashes = all_migrations
         .iter()
-        .map(|migration| hash(migration))
+        .map(|&(migration, _)| hash(&migration))
         .collect::<Vec<String>>();
     let fresh = trans.execute("
         SELECT *
@@ -45,7 +88,11 @@ pub fn run(conn: db::PostgresConnection) -> Result<(), ()> {
             println!("  ✓ {}", i);
         } else {
             println!("  → {} applying...", i);
-            trans.batch_execute(all_migrations[i]).unwrap();
+            let (migration, data_migration) = all_migrations[i];
+            trans.batch_execute(migration).unwrap();
+            if let Some(migrate_data) = data_migration {
+                migrate_data(&trans).unwrap();
+            }
             trans.execute("
                 INSERT INTO migrations ( migration ) VALUES ( $1 )", &[hashed])
                 .unwrap();
@@ -62,3 +109,115 @@ fn hash(s: &&str) -> String {
     hasher.input_str(s);
     hasher.result_str()
 }
+
+
+/// Merge topics whose names normalize to the same thing, keeping the oldest one.
+fn normalize_topics(conn: &GenericConnection) -> Result<(), Error> {
+    try!(conn.batch_execute(include_str!("./migrations/moved-topics.sql")));
+    let topics: Vec<(Uuid, Uuid, String, String)> = try!(conn
+        .query("
+            SELECT id, key, author, topic
+            FROM topic
+            ORDER BY timestamp", &[]))
+        .into_iter()
+        .map(|row| (row.get("id"), row.get("key"), row.get("author"), row.get("topic")))
+        .collect();
+
+    let mut keep: HashMap<(String, String), (Uuid, Uuid)> = HashMap::new();
+    for (id, key, author, topic) in topics {
+        let normalized = subject::topic_name(&topic);
+        // topic and author are citext
+        let name = (author.to_lowercase(), normalized.to_lowercase());
+        if let Some(&(into, into_key)) = keep.get(&name) {
+            try!(conn.execute("UPDATE post SET topic = $2 WHERE topic = $1", &[&id, &into]));
+            try!(conn.execute("DELETE FROM topic WHERE id = $1", &[&id]));
+            // the topic lives on under another key, so its links shouldn't say it's gone
+            try!(conn.execute("DELETE FROM removed WHERE key = $1", &[&key]));
+            try!(conn.execute("INSERT INTO moved_topic (key, moved_to) VALUES ($1, $2)", &[&key, &into_key]));
+            continue;
+        }
+        if normalized != topic {
+            try!(conn.execute("UPDATE topic SET topic = $2 WHERE id = $1", &[&id, &normalized]));
+        }
+        keep.insert(name, (id, key));
+    }
+    Ok(())
+}
+
+
+/// Name the topics that subject-less notes went into before there were rules for them, as
+/// each author's rule says. A name the author already has merges the two, like
+/// `normalize_topics` does.
+fn name_empty_topics(conn: &GenericConnection) -> Result<(), Error> {
+    let topics: Vec<(Uuid, Uuid, String, String, String, NaiveDateTime, Option<String>)> = try!(conn
+        .query("
+            SELECT
+                topic.id as id,
+                topic.key as key,
+                topic.author as author,
+                author.empty_subject as rule,
+                author.timezone as timezone,
+                coalesce(first.timestamp, topic.timestamp) as started,
+                first.body as body
+            FROM topic
+            JOIN author ON author.email = topic.author
+            LEFT JOIN LATERAL (
+                SELECT timestamp, body
+                FROM post
+                WHERE post.topic = topic.id
+                ORDER BY timestamp
+                LIMIT 1
+            ) first ON true
+            WHERE trim(topic.topic) = ''
+            ORDER BY topic.timestamp", &[]))
+        .into_iter()
+        .map(|row| (row.get("id"), row.get("key"), row.get("author"), row.get("rule"), row.get("timezone"),
+            row.get("started"), row.get("body")))
+        .collect();
+
+    for (id, key, author, rule, timezone, started, body) in topics {
+        let rule = rule.parse().unwrap_or(EmptySubject::FromBody);
+        let zone = zone::parse(&timezone).unwrap_or(Tz::UTC);
+        // encrypted bodies can't be read here, so those topics go by the rule's fallback
+        let text = body
+            .and_then(|body| if body.starts_with(at_rest::PREFIX) || e2e::is_sealed(&body) { None } else { Some(body) })
+            .map_or(String::new(), |body| export::to_markdown(&body));
+        let name = subject::topic_for_empty(rule, &text, &DateTime::from_utc(started, UTC), &zone);
+
+        let existing: Option<(Uuid, Uuid)> = try!(conn
+            .query("SELECT id, key FROM topic WHERE author = $1 AND topic = $2", &[&author, &name]))
+            .into_iter()
+            .map(|row| (row.get("id"), row.get("key")))
+            .next();
+        match existing {
+            Some((into, into_key)) => {
+                try!(conn.execute("UPDATE post SET topic = $2 WHERE topic = $1", &[&id, &into]));
+                try!(conn.execute("DELETE FROM topic WHERE id = $1", &[&id]));
+                try!(conn.execute("DELETE FROM removed WHERE key = $1", &[&key]));
+                try!(conn.execute("INSERT INTO moved_topic (key, moved_to) VALUES ($1, $2)", &[&key, &into_key]));
+            },
+            None => {
+                try!(conn.execute("UPDATE topic SET topic = $2 WHERE id = $1", &[&id, &name]));
+            },
+        }
+    }
+    Ok(())
+}
+
+
+/// Clean up post bodies that were stored before ingest started sanitizing them.
+fn sanitize_posts(conn: &GenericConnection) -> Result<(), Error> {
+    let posts: Vec<(Uuid, String)> = try!(conn
+        .query("SELECT id, body FROM post", &[]))
+        .into_iter()
+        .map(|row| (row.get("id"), row.get("body")))
+        .collect();
+
+    for (id, body) in posts {
+        let cleaned = sanitize::clean(&body);
+        if cleaned != body {
+            try!(conn.execute("UPDATE post SET body = $2 WHERE id = $1", &[&id, &cleaned]));
+        }
+    }
+    Ok(())
+}
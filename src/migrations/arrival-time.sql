@@ -0,0 +1,10 @@
+-- post.timestamp is when a note was written (its Date header, when that's believable);
+-- arrived is when we got it.
+ALTER TABLE post
+    ADD COLUMN arrived timestamp;
+
+UPDATE post SET arrived = timestamp;
+
+ALTER TABLE post
+    ALTER COLUMN arrived SET NOT NULL,
+    ALTER COLUMN arrived SET DEFAULT now();
@@ -0,0 +1,2 @@
+ALTER TABLE author
+    ADD COLUMN timezone text NOT NULL DEFAULT 'UTC';
@@ -0,0 +1,5 @@
+-- Encrypted notes were indexed by their words in the clear. Their index only holds keyed
+-- hashes of the words now, which `encrypt-notes` fills back in; until then they can't be found.
+UPDATE post
+    SET search = NULL
+    WHERE body LIKE 'enc1:%';
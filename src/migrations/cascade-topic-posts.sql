@@ -0,0 +1,5 @@
+ALTER TABLE post
+    DROP CONSTRAINT post_topic_fkey,
+    ADD FOREIGN KEY(topic)
+        REFERENCES topic(id)
+        ON UPDATE CASCADE ON DELETE CASCADE;
@@ -0,0 +1,8 @@
+-- New authors confirm their address from the welcome email before anything of theirs is
+-- shown. Everyone who's already here counts as confirmed.
+ALTER TABLE author
+    ADD COLUMN confirmed_at timestamp,
+    ADD COLUMN confirm_token_hash text UNIQUE;
+
+UPDATE author
+    SET confirmed_at = timestamp;
@@ -0,0 +1,5 @@
+ALTER TABLE author
+    ADD COLUMN empty_subject text NOT NULL DEFAULT 'body'
+        CONSTRAINT known_empty_subject_rule CHECK (
+          empty_subject IN ('body', 'untitled', 'dated')
+        );
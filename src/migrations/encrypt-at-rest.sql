@@ -0,0 +1,26 @@
+-- Per-author keys for encrypting note bodies at rest, each wrapped by the master key from the
+-- settings. Stored bodies name the key they were encrypted with.
+CREATE TABLE data_key
+(   id          uuid PRIMARY KEY DEFAULT uuid_generate_v4()
+,   author      citext NOT NULL REFERENCES author (email) ON UPDATE CASCADE ON DELETE CASCADE
+,   wrapped     bytea NOT NULL
+,   created     timestamp NOT NULL DEFAULT now()
+);
+
+CREATE INDEX data_key_author_index ON data_key (author, created);
+
+
+-- The database can't read encrypted bodies, so whoever stores one gives its search vector too.
+CREATE OR REPLACE FUNCTION post_text(body text) RETURNS text AS $$
+    SELECT CASE WHEN body LIKE 'e2e1:%' OR body LIKE 'enc1:%' THEN ''
+        ELSE regexp_replace(body, '<[^>]*>', ' ', 'g') END;
+$$ LANGUAGE sql IMMUTABLE;
+
+CREATE OR REPLACE FUNCTION update_post_search() RETURNS trigger AS $$
+BEGIN
+    IF NEW.body NOT LIKE 'enc1:%' THEN
+        NEW.search := to_tsvector('english', post_text(NEW.body));
+    END IF;
+    RETURN NEW;
+END;
+$$ LANGUAGE plpgsql;
@@ -0,0 +1,17 @@
+-- Keys for end-to-end encrypted topics. The private key is wrapped by the author's
+-- passphrase in their browser, so it's no use to us.
+CREATE TABLE topic_e2e_key
+(   topic               uuid PRIMARY KEY REFERENCES topic (id) ON DELETE CASCADE
+,   public_key          bytea NOT NULL
+,   wrapped_private_key bytea NOT NULL
+,   salt                bytea NOT NULL
+,   iv                  bytea NOT NULL
+,   created             timestamp NOT NULL DEFAULT now()
+);
+
+
+-- Sealed notes are nothing but noise to search.
+CREATE OR REPLACE FUNCTION post_text(body text) RETURNS text AS $$
+    SELECT CASE WHEN body LIKE 'e2e1:%' THEN ''
+        ELSE regexp_replace(body, '<[^>]*>', ' ', 'g') END;
+$$ LANGUAGE sql IMMUTABLE;
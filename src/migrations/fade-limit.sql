@@ -0,0 +1,8 @@
+-- Nothing lasts longer than 100 years (subject::MAX_LIFETIME_HOURS), so fades_at() can't
+-- overflow a timestamp.
+UPDATE topic
+    SET fade_after_seconds = 36525 * 24 * 60 * 60
+    WHERE fade_after_seconds > 36525 * 24 * 60 * 60;
+
+ALTER TABLE topic
+    ADD CONSTRAINT sane_fade CHECK (fade_after_seconds <= 36525 * 24 * 60 * 60);
@@ -0,0 +1,22 @@
+-- Notes can fade away: a post goes once it passes its own fades_at, or once it's been open
+-- for its topic's fade_after_seconds, whichever comes first.
+ALTER TABLE post
+    ADD COLUMN fades_at timestamp,
+    ADD COLUMN fade_warned boolean NOT NULL DEFAULT false;
+
+ALTER TABLE topic
+    ADD COLUMN fade_after_seconds bigint
+        CONSTRAINT positive_fade CHECK (fade_after_seconds > 0);
+
+ALTER TABLE author
+    ADD COLUMN fade_warnings boolean NOT NULL DEFAULT false;
+
+-- so a faded topic's link can say so, instead of looking deleted
+ALTER TABLE removed
+    ADD COLUMN faded boolean NOT NULL DEFAULT false;
+
+
+CREATE FUNCTION fades_at(fades_at timestamp, opened timestamp, fade_after_seconds bigint)
+RETURNS timestamp AS $$
+    SELECT least(fades_at, opened + fade_after_seconds * interval '1 second');
+$$ LANGUAGE sql IMMUTABLE;
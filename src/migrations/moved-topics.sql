@@ -0,0 +1,6 @@
+-- Old keys of topics merged into another one, so their links can still find it.
+-- normalize_topics creates this too, since it runs before this migration on a fresh database.
+CREATE TABLE IF NOT EXISTS moved_topic
+(   key         uuid PRIMARY KEY
+,   moved_to    uuid NOT NULL
+);
@@ -0,0 +1,4 @@
+-- Notes sent without a subject used to land in a topic with no name. They get one now, by
+-- their author's empty-subject rule (see subject::topic_for_empty), and the old ones are
+-- named the same way by migrate::name_empty_topics, right after this file.
+SELECT 1;
@@ -0,0 +1,6 @@
+-- Subjects are now normalized before becoming topics (see subject.rs), so existing
+-- topics like "Fwd: journal" and "journal" belong together.
+--
+-- The merge itself needs the same normalizer as ingest, so it runs from Rust right after
+-- this file: migrate::normalize_topics.
+SELECT 1;
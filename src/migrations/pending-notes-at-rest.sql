@@ -0,0 +1,7 @@
+-- Quarantined notes are encrypted at rest like every other note. Their sender might not be an
+-- author, so each gets a data key of its own that belongs to nobody, and goes with the note.
+ALTER TABLE data_key
+    ALTER COLUMN author DROP NOT NULL;
+
+ALTER TABLE pending_note
+    ADD COLUMN data_key uuid REFERENCES data_key (id) ON DELETE CASCADE;
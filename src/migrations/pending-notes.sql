@@ -0,0 +1,16 @@
+-- Notes from senders that couldn't be authenticated, waiting for the real address to confirm
+-- them. Only the hash of each confirmation link's token is kept.
+CREATE TABLE pending_note
+(   id          uuid PRIMARY KEY DEFAULT uuid_generate_v4()
+,   token_hash  text NOT NULL UNIQUE
+,   sender      citext NOT NULL
+,   subject     text NOT NULL
+,   html        text NOT NULL
+,   text        text NOT NULL
+,   headers     text NOT NULL  -- json [[name, value], ...], like mailgun posts them
+,   authored    timestamp
+,   verdict     text NOT NULL
+,   received    timestamp NOT NULL DEFAULT now()
+);
+
+CREATE INDEX pending_note_sender_index ON pending_note (sender);
@@ -0,0 +1,17 @@
+-- Edited posts keep their earlier versions; post.body is always the latest one.
+CREATE TABLE post_revision
+(   id          uuid PRIMARY KEY DEFAULT uuid_generate_v4()
+,   post        uuid NOT NULL REFERENCES post (id) ON DELETE CASCADE
+,   body        text NOT NULL
+,   replaced    timestamp NOT NULL DEFAULT now()
+);
+
+CREATE INDEX post_revision_post_index ON post_revision (post);
+
+
+ALTER TABLE post
+    ADD COLUMN edited_at timestamp,
+    -- so a reply to the original email can find its post
+    ADD COLUMN message_id text;
+
+CREATE INDEX post_message_id_index ON post (message_id);
@@ -0,0 +1,4 @@
+-- Unlisted topics can be read by anyone with the link; private ones only by their author.
+ALTER TABLE topic
+    ADD COLUMN visibility text NOT NULL DEFAULT 'unlisted'
+        CONSTRAINT known_visibility CHECK (visibility IN ('unlisted', 'private'));
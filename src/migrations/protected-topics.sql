@@ -0,0 +1,15 @@
+-- Protected topics can be read by anyone with the link and the passphrase.
+ALTER TABLE topic
+    DROP CONSTRAINT known_visibility,
+    ADD CONSTRAINT known_visibility CHECK (visibility IN ('unlisted', 'protected', 'private')),
+    ADD COLUMN passphrase_hash text,
+    ADD CONSTRAINT protected_passphrase CHECK ((visibility = 'protected') = (passphrase_hash IS NOT NULL));
+
+
+-- wrong passphrases, so guessing can be slowed down
+CREATE TABLE unlock_failure
+(   topic       uuid NOT NULL REFERENCES topic (id) ON DELETE CASCADE
+,   created     timestamp NOT NULL DEFAULT now()
+);
+
+CREATE INDEX unlock_failure_topic_index ON unlock_failure (topic, created);
@@ -0,0 +1,24 @@
+CREATE TABLE removed
+(   key         uuid PRIMARY KEY
+,   timestamp   timestamp NOT NULL DEFAULT now()
+);
+
+
+CREATE FUNCTION record_removed_key() RETURNS trigger AS $$
+BEGIN
+    INSERT INTO removed (key)
+        VALUES (OLD.key)
+        ON CONFLICT DO NOTHING;
+    RETURN OLD;
+END;
+$$ LANGUAGE plpgsql;
+
+
+CREATE TRIGGER author_removed
+    AFTER DELETE ON author
+    FOR EACH ROW EXECUTE PROCEDURE record_removed_key();
+
+
+CREATE TRIGGER topic_removed
+    AFTER DELETE ON topic
+    FOR EACH ROW EXECUTE PROCEDURE record_removed_key();
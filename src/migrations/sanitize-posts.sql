@@ -0,0 +1,2 @@
+-- Post bodies are cleaned up by the sanitize-posts data migration, and by ingest from now on.
+SELECT 1;
@@ -0,0 +1,8 @@
+-- Notes can be sealed until reveal_at, and optionally mailed back to their author then.
+ALTER TABLE post
+    ADD COLUMN reveal_at timestamp,
+    ADD COLUMN send_letter boolean NOT NULL DEFAULT false,
+    ADD COLUMN letter_sent_at timestamp;
+
+CREATE INDEX post_letters_due_index ON post (reveal_at)
+    WHERE send_letter AND letter_sent_at IS NULL;
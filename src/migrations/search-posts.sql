@@ -0,0 +1,28 @@
+CREATE FUNCTION post_text(body text) RETURNS text AS $$
+    SELECT regexp_replace(body, '<[^>]*>', ' ', 'g');
+$$ LANGUAGE sql IMMUTABLE;
+
+
+ALTER TABLE post
+    ADD COLUMN search tsvector;
+
+
+CREATE FUNCTION update_post_search() RETURNS trigger AS $$
+BEGIN
+    NEW.search := to_tsvector('english', post_text(NEW.body));
+    RETURN NEW;
+END;
+$$ LANGUAGE plpgsql;
+
+
+CREATE TRIGGER post_search
+    BEFORE INSERT OR UPDATE OF body ON post
+    FOR EACH ROW EXECUTE PROCEDURE update_post_search();
+
+
+UPDATE post
+    SET search = to_tsvector('english', post_text(body));
+
+
+CREATE INDEX post_search_index
+    ON post USING gin(search);
@@ -0,0 +1,19 @@
+CREATE TABLE sign_in_token
+(   token_hash  text PRIMARY KEY
+,   author      citext NOT NULL REFERENCES author (email) ON DELETE CASCADE ON UPDATE CASCADE
+,   created     timestamp NOT NULL DEFAULT now()
+,   expires     timestamp NOT NULL
+,   used        timestamp
+);
+
+
+CREATE TABLE session
+(   id          uuid PRIMARY KEY DEFAULT uuid_generate_v4()
+,   author      citext NOT NULL REFERENCES author (email) ON DELETE CASCADE ON UPDATE CASCADE
+,   created     timestamp NOT NULL DEFAULT now()
+,   last_seen   timestamp NOT NULL DEFAULT now()
+,   user_agent  text
+,   revoked     timestamp
+);
+
+CREATE INDEX session_author_index ON session (author);
@@ -0,0 +1,13 @@
+-- A topic can be shared through any number of links, each labeled with who it was for, so
+-- that one can be taken back without the others.
+CREATE TABLE share_link
+(   key         uuid PRIMARY KEY DEFAULT uuid_generate_v4()
+,   topic       uuid NOT NULL REFERENCES topic (id) ON DELETE CASCADE
+,   label       text NOT NULL
+,   created     timestamp NOT NULL DEFAULT now()
+,   expires     timestamp
+,   revoked     timestamp
+,   last_used   timestamp
+);
+
+CREATE INDEX share_link_topic_index ON share_link (topic);
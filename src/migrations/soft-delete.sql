@@ -0,0 +1,11 @@
+-- Deleting moves things to the trash, where they can be restored until they're purged.
+-- A trashed topic's posts are trashed along with it, at the same moment, so restoring the
+-- topic brings back exactly those.
+ALTER TABLE author
+    ADD COLUMN deleted_at timestamp;
+
+ALTER TABLE topic
+    ADD COLUMN deleted_at timestamp;
+
+ALTER TABLE post
+    ADD COLUMN deleted_at timestamp;
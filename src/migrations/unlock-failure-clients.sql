@@ -0,0 +1,8 @@
+-- Wrong passphrases count against whoever sent them, so nobody can lock a topic for everyone.
+DELETE FROM unlock_failure;
+
+ALTER TABLE unlock_failure
+    ADD COLUMN client text NOT NULL;
+
+DROP INDEX unlock_failure_topic_index;
+CREATE INDEX unlock_failure_client_index ON unlock_failure (topic, client, created);
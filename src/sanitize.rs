@@ -0,0 +1,167 @@
+//! Cleaning up a note's HTML before it's stored.
+//!
+//! Notes are shown as-is on the site, so only formatting gets through: known tags with a few
+//! harmless attributes, and links and images pointing at the web. Scripts, styles and the
+//! like are dropped along with their contents, and tags are balanced so a note can't break
+//! out of the page around it.
+
+use export::{attr, decode_entities};
+use html::escape;
+
+
+const ALLOWED: &'static [&'static str] = &[
+    "a", "abbr", "b", "blockquote", "br", "cite", "code", "dd", "del", "div", "dl", "dt",
+    "em", "h1", "h2", "h3", "h4", "h5", "h6", "hr", "i", "img", "ins", "li", "ol", "p", "pre",
+    "q", "s", "small", "span", "strike", "strong", "sub", "sup", "table", "tbody", "td",
+    "tfoot", "th", "thead", "tr", "u", "ul",
+];
+
+/// Tags whose contents aren't part of the note.
+const DROPPED: &'static [&'static str] = &[
+    "embed", "head", "iframe", "math", "noscript", "object", "script", "style", "svg",
+    "template", "title",
+];
+
+const VOID: &'static [&'static str] = &["br", "hr", "img"];
+
+
+pub fn clean(html: &str) -> String {
+    let mut out = String::with_capacity(html.len());
+    let mut open: Vec<String> = vec![];
+    let mut skip = 0;
+    let mut rest = html;
+    while !rest.is_empty() {
+        if rest.starts_with("<!--") {
+            rest = rest.find("-->").map_or("", |end| &rest[end + 3..]);
+        } else if rest.starts_with('<') {
+            let end = match rest.find('>') {
+                Some(end) => end,
+                None => break,
+            };
+            tag(&mut out, &mut open, &mut skip, &rest[1..end]);
+            rest = &rest[end + 1..];
+        } else {
+            let end = rest.find('<').unwrap_or(rest.len());
+            if skip == 0 {
+                out.push_str(&escape(&decode_entities(&rest[..end])));
+            }
+            rest = &rest[end..];
+        }
+    }
+    while let Some(name) = open.pop() {
+        out.push_str(&format!("</{}>", name));
+    }
+    out
+}
+
+
+fn tag(out: &mut String, open: &mut Vec<String>, skip: &mut usize, tag: &str) {
+    let closing = tag.starts_with('/');
+    let tag = tag.trim_left_matches('/');
+    let name_end = tag.find(|c: char| c.is_whitespace() || c == '/').unwrap_or(tag.len());
+    let name = tag[..name_end].to_lowercase();
+    let attrs = &tag[name_end..];
+
+    if is(DROPPED, &name) {
+        if closing {
+            *skip = skip.saturating_sub(1);
+        } else if !tag.ends_with('/') {
+            *skip += 1;
+        }
+        return;
+    }
+    if *skip > 0 || !is(ALLOWED, &name) {
+        return;
+    }
+
+    if closing {
+        if is(VOID, &name) {
+            return;
+        }
+        // close anything left open inside it, and ignore closing tags that were never opened
+        if let Some(i) = open.iter().rposition(|o| *o == name) {
+            while open.len() > i {
+                out.push_str(&format!("</{}>", open.pop().unwrap()));
+            }
+        }
+        return;
+    }
+
+    out.push('<');
+    out.push_str(&name);
+    for &(attr_name, check) in allowed_attrs(&name) {
+        if let Some(value) = attr(attrs, attr_name) {
+            if check(&value) {
+                out.push_str(&format!(" {}=\"{}\"", attr_name, escape(&value)));
+            }
+        }
+    }
+    out.push('>');
+    if !is(VOID, &name) {
+        open.push(name);
+    }
+}
+
+fn is(names: &[&str], name: &str) -> bool {
+    names.iter().any(|n| *n == name)
+}
+
+fn allowed_attrs(name: &str) -> &'static [(&'static str, fn(&str) -> bool)] {
+    const LINK: &'static [(&'static str, fn(&str) -> bool)] = &[("href", link), ("title", any)];
+    const IMAGE: &'static [(&'static str, fn(&str) -> bool)] =
+        &[("src", image), ("alt", any), ("width", number), ("height", number)];
+    const CELL: &'static [(&'static str, fn(&str) -> bool)] = &[("colspan", number), ("rowspan", number)];
+    const LIST: &'static [(&'static str, fn(&str) -> bool)] = &[("start", number)];
+    const TITLED: &'static [(&'static str, fn(&str) -> bool)] = &[("title", any)];
+    const NONE: &'static [(&'static str, fn(&str) -> bool)] = &[];
+    match name {
+        "a" => LINK,
+        "img" => IMAGE,
+        "td" | "th" => CELL,
+        "ol" => LIST,
+        "abbr" => TITLED,
+        _ => NONE,
+    }
+}
+
+fn any(_: &str) -> bool {
+    true
+}
+
+fn number(value: &str) -> bool {
+    !value.is_empty() && value.len() <= 5 && value.chars().all(|c| c.is_digit(10))
+}
+
+fn link(url: &str) -> bool {
+    let url = url.trim().to_lowercase();
+    url.starts_with("http://") || url.starts_with("https://") || url.starts_with("mailto:") || url.starts_with('#')
+}
+
+fn image(url: &str) -> bool {
+    let url = url.trim().to_lowercase();
+    url.starts_with("http://") || url.starts_with("https://")
+}
+
+
+#[test]
+fn test_clean() {
+    let cases = [
+        ("<p>hello <b>there</b></p>", "<p>hello <b>there</b></p>"),
+        ("<p onclick=\"evil()\">hi</p>", "<p>hi</p>"),
+        ("a<script>alert(1)</script>b", "ab"),
+        ("<style>p { color: red }</style><p>hi", "<p>hi</p>"),
+        ("<a href=\"javascript:alert(1)\">x</a>", "<a>x</a>"),
+        ("<a href='https://example.com/?a=1&amp;b=2'>x</a>", "<a href=\"https://example.com/?a=1&amp;b=2\">x</a>"),
+        ("<img src=\"https://example.com/cat.gif\" alt=\"a &quot;cat&quot;\" onerror=\"x\">",
+            "<img src=\"https://example.com/cat.gif\" alt=\"a &quot;cat&quot;\">"),
+        ("<div><p>unclosed</div>", "<div><p>unclosed</p></div>"),
+        ("</main>stray</p>", "stray"),
+        ("<!-- hidden -->shown <blink>x</blink>", "shown x"),
+        ("5 &lt; 6 & 7 > 2", "5 &lt; 6 &amp; 7 &gt; 2"),
+        ("cut off <b", "cut off "),
+        ("line<br/>break", "line<br>break"),
+    ];
+    for &(html, cleaned) in cases.iter() {
+        assert_eq!(clean(html), cleaned, "cleaning {:?}", html);
+    }
+}
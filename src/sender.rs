@@ -0,0 +1,285 @@
+//! Whether an email really came from the address it says it's from.
+//!
+//! Anyone can put anyone's address on an email, so before filing a note under its sender we
+//! look at what our mail provider found when it checked the message: the `Authentication-Results`
+//! header (DMARC, DKIM and SPF), and Mailgun's own `X-Mailgun-Spf` and
+//! `X-Mailgun-Dkim-Check-Result`. Mail that doesn't pass is accepted, quarantined until the
+//! real address confirms it, or rejected, as the settings say.
+//!
+//! Senders can write these headers too, so only the first of each counts: Mailgun adds its own
+//! above anything that came with the message. The first `Authentication-Results` also has to
+//! name our provider as its authserv-id. None of that means anything unless the webhook
+//! request itself came from Mailgun, so its signature is checked first.
+
+use std::str::FromStr;
+
+use crypto::hmac::Hmac;
+use crypto::mac::{Mac, MacResult};
+use crypto::sha2::Sha256;
+use rustc_serialize::hex::FromHex;
+
+use mail::{self, Headers};
+
+
+/// What to do with mail whose sender couldn't be authenticated.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Policy {
+    /// file it anyway, like before there were any checks
+    Accept,
+    /// hold it until someone at the sender's address confirms it
+    Quarantine,
+    /// drop it, and tell the mail provider not to try again
+    Reject,
+}
+
+impl FromStr for Policy {
+    type Err = ();
+    fn from_str(s: &str) -> Result<Policy, ()> {
+        match s {
+            "accept" => Ok(Policy::Accept),
+            "quarantine" => Ok(Policy::Quarantine),
+            "reject" => Ok(Policy::Reject),
+            _ => Err(()),
+        }
+    }
+}
+
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Verdict {
+    /// DMARC passed, or DKIM or SPF passed for the sender's own domain
+    Pass,
+    /// a check failed, and none passed
+    Fail,
+    /// nothing said either way
+    Unknown,
+}
+
+impl Verdict {
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            Verdict::Pass => "pass",
+            Verdict::Fail => "fail",
+            Verdict::Unknown => "unknown",
+        }
+    }
+}
+
+
+/// How far a webhook's timestamp can be from our clock, so a signed request that leaks can't
+/// be replayed later.
+pub const WEBHOOK_WINDOW_SECONDS: i64 = 15 * 60;
+
+/// How long quarantined notes wait to be confirmed.
+pub const PENDING_DAYS: i64 = 7;
+
+/// How many quarantined notes one address can have waiting; past that, more are dropped
+/// rather than sending yet another confirmation email.
+pub const MAX_PENDING: i64 = 10;
+
+
+/// One `method=result` from an `Authentication-Results` header, with its properties (like
+/// `header.d` or `smtp.mailfrom`).
+#[derive(Debug, PartialEq, Eq)]
+struct Check {
+    method: String,
+    result: String,
+    properties: Vec<(String, String)>,
+}
+
+impl Check {
+    fn property(&self, name: &str) -> Option<&str> {
+        self.properties.iter()
+            .find(|&&(ref n, _)| n.eq_ignore_ascii_case(name))
+            .map(|&(_, ref value)| &value[..])
+    }
+}
+
+
+/// Whether a webhook request was signed by Mailgun: its `signature` is an HMAC-SHA256 of the
+/// `timestamp` and `token` with our signing key, and the timestamp is close to `now`.
+pub fn check_webhook(signing_key: &str, timestamp: &str, token: &str, signature: &str, now: i64) -> bool {
+    let fresh = timestamp.parse::<i64>().ok()
+        .map_or(false, |t| (now - t).abs() <= WEBHOOK_WINDOW_SECONDS);
+    let signature = match signature.from_hex() {
+        Ok(bytes) => MacResult::new_from_owned(bytes),
+        Err(_) => return false,
+    };
+    let mut mac = Hmac::new(Sha256::new(), signing_key.as_bytes());
+    mac.input(timestamp.as_bytes());
+    mac.input(token.as_bytes());
+    mac.result() == signature && fresh  // MacResult compares in constant time
+}
+
+
+/// Judge whether `sender` really sent the message with these headers, trusting only
+/// `Authentication-Results` from `authserv_id`.
+pub fn verdict(headers: &Headers, sender: &str, authserv_id: &str) -> Verdict {
+    let domain = domain_of(sender);
+    let (mut passed, mut failed) = (false, false);
+
+    let results = mail::header(headers, "Authentication-Results")
+        .and_then(|value| if authserv_id_of(value).eq_ignore_ascii_case(authserv_id) { Some(value) } else { None });
+    for check in results.map_or(vec![], checks) {
+        let aligned = |property: &str| check.property(property)
+            .map_or(false, |value| aligns(&domain_of(value), &domain));
+        match (&check.method[..], &check.result[..]) {
+            ("dmarc", "pass") if check.property("header.from").is_none() || aligned("header.from") => passed = true,
+            ("dkim", "pass") if aligned("header.d") || aligned("header.i") => passed = true,
+            ("spf", "pass") if aligned("smtp.mailfrom") => passed = true,
+            ("dmarc", "fail") | ("dkim", "fail") | ("spf", "fail") => failed = true,
+            _ => {},
+        }
+    }
+
+    // Mailgun's SPF check is for the envelope sender, which is the `sender` it gives us
+    match mail::header(headers, "X-Mailgun-Spf").map(|v| v.trim().to_lowercase()) {
+        Some(ref result) if result == "pass" => passed = true,
+        Some(ref result) if result == "fail" => failed = true,
+        _ => {},
+    }
+    // its DKIM check doesn't say whose signature it checked, so a pass proves nothing about
+    // the sender; a failure still counts against the message though
+    if mail::header(headers, "X-Mailgun-Dkim-Check-Result").map_or(false, |v| v.trim().eq_ignore_ascii_case("fail")) {
+        failed = true;
+    }
+
+    if passed {
+        Verdict::Pass
+    } else if failed {
+        Verdict::Fail
+    } else {
+        Verdict::Unknown
+    }
+}
+
+
+/// An `Authentication-Results` header value without its (comments).
+fn uncommented(value: &str) -> String {
+    let mut plain = String::with_capacity(value.len());
+    let mut depth = 0;
+    for c in value.chars() {
+        match c {
+            '(' => depth += 1,
+            ')' if depth > 0 => depth -= 1,
+            _ if depth == 0 => plain.push(c),
+            _ => {},
+        }
+    }
+    plain
+}
+
+/// Who did the checks in an `Authentication-Results` header (leaving out any version number).
+fn authserv_id_of(value: &str) -> String {
+    uncommented(value)
+        .split(';')
+        .next()
+        .and_then(|first| first.split_whitespace().next())
+        .unwrap_or("")
+        .to_string()
+}
+
+/// The checks in an `Authentication-Results` header value, leaving out the authserv-id it
+/// starts with and any (comments).
+fn checks(value: &str) -> Vec<Check> {
+    uncommented(value)
+        .split(';')
+        .skip(1)
+        .filter_map(|resinfo| {
+            let mut words = resinfo.split_whitespace();
+            let (method, result) = match words.next().and_then(|word| split_pair(word)) {
+                Some(pair) => pair,
+                None => return None,
+            };
+            Some(Check {
+                method: method.to_lowercase(),
+                result: result.to_lowercase(),
+                properties: words
+                    .filter_map(split_pair)
+                    .map(|(name, value)| (name, value.trim_matches('"').to_string()))
+                    .collect(),
+            })
+        })
+        .collect()
+}
+
+fn split_pair(word: &str) -> Option<(String, String)> {
+    word.find('=').map(|eq| (word[..eq].to_string(), word[eq + 1..].to_string()))
+}
+
+/// The domain of an address (or the domain itself, if it already is one), lowercased.
+fn domain_of(address: &str) -> String {
+    let address = mail::address(address);
+    address.rsplit('@').next().unwrap_or("").trim_right_matches('.').to_lowercase()
+}
+
+/// Relaxed alignment: the same domain, or a subdomain of it, either way round.
+fn aligns(checked: &str, sender: &str) -> bool {
+    !checked.is_empty() && !sender.is_empty()
+        && (checked == sender
+            || checked.ends_with(&format!(".{}", sender))
+            || sender.ends_with(&format!(".{}", checked)))
+}
+
+
+#[test]
+fn test_verdict() {
+    const MAILGUN: &'static str = "mx.mailgun.org";
+    let headers = |pairs: &[(&str, &str)]| pairs.iter()
+        .map(|&(name, value)| (name.to_string(), value.to_string()))
+        .collect::<Headers>();
+
+    let dmarc = headers(&[("Authentication-Results",
+        "mx.mailgun.org; dkim=pass header.d=example.com header.s=mx; spf=pass (mailgun.org: domain of \
+         me@example.com designates 1.2.3.4 as permitted sender) smtp.mailfrom=me@example.com; \
+         dmarc=pass (p=NONE sp=NONE dis=NONE) header.from=example.com")]);
+    assert_eq!(verdict(&dmarc, "me@example.com", MAILGUN), Verdict::Pass);
+
+    // a valid signature from somebody else's domain isn't the sender's
+    let theirs = headers(&[("Authentication-Results", "mx.mailgun.org; dkim=pass header.d=forger.net")]);
+    assert_eq!(verdict(&theirs, "me@example.com", MAILGUN), Verdict::Unknown);
+    let subdomain = headers(&[("Authentication-Results", "mx.mailgun.org; dkim=pass header.d=mail.example.com")]);
+    assert_eq!(verdict(&subdomain, "Me <me@example.com>", MAILGUN), Verdict::Pass);
+
+    let forged = headers(&[
+        ("X-Mailgun-Spf", "Fail"),
+        ("X-Mailgun-Dkim-Check-Result", "Pass"),
+        ("Authentication-Results", "mx.mailgun.org; spf=fail smtp.mailfrom=me@example.com; dmarc=fail"),
+    ]);
+    assert_eq!(verdict(&forged, "me@example.com", MAILGUN), Verdict::Fail);
+
+    // only the first header counts: the sender's own come after the provider's
+    let smuggled = headers(&[
+        ("Authentication-Results", "mx.mailgun.org; dmarc=fail header.from=example.com"),
+        ("Authentication-Results", "trust.me; dmarc=pass"),
+    ]);
+    assert_eq!(verdict(&smuggled, "me@example.com", MAILGUN), Verdict::Fail);
+
+    assert_eq!(verdict(&headers(&[("X-Mailgun-Spf", "Pass")]), "me@example.com", MAILGUN), Verdict::Pass);
+    assert_eq!(verdict(&headers(&[("X-Mailgun-Spf", "Neutral")]), "me@example.com", MAILGUN), Verdict::Unknown);
+    assert_eq!(verdict(&vec![], "me@example.com", MAILGUN), Verdict::Unknown);
+
+    // results from anyone else don't count, even if they come first
+    let impostor = headers(&[("Authentication-Results", "trust.me; dmarc=pass header.from=example.com")]);
+    assert_eq!(verdict(&impostor, "me@example.com", MAILGUN), Verdict::Unknown);
+    let versioned = headers(&[("Authentication-Results", "MX.Mailgun.org 1; dmarc=pass header.from=example.com")]);
+    assert_eq!(verdict(&versioned, "me@example.com", MAILGUN), Verdict::Pass);
+
+    assert_eq!("quarantine".parse::<Policy>(), Ok(Policy::Quarantine));
+    assert_eq!("maybe".parse::<Policy>(), Err(()));
+}
+
+#[test]
+fn test_check_webhook() {
+    let key = "key-0123456789abcdef";
+    let signature = "431c4d771f3d0e03d5f696e6690b50bd85e56856e2902aaabc3914b233b6d431";
+    let now = 1476840612;
+    assert!(check_webhook(key, "1476840612", "c0ffee", signature, now));
+    assert!(check_webhook(key, "1476840612", "c0ffee", signature, now + WEBHOOK_WINDOW_SECONDS));
+    assert!(!check_webhook(key, "1476840612", "c0ffee", signature, now + WEBHOOK_WINDOW_SECONDS + 1));
+    assert!(!check_webhook("another key", "1476840612", "c0ffee", signature, now));
+    assert!(!check_webhook(key, "1476840612", "decaf", signature, now));
+    assert!(!check_webhook(key, "1476840612", "c0ffee", &signature[2..], now));
+    assert!(!check_webhook(key, "1476840612", "c0ffee", "not hex", now));
+    assert!(!check_webhook(key, "soon", "c0ffee", signature, now));
+}
@@ -0,0 +1,223 @@
+//! Signing in with a link sent by email, and staying signed in with a cookie.
+//!
+//! Sign-in links carry a random token that's only stored hashed. Sessions live in the
+//! database (so they can be revoked), and the cookie holds the session's id with an HMAC so
+//! that it can't be guessed or tampered with.
+//!
+//! Readers of passphrase-protected topics get a signed cookie of their own once they unlock
+//! one, scoped to the url they read it at.
+
+use crypto::digest::Digest;
+use crypto::hmac::Hmac;
+use crypto::mac::{Mac, MacResult};
+use crypto::scrypt::{self, ScryptParams};
+use crypto::sha2::Sha256;
+use rand::{OsRng, Rng};
+use rustc_serialize::hex::{FromHex, ToHex};
+use uuid::Uuid;
+
+
+pub const COOKIE: &'static str = "session";
+
+/// How long a sign-in link works for.
+pub const LINK_MINUTES: i64 = 15;
+
+/// How many sign-in links one author can ask for within `LINK_MINUTES`.
+pub const MAX_LINKS: i64 = 3;
+
+/// How long a session lasts without being used.
+pub const SESSION_DAYS: i64 = 30;
+
+/// How long a new author has to confirm their address from the welcome email before they,
+/// and their notes, are forgotten.
+pub const CONFIRM_DAYS: i64 = 7;
+
+pub const UNLOCK_COOKIE: &'static str = "unlock";
+
+/// How long an unlocked topic stays unlocked.
+pub const UNLOCK_DAYS: i64 = 30;
+
+/// How many wrong passphrases one client can send a topic within `UNLOCK_FAILURE_MINUTES`
+/// before being told to wait. Until then every guess from it is refused, even the right one.
+pub const MAX_UNLOCK_FAILURES: i64 = 5;
+pub const UNLOCK_FAILURE_MINUTES: i64 = 15;
+
+
+/// A fresh, unguessable token for a sign-in link.
+pub fn new_token() -> String {
+    let mut bytes = [0u8; 32];
+    OsRng::new()
+        .expect("the OS can provide randomness")
+        .fill_bytes(&mut bytes);
+    bytes.to_hex()
+}
+
+/// What gets stored for a token, so a database leak doesn't leak working links.
+pub fn hash_token(token: &str) -> String {
+    let mut hasher = Sha256::new();
+    hasher.input_str(token);
+    hasher.result_str()
+}
+
+
+fn sign(secret: &str, value: &str) -> MacResult {
+    let mut mac = Hmac::new(Sha256::new(), secret.as_bytes());
+    mac.input(value.as_bytes());
+    mac.result()
+}
+
+/// The cookie value for a session: its id and a signature.
+pub fn cookie_value(secret: &str, session: &Uuid) -> String {
+    let id = session.to_string();
+    format!("{}.{}", id, sign(secret, &id).code().to_hex())
+}
+
+/// The session id from a signed cookie value, if the signature checks out.
+pub fn verify(secret: &str, value: &str) -> Option<Uuid> {
+    let dot = match value.find('.') {
+        Some(i) => i,
+        None => return None,
+    };
+    let (id, signature) = (&value[..dot], &value[dot + 1..]);
+    let signature = match signature.from_hex() {
+        Ok(bytes) => MacResult::new_from_owned(bytes),
+        Err(_) => return None,
+    };
+    if sign(secret, id) == signature {  // MacResult compares in constant time
+        Uuid::parse_str(id).ok()
+    } else {
+        None
+    }
+}
+
+/// A token for a signed-in author's forms, so other sites can't post them on their behalf.
+pub fn csrf_token(secret: &str, session: &Uuid) -> String {
+    sign(secret, &format!("csrf {}", session)).code().to_hex()
+}
+
+pub fn check_csrf(secret: &str, session: &Uuid, token: &str) -> bool {
+    match token.from_hex() {
+        Ok(bytes) => sign(secret, &format!("csrf {}", session)) == MacResult::new_from_owned(bytes),
+        Err(_) => false,
+    }
+}
+
+/// A salted scrypt hash of a topic's passphrase, in the usual `$rscrypt$` format.
+pub fn hash_passphrase(passphrase: &str) -> String {
+    scrypt::scrypt_simple(passphrase, &ScryptParams::new(14, 8, 1))
+        .expect("the OS can provide randomness for a salt")
+}
+
+pub fn check_passphrase(passphrase: &str, hash: &str) -> bool {
+    scrypt::scrypt_check(passphrase, hash).unwrap_or(false)
+}
+
+/// How a passphrase guess went.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Guess {
+    Right,
+    Wrong,
+    TooMany,
+}
+
+/// Check a passphrase from a client that's sent `recent_failures` wrong ones lately. Past
+/// `MAX_UNLOCK_FAILURES` it isn't checked at all, so guessing faster costs no scrypt work and
+/// can't turn up the right one.
+pub fn check_guess(recent_failures: i64, passphrase: &str, hash: &str) -> Guess {
+    if recent_failures >= MAX_UNLOCK_FAILURES {
+        Guess::TooMany
+    } else if check_passphrase(passphrase, hash) {
+        Guess::Right
+    } else {
+        Guess::Wrong
+    }
+}
+
+/// The unlock cookie value for a topic. It's tied to the passphrase's hash, so changing the
+/// passphrase locks everyone out again.
+pub fn unlock_value(secret: &str, topic: &Uuid, passphrase_hash: &str) -> String {
+    sign(secret, &format!("unlock {} {}", topic, passphrase_hash)).code().to_hex()
+}
+
+pub fn check_unlock(secret: &str, topic: &Uuid, passphrase_hash: &str, value: &str) -> bool {
+    match value.from_hex() {
+        Ok(bytes) => sign(secret, &format!("unlock {} {}", topic, passphrase_hash)) == MacResult::new_from_owned(bytes),
+        Err(_) => false,
+    }
+}
+
+/// Find a cookie's value in the raw `Cookie` request headers.
+pub fn find_cookie(headers: &[Vec<u8>], name: &str) -> Option<String> {
+    headers
+        .iter()
+        .flat_map(|header| String::from_utf8_lossy(header)
+            .split(';')
+            .map(|pair| pair.trim().to_string())
+            .collect::<Vec<String>>())
+        .filter_map(|pair| pair.find('=').and_then(|eq| if &pair[..eq] == name {
+            Some(pair[eq + 1..].to_string())
+        } else {
+            None
+        }))
+        .next()
+}
+
+/// A `Set-Cookie` header value; `None` clears the cookie.
+pub fn set_cookie(value: Option<&str>, secure: bool) -> String {
+    cookie(COOKIE, "/", value, SESSION_DAYS, secure)
+}
+
+/// A `Set-Cookie` header value for having unlocked the topic read at `path`.
+pub fn set_unlock_cookie(path: &str, value: &str, secure: bool) -> String {
+    cookie(UNLOCK_COOKIE, path, Some(value), UNLOCK_DAYS, secure)
+}
+
+fn cookie(name: &str, path: &str, value: Option<&str>, days: i64, secure: bool) -> String {
+    let (value, max_age) = match value {
+        Some(v) => (v, days * 24 * 60 * 60),
+        None => ("", 0),
+    };
+    format!("{}={}; Path={}; Max-Age={}; HttpOnly; SameSite=Lax{}",
+        name, value, path, max_age, if secure { "; Secure" } else { "" })
+}
+
+
+#[test]
+fn test_session_cookie() {
+    let secret = "0123456789abcdef0123456789abcdef";
+    let id = Uuid::parse_str("7c1b0e0c-3c55-4a4b-9a3f-0f9d2b4fd1a2").unwrap();
+    let value = cookie_value(secret, &id);
+    assert_eq!(verify(secret, &value), Some(id));
+    assert_eq!(verify("another secret, another secret!!", &value), None);
+    let forged = format!("{}{}", "00000000-3c55-4a4b-9a3f-0f9d2b4fd1a2", &value[36..]);
+    assert_eq!(verify(secret, &forged), None);
+    assert_eq!(verify(secret, "nonsense"), None);
+
+    let csrf = csrf_token(secret, &id);
+    assert!(check_csrf(secret, &id, &csrf));
+    assert!(!check_csrf(secret, &id, "00"));
+    assert!(!check_csrf(secret, &id, &value[37..]));  // the cookie's own signature isn't a token
+
+    let unlock = unlock_value(secret, &id, "$rscrypt$hash");
+    assert!(check_unlock(secret, &id, "$rscrypt$hash", &unlock));
+    assert!(!check_unlock(secret, &id, "$rscrypt$changed", &unlock));
+    assert!(!check_unlock(secret, &id, "$rscrypt$hash", &csrf));
+
+    let headers = vec![b"theme=dark; session=abc.def".to_vec()];
+    assert_eq!(find_cookie(&headers, COOKIE), Some("abc.def".to_string()));
+    assert_eq!(find_cookie(&headers, "missing"), None);
+
+    assert_eq!(hash_token("a").len(), 64);
+    assert_eq!(set_cookie(None, false), "session=; Path=/; Max-Age=0; HttpOnly; SameSite=Lax");
+    assert!(new_token() != new_token());
+}
+
+#[test]
+fn test_check_guess() {
+    let hash = hash_passphrase("open sesame");
+    assert_eq!(check_guess(0, "open sesame", &hash), Guess::Right);
+    assert_eq!(check_guess(0, "open says me", &hash), Guess::Wrong);
+    assert_eq!(check_guess(MAX_UNLOCK_FAILURES - 1, "open sesame", &hash), Guess::Right);
+    assert_eq!(check_guess(MAX_UNLOCK_FAILURES, "open sesame", &hash), Guess::TooMany);
+    assert_eq!(check_guess(MAX_UNLOCK_FAILURES, "open says me", &hash), Guess::TooMany);
+}
@@ -0,0 +1,343 @@
+use std::env;
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+use std::str::FromStr;
+
+use rustc_serialize::base64::FromBase64;
+use toml::{Parser, Value};
+
+use sender::Policy;
+
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct Mailgun {
+    pub domain: String,
+    pub key: String,
+    /// signs the webhook requests that deliver mail
+    pub signing_key: String,
+}
+
+
+/// Master keys for encrypting notes at rest (see `at_rest`).
+#[derive(Debug, PartialEq, Eq)]
+pub struct MasterKeys {
+    /// wraps new data keys
+    pub current: Vec<u8>,
+    /// the key being rotated away from, still tried for data keys it wrapped
+    pub previous: Option<Vec<u8>>,
+}
+
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct Features {
+    pub welcome_email: bool,
+    pub visitor_counter: bool,
+}
+
+
+/// Everything that differs between deployments.
+///
+/// Loaded from a TOML file (`write-only.toml`, or wherever `WRITE_ONLY_CONFIG` points), with
+/// environment variables taking precedence over anything in the file.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Settings {
+    /// public url of the site, without a trailing slash
+    pub base_url: String,
+    /// where notes get emailed to
+    pub inbound_address: String,
+    pub port: u16,
+    /// whether a proxy (like Heroku's router) is in front, adding clients to X-Forwarded-For
+    pub behind_proxy: bool,
+    pub database_url: String,
+    pub db_pool_size: u32,
+    pub mailgun: Mailgun,
+    /// signs session cookies; changing it signs everyone out
+    pub session_secret: String,
+    /// how long deleted things wait in the trash before they're gone for good
+    pub trash_days: u32,
+    /// `None` leaves new notes unencrypted at rest
+    pub encryption: Option<MasterKeys>,
+    /// what happens to mail that doesn't prove it's from its sender
+    pub sender_policy: Policy,
+    /// the only authserv-id whose `Authentication-Results` are believed
+    pub authserv_id: String,
+    pub features: Features,
+}
+
+impl Settings {
+    pub fn load() -> Result<Settings, Vec<String>> {
+        let path = env::var("WRITE_ONLY_CONFIG").ok();
+        let file = match path {
+            Some(ref p) => try!(read_config(Path::new(p))),
+            None if Path::new("write-only.toml").exists() =>
+                try!(read_config(Path::new("write-only.toml"))),
+            None => Value::Table(Default::default()),
+        };
+        Settings::from_sources(file, &|name: &str| env::var(name).ok())
+    }
+
+    fn from_sources(file: Value, env: &Fn(&str) -> Option<String>) -> Result<Settings, Vec<String>> {
+        let mut l = Loader { file: file, env: env, errors: vec![] };
+
+        let base_url = l.string("base_url", "BASE_URL")
+            .unwrap_or("https://write-only.space".to_string())
+            .trim_right_matches('/')
+            .to_string();
+        if !(base_url.starts_with("http://") || base_url.starts_with("https://")) {
+            l.errors.push(format!("base_url must be an http(s) url, not `{}`", base_url));
+        }
+
+        let inbound_address = l.string("inbound_address", "INBOUND_ADDRESS")
+            .unwrap_or("note@write-only.space".to_string());
+        if !inbound_address.contains('@') {
+            l.errors.push(format!("inbound_address must be an email address, not `{}`", inbound_address));
+        }
+
+        let port = l.number("port", "PORT", 8080);
+        let behind_proxy = l.flag("behind_proxy", "BEHIND_PROXY", false);
+        let database_url = l.string("database.url", "DATABASE_URL")
+            .unwrap_or("postgresql://postgres@localhost".to_string());
+        let db_pool_size = l.number("database.pool_size", "DB_POOL_SIZE", 10);
+        if db_pool_size == 0 {
+            l.errors.push("database.pool_size must be at least 1".to_string());
+        }
+
+        let mailgun = Mailgun {
+            domain: l.required("mailgun.domain", "MAILGUN_DOMAIN"),
+            key: l.required("mailgun.key", "MAILGUN_KEY"),
+            signing_key: l.required("mailgun.signing_key", "MAILGUN_SIGNING_KEY"),
+        };
+
+        let session_secret = l.required("session.secret", "SESSION_SECRET");
+        if session_secret.len() > 0 && session_secret.len() < 32 {
+            l.errors.push("session.secret must be at least 32 characters".to_string());
+        }
+
+        let trash_days = l.number("trash.retention_days", "TRASH_RETENTION_DAYS", 30);
+        if trash_days == 0 {
+            l.errors.push("trash.retention_days must be at least 1".to_string());
+        }
+
+        let master_key = l.master_key("encryption.master_key", "MASTER_KEY");
+        let previous_master_key = l.master_key("encryption.previous_master_key", "PREVIOUS_MASTER_KEY");
+        let encryption = match (master_key, previous_master_key) {
+            (Some(current), previous) => Some(MasterKeys { current: current, previous: previous }),
+            (None, Some(_)) => {
+                l.errors.push("encryption.previous_master_key needs a new encryption.master_key to rotate to".to_string());
+                None
+            },
+            (None, None) => None,
+        };
+
+        let sender_policy = l.string("sender_auth.policy", "SENDER_AUTH_POLICY")
+            .unwrap_or("quarantine".to_string());
+        let sender_policy = match sender_policy.trim().parse() {
+            Ok(policy) => policy,
+            Err(()) => {
+                l.errors.push(format!("sender_auth.policy must be accept, quarantine or reject, not `{}`", sender_policy));
+                Policy::Quarantine
+            },
+        };
+
+        let authserv_id = l.string("sender_auth.authserv_id", "SENDER_AUTH_AUTHSERV_ID")
+            .unwrap_or("mx.mailgun.org".to_string())
+            .trim()
+            .to_string();
+
+        let features = Features {
+            welcome_email: l.flag("features.welcome_email", "FEATURE_WELCOME_EMAIL", true),
+            visitor_counter: l.flag("features.visitor_counter", "FEATURE_VISITOR_COUNTER", true),
+        };
+
+        if l.errors.len() > 0 {
+            return Err(l.errors);
+        }
+        Ok(Settings {
+            base_url: base_url,
+            inbound_address: inbound_address,
+            port: port,
+            behind_proxy: behind_proxy,
+            database_url: database_url,
+            db_pool_size: db_pool_size,
+            mailgun: mailgun,
+            session_secret: session_secret,
+            trash_days: trash_days,
+            encryption: encryption,
+            sender_policy: sender_policy,
+            authserv_id: authserv_id,
+            features: features,
+        })
+    }
+}
+
+
+fn read_config(path: &Path) -> Result<Value, Vec<String>> {
+    let mut text = String::new();
+    try!(File::open(path)
+        .and_then(|mut f| f.read_to_string(&mut text))
+        .map_err(|e| vec![format!("could not read {}: {}", path.display(), e)]));
+    let mut parser = Parser::new(&text);
+    match parser.parse() {
+        Some(table) => Ok(Value::Table(table)),
+        None => Err(parser.errors
+            .iter()
+            .map(|e| {
+                let (line, col) = parser.to_linecol(e.lo);
+                format!("{}:{}:{}: {}", path.display(), line + 1, col + 1, e.desc)
+            })
+            .collect()),
+    }
+}
+
+
+/// Pulls values out of the config file and environment, collecting any problems along the way
+/// so that they can all be reported at once.
+struct Loader<'a> {
+    file: Value,
+    env: &'a Fn(&str) -> Option<String>,
+    errors: Vec<String>,
+}
+
+impl<'a> Loader<'a> {
+    fn string(&mut self, path: &str, var: &str) -> Option<String> {
+        if let Some(v) = (self.env)(var) {
+            return Some(v);
+        }
+        match self.file.lookup(path) {
+            None => None,
+            Some(&Value::String(ref s)) => Some(s.clone()),
+            Some(_) => {
+                self.errors.push(format!("{} must be a string", path));
+                None
+            },
+        }
+    }
+
+    /// Secrets have no defaults: leaving one out is an error.
+    fn required(&mut self, path: &str, var: &str) -> String {
+        match self.string(path, var) {
+            Some(ref s) if s.len() > 0 => s.clone(),
+            _ => {
+                self.errors.push(format!("{} (or ${}) is required", path, var));
+                String::new()
+            },
+        }
+    }
+
+    /// A 256-bit key, in base64 (eg. from `openssl rand -base64 32`).
+    fn master_key(&mut self, path: &str, var: &str) -> Option<Vec<u8>> {
+        match self.string(path, var) {
+            Some(ref s) if s.len() > 0 => match s.trim().from_base64() {
+                Ok(ref key) if key.len() == 32 => Some(key.clone()),
+                _ => {
+                    self.errors.push(format!("{} must be 32 bytes in base64", path));
+                    None
+                },
+            },
+            _ => None,
+        }
+    }
+
+    fn number<T: FromStr>(&mut self, path: &str, var: &str, default: T) -> T {
+        let raw = match (self.env)(var) {
+            Some(v) => v,
+            None => match self.file.lookup(path) {
+                None => return default,
+                Some(&Value::Integer(n)) => n.to_string(),
+                Some(_) => {
+                    self.errors.push(format!("{} must be a number", path));
+                    return default;
+                },
+            },
+        };
+        match raw.trim().parse() {
+            Ok(n) => n,
+            Err(_) => {
+                self.errors.push(format!("{} must be a number in range, not `{}`", path, raw));
+                default
+            },
+        }
+    }
+
+    fn flag(&mut self, path: &str, var: &str, default: bool) -> bool {
+        if let Some(v) = (self.env)(var) {
+            return match &v.trim().to_lowercase()[..] {
+                "1" | "true" | "yes" | "on" => true,
+                "0" | "false" | "no" | "off" => false,
+                _ => {
+                    self.errors.push(format!("${} must be true or false, not `{}`", var, v));
+                    default
+                },
+            };
+        }
+        match self.file.lookup(path) {
+            None => default,
+            Some(&Value::Boolean(b)) => b,
+            Some(_) => {
+                self.errors.push(format!("{} must be true or false", path));
+                default
+            },
+        }
+    }
+}
+
+
+#[test]
+fn test_settings_sources() {
+    let file = Value::Table(Parser::new("
+        base_url = \"https://notes.example.com/\"
+        [mailgun]
+        domain = \"mg.example.com\"
+        key = \"from-file\"
+        [database]
+        pool_size = 3
+    ").parse().unwrap());
+    let env = |name: &str| match name {
+        "MAILGUN_KEY" => Some("from-env".to_string()),
+        "MAILGUN_SIGNING_KEY" => Some("signing".to_string()),
+        "SESSION_SECRET" => Some("0123456789abcdef0123456789abcdef".to_string()),
+        _ => None,
+    };
+    let settings = Settings::from_sources(file, &env).unwrap();
+    assert_eq!(settings.base_url, "https://notes.example.com");
+    assert_eq!(settings.inbound_address, "note@write-only.space");
+    assert_eq!(settings.db_pool_size, 3);
+    assert!(!settings.behind_proxy);
+    assert_eq!(settings.mailgun.domain, "mg.example.com");
+    assert_eq!(settings.mailgun.key, "from-env");
+    assert_eq!(settings.trash_days, 30);
+    assert_eq!(settings.encryption, None);
+    assert_eq!(settings.sender_policy, Policy::Quarantine);
+    assert_eq!(settings.authserv_id, "mx.mailgun.org");
+    assert!(settings.features.welcome_email);
+}
+
+#[test]
+fn test_settings_master_keys() {
+    let env = |name: &str| match name {
+        "MAILGUN_DOMAIN" | "MAILGUN_KEY" | "MAILGUN_SIGNING_KEY" => Some("mg".to_string()),
+        "SESSION_SECRET" => Some("0123456789abcdef0123456789abcdef".to_string()),
+        "MASTER_KEY" => Some("AQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQE=".to_string()),
+        _ => None,
+    };
+    let settings = Settings::from_sources(Value::Table(Default::default()), &env).unwrap();
+    assert_eq!(settings.encryption, Some(MasterKeys { current: vec![1; 32], previous: None }));
+
+    let short = |name: &str| match name {
+        "PREVIOUS_MASTER_KEY" => Some("c2hvcnQ=".to_string()),
+        _ => env(name),
+    };
+    assert_eq!(Settings::from_sources(Value::Table(Default::default()), &short).unwrap_err(),
+        vec!["encryption.previous_master_key must be 32 bytes in base64".to_string()]);
+}
+
+#[test]
+fn test_settings_require_secrets() {
+    let env = |name: &str| match name {
+        "PORT" => Some("not-a-port".to_string()),
+        _ => None,
+    };
+    let errors = Settings::from_sources(Value::Table(Default::default()), &env).unwrap_err();
+    assert_eq!(errors.len(), 5);
+}
@@ -0,0 +1,372 @@
+use std::str::FromStr;
+
+use chrono::{DateTime, Duration, NaiveDate, NaiveDateTime, UTC};
+use chrono_tz::Tz;
+use unicode_normalization::UnicodeNormalization;
+
+
+/// Reply and forward markers that mail clients stick on the front of subjects, lowercased.
+const PREFIXES: &'static [&'static str] = &[
+    "re", "fw", "fwd",
+    "aw", "wg",             // german
+    "sv", "vs", "vb",       // nordic
+    "antw", "doorst",       // dutch
+    "tr", "rif", "r",       // french, italian
+    "res", "enc",           // portuguese
+    "odp", "pd",            // polish
+    "ynt", "ilt",           // turkish
+    "回复", "答复", "转发",
+];
+
+
+/// The topic a subject line belongs to.
+///
+/// Replies and forwards land in the same topic as the original: reply/forward prefixes and
+/// `[list]` tags are stripped off the front, whitespace is collapsed, and the result is
+/// unicode-normalized (NFC) so that visually identical subjects match.
+pub fn normalize(subject: &str) -> String {
+    let collapsed = collapse(subject);
+    let mut rest = &collapsed[..];
+    while let Some(stripped) = strip_prefix(rest) {
+        rest = stripped;
+    }
+    rest.to_string()
+}
+
+/// Like `normalize`, but a subject that's nothing except prefixes (like `Re:`) keeps them
+/// instead of ending up with no name at all. Only a subject that was empty to begin with
+/// stays empty.
+pub fn topic_name(subject: &str) -> String {
+    let topic = normalize(subject);
+    if topic.is_empty() {
+        collapse(subject)
+    } else {
+        topic
+    }
+}
+
+fn collapse(subject: &str) -> String {
+    subject
+        .nfc()
+        .collect::<String>()
+        .split_whitespace()
+        .collect::<Vec<&str>>()
+        .join(" ")
+}
+
+
+/// What an author wants to happen to notes sent without a subject.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum EmptySubject {
+    /// the first #hashtag in the note, or its first line
+    FromBody,
+    Untitled,
+    /// one topic per day
+    Dated,
+}
+
+impl EmptySubject {
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            EmptySubject::FromBody => "body",
+            EmptySubject::Untitled => "untitled",
+            EmptySubject::Dated => "dated",
+        }
+    }
+}
+
+impl FromStr for EmptySubject {
+    type Err = String;
+    fn from_str(s: &str) -> Result<EmptySubject, String> {
+        match s {
+            "body" => Ok(EmptySubject::FromBody),
+            "untitled" => Ok(EmptySubject::Untitled),
+            "dated" => Ok(EmptySubject::Dated),
+            other => Err(format!("unknown empty-subject rule `{}` (try body, untitled or dated)", other)),
+        }
+    }
+}
+
+
+/// The topic for a note sent without a subject. Dated topics go by the day it was `now` in
+/// the author's `zone`.
+pub fn topic_for_empty(rule: EmptySubject, body_text: &str, now: &DateTime<UTC>, zone: &Tz) -> String {
+    match rule {
+        EmptySubject::FromBody => from_body(body_text).unwrap_or("Untitled".to_string()),
+        EmptySubject::Untitled => "Untitled".to_string(),
+        EmptySubject::Dated => now.with_timezone(zone).format("%Y %B %-d").to_string(),
+    }
+}
+
+fn from_body(text: &str) -> Option<String> {
+    let hashtag = text
+        .split_whitespace()
+        .filter(|word| word.starts_with('#'))
+        .map(|word| word
+            .trim_left_matches('#')
+            .trim_right_matches(|c: char| !(c.is_alphanumeric() || c == '_' || c == '-')))
+        .find(|tag| tag.len() > 0);
+    if let Some(tag) = hashtag {
+        return Some(normalize(tag));
+    }
+    text.lines()
+        .map(normalize)
+        .find(|line| line.len() > 0)
+        .map(|line| if line.chars().count() > 60 {
+            format!("{}…", line.chars().take(60).collect::<String>().trim_right())
+        } else {
+            line
+        })
+}
+
+
+/// When a sealed note should open, in its author's timezone.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Schedule {
+    pub at: NaiveDateTime,
+    /// mail it back to the author when it opens, as a letter to their future self
+    pub letter: bool,
+}
+
+
+/// Per-note options tacked onto the end of a subject line in brackets.
+#[derive(Debug, PartialEq, Eq, Default)]
+pub struct Suffixes {
+    /// `[at 2027-01-01]` or `[letter 2027-01-01 09:00]`
+    pub schedule: Option<Schedule>,
+    /// `[fade 30d]`: delete the note this long after it arrives
+    pub fade: Option<Duration>,
+}
+
+/// Split any recognized `[...]` options off the end of a subject line.
+///
+/// Unrecognized brackets stay put as part of the topic.
+pub fn suffixes(subject: &str) -> (String, Suffixes) {
+    let mut rest = subject.trim();
+    let mut found = Suffixes::default();
+    while rest.ends_with(']') {
+        let open = match rest.rfind('[') {
+            Some(i) => i,
+            None => break,
+        };
+        let inner = rest[open + 1..rest.len() - 1].trim().to_lowercase();
+        if inner.starts_with("at ") || inner.starts_with("letter ") {
+            let (letter, when) = if inner.starts_with("at ") {
+                (false, inner[3..].trim())
+            } else {
+                (true, inner[7..].trim())
+            };
+            let at = NaiveDateTime::parse_from_str(when, "%Y-%m-%d %H:%M").ok()
+                .or_else(|| NaiveDate::parse_from_str(when, "%Y-%m-%d").ok().map(|d| d.and_hms(0, 0, 0)));
+            match at {
+                Some(at) => found.schedule = Some(Schedule { at: at, letter: letter }),
+                None => break,
+            }
+        } else if inner.starts_with("fade ") {
+            match parse_lifetime(&inner[5..]) {
+                Some(lifetime) => found.fade = Some(lifetime),
+                None => break,
+            }
+        } else {
+            break;
+        }
+        rest = rest[..open].trim_right();
+    }
+    (rest.to_string(), found)
+}
+
+/// Take a `[private]` tag off the front of a subject (even after `Re:` and the like), saying
+/// whether there was one.
+pub fn private_tag(subject: &str) -> (String, bool) {
+    let subject = subject.trim();
+    let mut rest = subject;
+    loop {
+        if rest.starts_with('[') {
+            if let Some(end) = rest.find(']') {
+                if rest[1..end].trim().to_lowercase() == "private" {
+                    let before = &subject[..subject.len() - rest.len()];
+                    return (format!("{}{}", before, rest[end + 1..].trim_left()), true);
+                }
+            }
+        }
+        match strip_prefix(rest) {
+            Some(stripped) => rest = stripped,
+            None => return (subject.to_string(), false),
+        }
+    }
+}
+
+/// The longest a note can be set to last: 100 years. The fade-limit migration holds
+/// `topic.fade_after_seconds` to the same.
+pub const MAX_LIFETIME_HOURS: i64 = 36525 * 24;
+
+/// A lifetime like `30d`, `12 hours` or `2 weeks`, up to `MAX_LIFETIME_HOURS`.
+pub fn parse_lifetime(s: &str) -> Option<Duration> {
+    let s = s.trim().to_lowercase();
+    let digits = s.chars().take_while(|c| c.is_digit(10)).count();
+    let n: i64 = match s[..digits].parse() {
+        Ok(n) if n > 0 => n,
+        _ => return None,
+    };
+    let hours_each = match s[digits..].trim() {
+        "h" | "hour" | "hours" => 1,
+        "d" | "day" | "days" => 24,
+        "w" | "week" | "weeks" => 7 * 24,
+        _ => return None,
+    };
+    match n.checked_mul(hours_each) {
+        Some(hours) if hours <= MAX_LIFETIME_HOURS => Some(Duration::hours(hours)),
+        _ => None,
+    }
+}
+
+fn strip_prefix(s: &str) -> Option<&str> {
+    if s.starts_with('[') {
+        // a mailing list's name, like [rust-users], but only in front of a subject of its own:
+        // a lone [draft] is the subject
+        let end = match s.find(']') {
+            Some(end) => end,
+            None => return None,
+        };
+        let list = &s[1..end];
+        let rest = s[end + 1..].trim_left();
+        let named = !list.is_empty()
+            && list.chars().all(|c| c.is_alphanumeric() || c == '-' || c == '_' || c == '.');
+        return if named && !rest.is_empty() { Some(rest) } else { None };
+    }
+    let colon = match s.find(|c: char| c == ':' || c == '：') {
+        Some(i) => i,
+        None => return None,
+    };
+    let marker = s[..colon].trim_right();
+    let marker = match marker.find(|c: char| c == '[' || c == '(') {  // counters, like Re[2]:
+        Some(i) if marker.ends_with(']') || marker.ends_with(')') => &marker[..i],
+        _ => marker,
+    };
+    let marker = marker.to_lowercase();
+    if PREFIXES.iter().any(|p| *p == marker) {
+        let after = colon + s[colon..].chars().next().unwrap().len_utf8();
+        Some(s[after..].trim_left())
+    } else {
+        None
+    }
+}
+
+
+#[test]
+fn test_normalize() {
+    let cases = [
+        ("hello", "hello"),
+        ("re: hello", "hello"),
+        ("RE: hello", "hello"),
+        ("Re:hello", "hello"),
+        ("Re: Re: re: hello", "hello"),
+        ("Re[2]: hello", "hello"),
+        ("Fwd: hello", "hello"),
+        ("FW: hello", "hello"),
+        ("Fwd: Re: hello", "hello"),
+        ("AW: hello", "hello"),
+        ("SV: hello", "hello"),
+        ("Antw: hello", "hello"),
+        ("回复：hello", "hello"),
+        ("[journal] hello", "hello"),
+        ("Re: [journal] Re: hello", "hello"),
+        ("[draft]", "[draft]"),
+        ("Re: [draft]", "[draft]"),
+        ("[two words] hello", "[two words] hello"),
+        ("[] hello", "[] hello"),
+        ("  hello \t  world  ", "hello world"),
+        ("re : hello", "hello"),
+        ("cafe\u{301}", "café"),
+        ("Re: cafe\u{301}", "café"),
+        ("note: hello", "note: hello"),
+        ("Regarding: hello", "Regarding: hello"),
+        ("hello re: world", "hello re: world"),
+        ("12:30 lunch", "12:30 lunch"),
+        ("", ""),
+        ("   ", ""),
+        ("Re: ", ""),
+    ];
+    for &(subject, topic) in cases.iter() {
+        assert_eq!(normalize(subject), topic, "normalizing {:?}", subject);
+    }
+}
+
+#[test]
+fn test_topic_name() {
+    assert_eq!(topic_name("Re: hello"), "hello");
+    assert_eq!(topic_name("Re:  "), "Re:");
+    assert_eq!(topic_name("Fwd:  Re:"), "Fwd: Re:");
+    assert_eq!(topic_name("[draft]"), "[draft]");
+    assert_eq!(topic_name("  "), "");
+}
+
+#[test]
+fn test_from_body() {
+    let cases = [
+        ("some thoughts on #gardening today", Some("gardening")),
+        ("#ideas, mostly\nand more #later", Some("ideas")),
+        ("a lone # sign\nsecond line", Some("a lone # sign")),
+        ("\n   \nFirst real line\nsecond", Some("First real line")),
+        ("Re: first line looks like a subject", Some("first line looks like a subject")),
+        ("", None),
+        (" \n\t", None),
+    ];
+    for &(body, topic) in cases.iter() {
+        assert_eq!(from_body(body), topic.map(String::from), "deriving a topic from {:?}", body);
+    }
+}
+
+#[test]
+fn test_topic_for_empty() {
+    use chrono::TimeZone;
+    let evening = UTC.ymd(2016, 3, 5).and_hms(2, 30, 0);  // still the 4th in Toronto
+    let toronto: Tz = "America/Toronto".parse().unwrap();
+    assert_eq!(topic_for_empty(EmptySubject::Dated, "", &evening, &toronto), "2016 March 4");
+    assert_eq!(topic_for_empty(EmptySubject::Dated, "", &evening, &Tz::UTC), "2016 March 5");
+    assert_eq!(topic_for_empty(EmptySubject::Untitled, "#ideas", &evening, &toronto), "Untitled");
+    assert_eq!(topic_for_empty(EmptySubject::FromBody, "#ideas", &evening, &toronto), "ideas");
+}
+
+#[test]
+fn test_suffixes() {
+    let new_year = NaiveDate::from_ymd(2027, 1, 1);
+    let at = |hour, minute, letter| Some(Schedule { at: new_year.and_hms(hour, minute, 0), letter: letter });
+    let cases = [
+        ("hello [at 2027-01-01]", "hello", at(0, 0, false), None),
+        ("hello [Letter 2027-01-01 09:30] ", "hello", at(9, 30, true), None),
+        ("[at 2027-01-01]", "", at(0, 0, false), None),
+        ("hello [fade 30d]", "hello", None, Some(Duration::days(30))),
+        ("hello [at 2027-01-01] [fade 2 weeks]", "hello", at(0, 0, false), Some(Duration::weeks(2))),
+        ("hello [draft] [fade 12h]", "hello [draft]", None, Some(Duration::hours(12))),
+        ("hello [at someday]", "hello [at someday]", None, None),
+        ("hello [fade forever]", "hello [fade forever]", None, None),
+        ("hello [fade 99999999999999w]", "hello [fade 99999999999999w]", None, None),
+        ("hello [fade 99999999999999999999d]", "hello [fade 99999999999999999999d]", None, None),
+        ("hello", "hello", None, None),
+    ];
+    for &(ref subject, topic, ref schedule, fade) in cases.iter() {
+        let (rest, found) = suffixes(subject);
+        assert_eq!(rest, topic, "topic from {:?}", subject);
+        assert_eq!(&found.schedule, schedule, "schedule from {:?}", subject);
+        assert_eq!(found.fade, fade, "fade from {:?}", subject);
+    }
+}
+
+#[test]
+fn test_private_tag() {
+    assert_eq!(private_tag("[private] Dreams"), ("Dreams".to_string(), true));
+    assert_eq!(private_tag("Re: [Private] Dreams"), ("Re: Dreams".to_string(), true));
+    assert_eq!(private_tag("[list] Dreams"), ("[list] Dreams".to_string(), false));
+    assert_eq!(private_tag("Dreams [private]"), ("Dreams [private]".to_string(), false));
+}
+
+#[test]
+fn test_parse_lifetime() {
+    assert_eq!(parse_lifetime("30d"), Some(Duration::days(30)));
+    assert_eq!(parse_lifetime("5218 weeks"), Some(Duration::weeks(5218)));
+    assert_eq!(parse_lifetime("36525 days"), Some(Duration::days(36525)));
+    assert_eq!(parse_lifetime("36526 days"), None);
+    assert_eq!(parse_lifetime("1317624000000000w"), None);  // overflows i64 hours
+    assert_eq!(parse_lifetime("0d"), None);
+}
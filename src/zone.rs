@@ -0,0 +1,40 @@
+//! Authors' timezones, so dates land on the day they were written.
+
+use chrono::{FixedOffset, Offset};
+use chrono_tz::Tz;
+
+
+/// A timezone by IANA name, like `America/Toronto`.
+pub fn parse(name: &str) -> Result<Tz, String> {
+    name.trim().parse()
+        .map_err(|_| format!("unknown timezone: {} (try a name like America/Toronto)", name.trim()))
+}
+
+/// The name of a best-guess timezone for a single UTC offset.
+///
+/// An offset can't tell Toronto from Havana, so whole hours get the fixed `Etc/GMT` zones
+/// (which don't follow daylight saving) and anything else is left alone.
+pub fn from_offset(offset: &FixedOffset) -> Option<String> {
+    let seconds = offset.local_minus_utc().num_seconds();
+    if seconds % 3600 != 0 {
+        return None;
+    }
+    // yes, the Etc zones' signs are backwards
+    let name = match seconds / 3600 {
+        0 => "UTC".to_string(),
+        hours if hours > 0 => format!("Etc/GMT-{}", hours),
+        hours => format!("Etc/GMT+{}", -hours),
+    };
+    parse(&name).ok().map(|_| name)
+}
+
+
+#[test]
+fn test_from_offset() {
+    assert_eq!(from_offset(&FixedOffset::west(4 * 3600)), Some("Etc/GMT+4".to_string()));
+    assert_eq!(from_offset(&FixedOffset::east(9 * 3600)), Some("Etc/GMT-9".to_string()));
+    assert_eq!(from_offset(&FixedOffset::east(0)), Some("UTC".to_string()));
+    assert_eq!(from_offset(&FixedOffset::east(5 * 3600 + 1800)), None);
+    assert_eq!(parse(" Europe/Paris "), Ok(Tz::Europe__Paris));
+    assert!(parse("Mars/Olympus_Mons").is_err());
+}